@@ -1,26 +1,57 @@
-use std::{path::PathBuf, str::FromStr};
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    fs,
+    io::{Error, ErrorKind, Write},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
 
+use chrono::{TimeZone, Utc};
+use glob::{MatchOptions, Pattern};
 use log::{error, info};
 
 use crate::{
     backup::{
-        do_full_backup, do_incremental_backup, get_all_local_backups,
-        get_all_local_backups_for_app, get_files_changed_since_backup, get_last_backup_time,
-        prune_local_backups, prune_remote_backups, Backup, BackupType,
+        backup_name, do_full_backup, do_incremental_backup, get_all_local_backups,
+        get_all_local_backups_for_app, get_backup_chain, get_backup_path_with_extension,
+        get_files_changed_since_backup, get_last_backup_time, get_new_backup_file_path,
+        parse_backup_from_path, prune_local_backups, prune_remote_backups, upload_backup, Backup,
+        BackupType,
+    },
+    browse::{
+        cat_archive, find_backups, list_archive, normalize_path, BackupChain, EntryInfo, Located,
+        Lookup,
+    },
+    catalog::{remote_backups, update_catalog, Catalog},
+    compress::{decompress_archive, read_virtual_entry},
+    config::{get_all_configs, get_config_from_app_name, Config},
+    diff::{
+        as_text, backup_tree, compare, differences, live_tree, read_contents, unified_diff,
+        unified_diff_candidates, Change, DiffTarget, FileState, Kind, Tree,
     },
-    compress::decompress_archive,
-    config::{get_all_configs, get_config_from_app_name},
-    scripts::run_script,
-    storage::s3::{download_backup_from_remote, get_all_remote_backups},
+    extract::{Extraction, RestoreOptions},
+    globalconfig::GlobalConfig,
+    index::{index_backup, load_index},
+    lock::{lock_app, lock_remote_app, lock_repository},
+    recover::upload_config_snapshot,
+    scripts::{run_script, ScriptContext, ScriptPhase},
+    semaphore::Semaphore,
+    sources::dump::restore_dump,
+    storage::s3::download_backup_from_remote,
 };
 
-pub fn list(app_name: &Option<String>) {
+pub fn list(global_config: &GlobalConfig, app_name: &Option<String>) -> Result<(), Error> {
     // println!("list");
 
     match app_name {
         Some(app_name) => {
-            let config = get_config_from_app_name(&app_name);
-            let backups = get_all_local_backups_for_app(&config);
+            let config = get_config_from_app_name(global_config, app_name)?;
+            let backups = get_all_local_backups_for_app(global_config, &config);
             info!("{} Backups for {}", backups.len(), app_name);
             backups.iter().for_each(|backup| {
                 info!("{:?} {}", backup.backup_type, backup.file_name);
@@ -30,12 +61,12 @@ pub fn list(app_name: &Option<String>) {
             info!("--------------------------------------------");
             info!("Listing all backups from local applications");
 
-            let all_remote_backups = get_all_remote_backups();
+            let all_remote_backups = remote_backups(global_config);
 
-            let all_local_backups = get_all_local_backups();
+            let all_local_backups = get_all_local_backups(global_config);
 
-            let configs = get_all_configs();
-            for config in configs.to_vec() {
+            let configs = get_all_configs(global_config);
+            for config in &configs {
                 // let backups = get_all_local_backups_for_app(&config);
 
                 info!("App: {}", config.app_name);
@@ -64,11 +95,11 @@ pub fn list(app_name: &Option<String>) {
                 .filter(|b| configs.iter().all(|c| c.app_name != b.app_name))
                 .collect::<Vec<&Backup>>();
 
-            if remote_only_backups.len() > 0 {
+            if !remote_only_backups.is_empty() {
                 info!("--------------------------------------------");
                 info!("Listing all backups from remote applications");
             } else {
-                return;
+                return Ok(());
             }
 
             remote_only_backups.sort_by_key(|b| b.app_name.clone());
@@ -96,107 +127,1004 @@ pub fn list(app_name: &Option<String>) {
             }
         }
     }
+
+    Ok(())
 }
 
-pub fn full_backup(app_name: &String) {
-    let config = get_config_from_app_name(app_name);
-    // info!("Running full backup of {}", app_name);
-    info!("Pre backup script: {:?}", config.pre_backup_script);
-    run_script(&config.pre_backup_script);
-    do_full_backup(&config);
-    info!("Post backup script: {:?}", config.post_backup_script);
-    run_script(&config.post_backup_script);
-    info!("Pruning local backups");
-    prune_local_backups(&config);
-    info!("Pruning remote backups");
-    prune_remote_backups(&config);
+#[derive(Debug, Clone, PartialEq)]
+pub enum BackupStatus {
+    Completed,
+    Skipped,
+    Failed(String),
+    // another run held a lock of the app and the run didn't wait for it
+    Locked(String),
+    // not started because backup_all_until was asked to stop
+    Cancelled,
+}
+
+#[derive(Debug, Clone)]
+pub struct BackupSummary {
+    pub app_name: String,
+    pub backup_type: BackupType,
+    pub status: BackupStatus,
+    pub duration: Duration,
+}
+
+// `wait` decides whether to wait for locks held by other runs, or to fail right away
+pub fn full_backup(global_config: &GlobalConfig, config: &Config, wait: bool) -> Result<(), Error> {
+    let slots = Semaphore::new(1);
+    run_backup(
+        global_config,
+        config,
+        &BackupType::Full,
+        wait,
+        &slots,
+        &slots,
+        &AtomicBool::new(false),
+    )?;
+    Ok(())
+}
+
+pub fn incremental_backup(
+    global_config: &GlobalConfig,
+    config: &Config,
+    wait: bool,
+) -> Result<(), Error> {
+    let slots = Semaphore::new(1);
+    run_backup(
+        global_config,
+        config,
+        &BackupType::Incremental,
+        wait,
+        &slots,
+        &slots,
+        &AtomicBool::new(false),
+    )?;
+    Ok(())
+}
+
+// backs up all configs concurrently, each app runs in its own thread named after the app so
+// its log lines are labelled. At most `jobs` apps run scripts and compress at the same time,
+// and at most `upload_jobs` archives are uploaded at the same time.
+pub fn backup_all(
+    global_config: &GlobalConfig,
+    configs: &[Config],
+    backup_type: &BackupType,
+    jobs: usize,
+    upload_jobs: usize,
+    wait: bool,
+) -> Result<Vec<BackupSummary>, Error> {
+    backup_all_until(
+        global_config,
+        configs,
+        backup_type,
+        jobs,
+        upload_jobs,
+        wait,
+        &AtomicBool::new(false),
+    )
 }
 
-pub fn incremental_backup(app_name: &String) {
-    let config = get_config_from_app_name(app_name);
-    let last_backup_time = get_last_backup_time(&config);
-    let files_changed_since_backup = get_files_changed_since_backup(&config, &last_backup_time);
-    if files_changed_since_backup.len() == 0 {
-        info!("No files changed since last backup, skipping incremental backup.");
-        return;
+// like backup_all, apps which haven't started when stop is set are cancelled, running
+// backups are finished
+pub fn backup_all_until(
+    global_config: &GlobalConfig,
+    configs: &[Config],
+    backup_type: &BackupType,
+    jobs: usize,
+    upload_jobs: usize,
+    wait: bool,
+    stop: &AtomicBool,
+) -> Result<Vec<BackupSummary>, Error> {
+    if jobs == 0 || upload_jobs == 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "jobs and upload_jobs must be at least 1",
+        ));
     }
-    info!("Pre backup script: {:?}", config.pre_backup_script);
-    run_script(&config.pre_backup_script);
-    do_incremental_backup(&config, &files_changed_since_backup);
-    info!("Post backup script: {:?}", config.post_backup_script);
-    run_script(&config.post_backup_script);
+
+    let _lock = lock_repository(global_config, "backup --all", wait)?;
+
+    let compress_slots = Semaphore::new(jobs);
+    let upload_slots = Semaphore::new(upload_jobs);
+
+    let summaries = thread::scope(|scope| {
+        let handles = configs
+            .iter()
+            .map(|config| {
+                let compress_slots = &compress_slots;
+                let upload_slots = &upload_slots;
+                let handle = thread::Builder::new()
+                    .name(config.app_name.clone())
+                    .spawn_scoped(scope, move || {
+                        run_backup(
+                            global_config,
+                            config,
+                            backup_type,
+                            wait,
+                            compress_slots,
+                            upload_slots,
+                            stop,
+                        )
+                    })
+                    .unwrap();
+                (config, handle, Instant::now())
+            })
+            .collect::<Vec<_>>();
+
+        handles
+            .into_iter()
+            .map(|(config, handle, started)| {
+                let status = match handle.join() {
+                    Ok(Ok(true)) => BackupStatus::Completed,
+                    Ok(Ok(false)) => BackupStatus::Skipped,
+                    Ok(Err(e)) if e.kind() == ErrorKind::WouldBlock => {
+                        BackupStatus::Locked(e.to_string())
+                    }
+                    Ok(Err(e)) if e.kind() == ErrorKind::Interrupted => BackupStatus::Cancelled,
+                    Ok(Err(e)) => BackupStatus::Failed(e.to_string()),
+                    Err(panic) => BackupStatus::Failed(panic_message(panic.as_ref())),
+                };
+
+                BackupSummary {
+                    app_name: config.app_name.clone(),
+                    backup_type: backup_type.clone(),
+                    status,
+                    duration: started.elapsed(),
+                }
+            })
+            .collect()
+    });
+
+    Ok(summaries)
 }
 
-pub fn restore(app_name: &String, backup_name: &String) -> () {
-    // println!("restore");
-    info!("Restoring {} from {}", app_name, backup_name);
-    let config = get_config_from_app_name(app_name);
-    let local_backups = get_all_local_backups_for_app(&config);
+// logs one line per app and a total, returns the number of failed backups
+pub fn log_backup_summary(summaries: &[BackupSummary]) -> usize {
+    info!("--------------------------------------------");
+    info!("Backup summary");
+    for summary in summaries {
+        match &summary.status {
+            BackupStatus::Failed(e) | BackupStatus::Locked(e) => error!(
+                "{}: failed after {:.1}s: {}",
+                summary.app_name,
+                summary.duration.as_secs_f32(),
+                e
+            ),
+            status => info!(
+                "{}: {:?} in {:.1}s",
+                summary.app_name,
+                status,
+                summary.duration.as_secs_f32()
+            ),
+        }
+    }
 
-    // filter backups until last full backup
-    let mut backups_to_restore: Vec<Backup> = Vec::new();
-    let mut found = false;
-    for backup in local_backups {
-        println!("{:?} {}", backup.backup_type, backup.file_name);
-        if !found && backup.file_name == *backup_name {
-            found = true;
+    let failed = summaries
+        .iter()
+        .filter(|s| {
+            matches!(
+                s.status,
+                BackupStatus::Failed(_) | BackupStatus::Locked(_) | BackupStatus::Cancelled
+            )
+        })
+        .count();
+    let skipped = summaries
+        .iter()
+        .filter(|s| s.status == BackupStatus::Skipped)
+        .count();
+    info!(
+        "{} completed, {} skipped, {} failed",
+        summaries.len() - failed - skipped,
+        skipped,
+        failed
+    );
+
+    failed
+}
+
+// returns false if the backup was skipped because nothing changed
+fn run_backup(
+    global_config: &GlobalConfig,
+    config: &Config,
+    backup_type: &BackupType,
+    wait: bool,
+    compress_slots: &Semaphore,
+    upload_slots: &Semaphore,
+    stop: &AtomicBool,
+) -> Result<bool, Error> {
+    // apps queued for a job slot don't hold their locks yet, so other hosts only see the
+    // apps which are actually being backed up as busy
+    let compress_permit = compress_slots.acquire();
+    if stop.load(Ordering::Relaxed) {
+        return Err(Error::new(
+            ErrorKind::Interrupted,
+            "Stopped before starting",
+        ));
+    }
+
+    let operation = format!("{:?} backup", backup_type).to_lowercase();
+    let _lock = lock_app(global_config, &config.app_name, &operation, wait)?;
+    let _remote_lock = lock_remote_app(global_config, &config.app_name, &operation, wait)?;
+
+    let files_changed_since_backup = match backup_type {
+        BackupType::Full => None,
+        BackupType::Incremental => {
+            let last_backup_time = get_last_backup_time(global_config, config);
+            let files_changed_since_backup =
+                get_files_changed_since_backup(config, &last_backup_time);
+            // changes of dumped databases can't be detected, those apps are always backed up
+            if files_changed_since_backup.is_empty() && config.sources.dump.is_empty() {
+                info!("No files changed since last backup, skipping incremental backup.");
+                return Ok(false);
+            }
+            Some(files_changed_since_backup)
         }
+    };
 
-        if found {
-            backups_to_restore.push(backup.clone());
+    let backup_file_path = get_new_backup_file_path(global_config, config, backup_type);
+    let mut script_context = ScriptContext::new(config, ScriptPhase::PreBackup);
+    script_context.backup_type = Some(backup_type.clone());
+    script_context.backup_name = backup_file_path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string());
+    script_context.backup_path = Some(get_backup_path_with_extension(&backup_file_path, ".tar.gz"));
 
-            if backup.backup_type == BackupType::Full {
-                break;
+    // info!("Running full backup of {}", app_name);
+    info!("Pre backup script: {}", config.pre_backup_script);
+    let mut result =
+        run_script(config, &config.pre_backup_script, &script_context).map_err(|e| e.to_string());
+
+    if result.is_ok() {
+        // a panic while compressing or uploading must not skip the cleanup hooks below
+        result = panic::catch_unwind(AssertUnwindSafe(|| {
+            match files_changed_since_backup {
+                None => do_full_backup(config, &backup_file_path),
+                Some(paths) => do_incremental_backup(config, &paths, &backup_file_path),
+            }
+            .map_err(|e| e.to_string())?;
+
+            // find and history build missing indexes themselves
+            if let Err(e) = index_backup(global_config, &backup_file_path) {
+                error!("Error indexing backup of {}: {}", config.app_name, e);
             }
+
+            drop(compress_permit);
+
+            let _upload_permit = upload_slots.acquire();
+            upload_backup(global_config, backup_file_path);
+            upload_config_snapshot(global_config, config);
+            Ok(())
+        }))
+        .map_err(|panic| panic_message(panic.as_ref()))
+        .and_then(|result| result);
+    }
+
+    if let Err(e) = &result {
+        error!("Backup of {} failed: {}", config.app_name, e);
+    }
+
+    // the post script runs whatever the outcome, it has to undo what the pre script did
+    script_context.phase = ScriptPhase::PostBackup;
+    script_context.result = Some(result.clone());
+    info!("Post backup script: {}", config.post_backup_script);
+    if let Err(e) = run_script(config, &config.post_backup_script, &script_context) {
+        result = result.and(Err(e.to_string()));
+    }
+
+    if result.is_err() {
+        script_context.phase = ScriptPhase::OnFailure;
+        script_context.result = Some(result.clone());
+        info!("On failure script: {}", config.on_failure_script);
+        if let Err(e) = run_script(config, &config.on_failure_script, &script_context) {
+            error!("{}", e);
         }
     }
 
-    if !found {
-        // println!("Backup not found locally");
+    script_context.phase = ScriptPhase::Finally;
+    script_context.result = Some(result.clone());
+    info!("Finally script: {}", config.finally_script);
+    if let Err(e) = run_script(config, &config.finally_script, &script_context) {
+        result = result.and(Err(e.to_string()));
+    }
+
+    result.map_err(Error::other)?;
 
-        let remote_backups = get_all_remote_backups();
+    if *backup_type == BackupType::Full {
+        do_prune(global_config, config);
+    }
 
-        for backup in remote_backups {
-            if backup.file_name == *backup_name {
-                found = true;
-                download_backup_from_remote(&backup);
-                backups_to_restore.push(backup);
+    Ok(true)
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    panic
+        .downcast_ref::<String>()
+        .cloned()
+        .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+        .unwrap_or_else(|| "backup panicked".to_string())
+}
+
+// every backup contains a complete dump, only the newest one of the chain is restored
+fn restore_dumps(config: &Config, backups: &[Backup], dry_run: bool) -> Result<(), Error> {
+    for source in &config.sources.dump {
+        if source.restore_command.is_empty() {
+            info!("No restore command for dump {}", source.name);
+            continue;
+        }
+
+        let mut restored = false;
+        for backup in backups.iter().rev() {
+            let result =
+                read_virtual_entry(&backup.path, &source.entry_name(), |dump| match dry_run {
+                    true => Ok(()),
+                    false => restore_dump(source, dump),
+                })?;
+
+            if result.is_some() {
+                if dry_run {
+                    info!(
+                        "Restore would pipe dump {} from {} into its restore command",
+                        source.name, backup.file_name
+                    );
+                }
+                restored = true;
                 break;
             }
         }
+
+        if !restored {
+            error!("Dump {} not found in the restored backups", source.name);
+        }
+    }
+
+    Ok(())
+}
+
+// writes the entries below path of the backup, including those of the backups it is based
+// on, one per line
+pub fn ls(
+    global_config: &GlobalConfig,
+    config: &Config,
+    backup_name: &str,
+    path: Option<&Path>,
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    let chain = BackupChain::find(global_config, config, backup_name)?;
+    let path = path.map(normalize_path).unwrap_or_default();
+
+    let mut entries: BTreeMap<PathBuf, EntryInfo> = BTreeMap::new();
+    for backup in chain.backups.iter().rev() {
+        list_archive(&mut chain.open(global_config, backup)?, &path, &mut entries)?;
+    }
+
+    for entry in entries.values() {
+        writeln!(out, "{}", entry)?;
+    }
+
+    Ok(())
+}
+
+// writes the content of file as of the backup, from the newest backup of its chain which
+// contains it
+pub fn cat(
+    global_config: &GlobalConfig,
+    config: &Config,
+    backup_name: &str,
+    file: &Path,
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    let chain = BackupChain::find(global_config, config, backup_name)?;
+    let mut path = normalize_path(file);
+    // a hard link to itself would be followed forever
+    let mut hard_links = 0;
+
+    let mut backups = chain.backups.iter();
+    let mut backup = backups.next();
+    while let Some(current) = backup {
+        match cat_archive(&mut chain.open(global_config, current)?, &path, out)? {
+            Lookup::Found => return Ok(()),
+            // hard links point to an earlier entry of the same archive
+            Lookup::HardLink(target) if hard_links < 8 => {
+                hard_links += 1;
+                path = target;
+            }
+            Lookup::HardLink(target) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Too many hard links to {}", target.display()),
+                ))
+            }
+            Lookup::NotFound => backup = backups.next(),
+        }
+    }
+
+    Err(Error::new(
+        ErrorKind::NotFound,
+        format!("{} not found in backup {}", file.display(), backup_name),
+    ))
+}
+
+// writes the files added, removed and modified between a backup and a later backup or
+// the live files, with a unified diff of small text files when asked
+pub fn diff(
+    global_config: &GlobalConfig,
+    config: &Config,
+    backup_name: &str,
+    target: &DiffTarget,
+    unified: bool,
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    let old_chain = BackupChain::find(global_config, config, backup_name)?;
+    let old = backup_tree(global_config, &old_chain)?;
+
+    let (new_chain, new, new_label) = match target {
+        DiffTarget::Backup(new_name) => {
+            let new_chain = BackupChain::find(global_config, config, new_name)?;
+            let new = backup_tree(global_config, &new_chain)?;
+            (Some(new_chain), new, new_name.clone())
+        }
+        DiffTarget::Live => (
+            None,
+            live_tree(config)?,
+            config.app_root.trim_end_matches('/').to_string(),
+        ),
+    };
+
+    let changes = compare(&old, &new);
+
+    let (mut old_contents, mut new_contents) = Default::default();
+    if unified {
+        let candidates = unified_diff_candidates(&changes, &old, &new);
+        old_contents = read_contents(global_config, Some(&old_chain), &old, &candidates)?;
+        new_contents = read_contents(global_config, new_chain.as_ref(), &new, &candidates)?;
+    }
+
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for (path, change) in &changes {
+        match change {
+            Change::Modified(differences) => writeln!(
+                out,
+                "{} {} ({})",
+                change,
+                path.display(),
+                differences.join(", ")
+            )?,
+            _ => writeln!(out, "{} {}", change, path.display())?,
+        }
+        *counts.entry(change.as_str()).or_default() += 1;
+
+        let old_text = old_contents.get(path).and_then(|content| as_text(content));
+        let new_text = new_contents.get(path).and_then(|content| as_text(content));
+        if let (Some(old_text), Some(new_text)) = (old_text, new_text) {
+            write!(
+                out,
+                "{}",
+                unified_diff(
+                    &format!("{}/{}", backup_name, path.display()),
+                    &format!("{}/{}", new_label, path.display()),
+                    old_text,
+                    new_text
+                )
+            )?;
+        }
+    }
+
+    info!(
+        "{} added, {} removed, {} modified",
+        counts.get("added").unwrap_or(&0),
+        counts.get("removed").unwrap_or(&0),
+        counts.get("modified").unwrap_or(&0)
+    );
+    Ok(())
+}
+
+// writes every backup of the app containing a path which matches pattern, with the
+// size, crc32 and modification time of the file. * doesn't match /, ** does.
+pub fn find(
+    global_config: &GlobalConfig,
+    config: &Config,
+    pattern: &str,
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    let pattern = Pattern::new(
+        normalize_path(Path::new(pattern))
+            .to_str()
+            .unwrap_or(pattern),
+    )
+    .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let options = MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+
+    let mut matches = 0;
+    for_each_indexed_backup(global_config, config, |located, tree| {
+        for (path, state) in tree {
+            if pattern.matches_path_with(path, options) {
+                matches += 1;
+                writeln!(
+                    out,
+                    "{} {}",
+                    located.backup.file_name,
+                    indexed_file(path, state)
+                )?;
+            }
+        }
+        Ok(())
+    })?;
+
+    info!("{} matches", matches);
+    Ok(())
+}
+
+// writes the backups in which the content, type or mode of file changed, oldest first
+pub fn history(
+    global_config: &GlobalConfig,
+    config: &Config,
+    file: &Path,
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    let path = normalize_path(file);
+    let mut previous: Option<FileState> = None;
+    let mut found = false;
+
+    for_each_indexed_backup(global_config, config, |located, tree| {
+        let current = tree.get(&path);
+        let change = match (&previous, current) {
+            (None, Some(_)) => "added".to_string(),
+            (Some(_), None) => "removed".to_string(),
+            (Some(previous), Some(current)) => {
+                let differences = differences(previous, current);
+                if differences.is_empty() {
+                    return Ok(());
+                }
+                format!("modified ({})", differences.join(", "))
+            }
+            (None, None) => return Ok(()),
+        };
+
+        found = true;
+        writeln!(
+            out,
+            "{} {} {}",
+            located.backup.time.format("%Y-%m-%d %H:%M:%S"),
+            located.backup.file_name,
+            change
+        )?;
+        previous = current.cloned();
+        Ok(())
+    })?;
+
+    match found {
+        true => Ok(()),
+        false => Err(Error::new(
+            ErrorKind::NotFound,
+            format!(
+                "{} not found in any backup of {}",
+                file.display(),
+                config.app_name
+            ),
+        )),
+    }
+}
+
+// calls f with each backup of the app, oldest first, and the files of its chain from the
+// backup indexes
+fn for_each_indexed_backup(
+    global_config: &GlobalConfig,
+    config: &Config,
+    mut f: impl FnMut(&Located, &Tree) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut backups = find_backups(global_config)
+        .into_iter()
+        .filter(|located| located.backup.app_name == config.app_name)
+        .collect::<Vec<Located>>();
+    backups.reverse();
+
+    let mut tree = Tree::new();
+    for located in &backups {
+        let index = load_index(global_config, &located.backup, located.remote)?;
+        match located.backup.backup_type {
+            BackupType::Full => tree = index,
+            BackupType::Incremental => tree.extend(index),
+        }
+        f(located, &tree)?;
+    }
+
+    Ok(())
+}
+
+fn indexed_file(path: &Path, state: &FileState) -> String {
+    let crc = state
+        .crc
+        .map(|crc| format!("{:08x}", crc))
+        .unwrap_or_else(|| "-".repeat(8));
+    let mtime = Utc
+        .timestamp_opt(state.mtime as i64, 0)
+        .single()
+        .map(|mtime| mtime.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_default();
+
+    match &state.kind {
+        Kind::Symlink(target) => format!(
+            "{:>10} {} {} {} -> {}",
+            state.size,
+            crc,
+            mtime,
+            path.display(),
+            target.display()
+        ),
+        Kind::Dir => format!("{:>10} {} {} {}/", state.size, crc, mtime, path.display()),
+        _ => format!("{:>10} {} {} {}", state.size, crc, mtime, path.display()),
+    }
+}
+
+// records the backups in local and remote storage in a new catalog
+pub fn rebuild_catalog(global_config: &GlobalConfig, wait: bool) -> Result<(), Error> {
+    let _lock = lock_repository(global_config, "catalog rebuild", wait)?;
+    Catalog::open(Path::new(&global_config.local_storage_location))?.rebuild(global_config)
+}
+
+// writes the backups in the catalog with their location, size and last verification
+pub fn list_catalog(
+    global_config: &GlobalConfig,
+    app_name: Option<&str>,
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    let catalog = Catalog::open(Path::new(&global_config.local_storage_location))?;
+
+    for entry in catalog.entries(app_name)? {
+        let location = match (entry.local_path.is_some(), entry.remote) {
+            (true, true) => "local+remote",
+            (true, false) => "local",
+            _ => "remote",
+        };
+        let size = entry
+            .size
+            .map(|size| size.to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let verification = match (&entry.verification, &entry.verified_at) {
+            (Some(verification), Some(verified_at)) => {
+                format!("{} at {}", verification, verified_at)
+            }
+            _ => "unverified".to_string(),
+        };
+
+        write!(
+            out,
+            "{} {:<11} {:<12} {:>12} {}",
+            entry.name,
+            entry.backup_type.as_str(),
+            location,
+            size,
+            verification
+        )?;
+        match &entry.base {
+            Some(base) => writeln!(out, " based on {}", base)?,
+            None => writeln!(out)?,
+        }
     }
 
-    if !found {
-        error!("Couldn't find specified backup");
-        return;
+    Ok(())
+}
+
+pub fn prune(global_config: &GlobalConfig, config: &Config, wait: bool) -> Result<(), Error> {
+    let _lock = lock_app(global_config, &config.app_name, "prune", wait)?;
+    let _remote_lock = lock_remote_app(global_config, &config.app_name, "prune", wait)?;
+
+    do_prune(global_config, config);
+
+    Ok(())
+}
+
+fn do_prune(global_config: &GlobalConfig, config: &Config) {
+    info!("Pruning local backups");
+    prune_local_backups(global_config, config);
+    info!("Pruning remote backups");
+    prune_remote_backups(global_config, config);
+}
+
+// downloads a remote backup into local storage, where it is kept like a local backup
+fn download_backup(global_config: &GlobalConfig, backup: &Backup) -> Result<Backup, Error> {
+    let storage = Path::new(&global_config.local_storage_location);
+    let path = storage.join(format!("{}.tar.gz", backup.file_name));
+
+    if !path.exists() {
+        info!("Downloading {}", backup.file_name);
+        fs::create_dir_all(storage)?;
+        // without the .tar.gz extension an interrupted download isn't taken for a backup
+        let partial_path = storage.join(format!("{}.part", backup.file_name));
+        panic::catch_unwind(|| download_backup_from_remote(global_config, backup, &partial_path))
+            .map_err(|panic| {
+            let _ = fs::remove_file(&partial_path);
+            Error::other(format!(
+                "Error downloading {}: {}",
+                backup.file_name,
+                panic_message(panic.as_ref())
+            ))
+        })?;
+        fs::rename(&partial_path, &path)?;
     }
 
-    println!("Found {} backups to restore", backups_to_restore.len());
+    let local_backup = parse_backup_from_path(&path);
+    update_catalog(storage, |catalog| catalog.add_local(&local_backup));
+    Ok(local_backup)
+}
+
+pub fn restore(
+    global_config: &GlobalConfig,
+    config: &Config,
+    backup_name: &str,
+    options: &RestoreOptions,
+    wait: bool,
+) -> Result<(), Error> {
+    let _lock = lock_app(global_config, &config.app_name, "restore", wait)?;
+
+    info!("Restoring {} from {}", config.app_name, backup_name);
+    let backups_to_restore = find_backups_to_restore(global_config, config, backup_name)?;
+    restore_backups(global_config, config, backups_to_restore, options)
+}
+
+// restores a chain of remote backups, newest first, which the caller picked instead of a
+// backup name. Backups already in local storage aren't downloaded again.
+pub fn restore_remote_chain(
+    global_config: &GlobalConfig,
+    config: &Config,
+    chain: &[Backup],
+    options: &RestoreOptions,
+    wait: bool,
+) -> Result<(), Error> {
+    let _lock = lock_app(global_config, &config.app_name, "restore", wait)?;
+
+    let requested_backup = chain.first().ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!("No backups of {} to restore", config.app_name),
+        )
+    })?;
+    info!(
+        "Restoring {} from {}",
+        config.app_name, requested_backup.file_name
+    );
+    let backups_to_restore = chain
+        .iter()
+        .map(|backup| download_backup(global_config, backup))
+        .collect::<Result<Vec<Backup>, Error>>()?;
+    restore_backups(global_config, config, backups_to_restore, options)
+}
+
+// the chain of backup_name, newest first, from local storage or else downloaded from
+// remote storage. Only backups of the server which made backup_name belong to its chain,
+// other servers may back up an app of the same name into the same bucket.
+fn find_backups_to_restore(
+    global_config: &GlobalConfig,
+    config: &Config,
+    backup_name: &str,
+) -> Result<Vec<Backup>, Error> {
+    let local_backups = backups_of_server(
+        get_all_local_backups_for_app(global_config, config),
+        backup_name,
+    );
+
+    // filter backups until last full backup
+    let mut backups_to_restore = get_backup_chain(&local_backups, backup_name);
+
+    if backups_to_restore.is_empty() {
+        // remote backups are named without extension and listed oldest first, the s3 client
+        // panics when the remote storage is unreachable
+        let remote_backups =
+            panic::catch_unwind(|| remote_backups(global_config)).map_err(|_| {
+                Error::other(format!(
+                    "Backup {} isn't in local storage and remote storage is unavailable",
+                    backup_name
+                ))
+            })?;
+        let remote_name = backup_name.strip_suffix(".tar.gz").unwrap_or(backup_name);
+        let mut remote_backups = backups_of_server(
+            remote_backups
+                .into_iter()
+                .filter(|b| b.app_name == config.app_name)
+                .collect(),
+            remote_name,
+        );
+        remote_backups.reverse();
+
+        for backup in get_backup_chain(&remote_backups, remote_name) {
+            backups_to_restore.push(download_backup(global_config, &backup)?);
+        }
+    }
+
+    if backups_to_restore.is_empty() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!(
+                "Couldn't find backup {} of {}",
+                backup_name, config.app_name
+            ),
+        ));
+    }
+
+    Ok(backups_to_restore)
+}
+
+// the backups made by the server which made backup_name, none if it isn't among them
+fn backups_of_server(backups: Vec<Backup>, backup_name: &str) -> Vec<Backup> {
+    let Some(server_name) = backups
+        .iter()
+        .find(|backup| backup.file_name == backup_name)
+        .map(|backup| backup.server_name.clone())
+    else {
+        return Vec::new();
+    };
+
+    backups
+        .into_iter()
+        .filter(|backup| backup.server_name == server_name)
+        .collect()
+}
+
+// restores the downloaded chain of a backup, newest first
+fn restore_backups(
+    global_config: &GlobalConfig,
+    config: &Config,
+    mut backups_to_restore: Vec<Backup>,
+    options: &RestoreOptions,
+) -> Result<(), Error> {
+    info!("Found {} backups to restore", backups_to_restore.len());
     for backup in &backups_to_restore {
-        println!("{}", backup.file_name);
+        info!("{}", backup.file_name);
     }
 
     backups_to_restore.reverse();
 
-    if &config.pre_restore_script == "" {
+    let app_root = PathBuf::from_str(config.app_root.as_str())
+        .unwrap()
+        .components()
+        .collect::<PathBuf>();
+
+    if options.dry_run {
+        let mut extraction = Extraction::new(options.clone());
+        for backup in &backups_to_restore {
+            decompress_archive(
+                backup.path.clone(),
+                app_root.clone(),
+                config,
+                &mut extraction,
+            )?;
+        }
+        if options.move_aside {
+            extraction.plan_deletions(&app_root)?;
+        }
+        restore_dumps(config, &backups_to_restore, true)?;
+        extraction.log_plan();
+        return Ok(());
+    }
+
+    let requested_backup = backups_to_restore.last().unwrap();
+    let mut script_context = ScriptContext::new(config, ScriptPhase::PreRestore);
+    script_context.backup_type = Some(requested_backup.backup_type.clone());
+    script_context.backup_name = Some(backup_name(requested_backup).to_string());
+    script_context.backup_path = Some(requested_backup.path.clone());
+
+    if config.pre_restore_script.is_empty() {
         info!("No pre restore script");
     } else {
-        run_script(&config.pre_restore_script);
+        run_script(config, &config.pre_restore_script, &script_context)?;
     }
 
-    for backup in backups_to_restore {
+    // the whole chain is unpacked and verified next to app_root first, so a failing
+    // archive leaves app_root untouched
+    let staging = Staging::create(&app_root)?;
+    let mut extraction = Extraction::staged(options.clone(), &app_root);
+
+    for backup in &backups_to_restore {
         info!("Restoring {}", backup.file_name);
         decompress_archive(
-            backup.path,
-            PathBuf::from_str(config.app_root.as_str()).unwrap(),
-        );
+            backup.path.clone(),
+            staging.0.clone(),
+            config,
+            &mut extraction,
+        )?;
     }
 
-    if &config.post_restore_script == "" {
+    info!("Verifying restored files");
+    let verification = extraction.verify(&staging.0);
+    update_catalog(
+        Path::new(&global_config.local_storage_location),
+        |catalog| {
+            match &verification {
+                Ok(()) => backups_to_restore
+                    .iter()
+                    .try_for_each(|backup| catalog.set_verification(backup, Ok(()))),
+                // the chain failed as a whole, recorded for the backup asked for
+                Err(e) => catalog.set_verification(requested_backup, Err(e.to_string())),
+            }
+        },
+    );
+    verification?;
+
+    if options.move_aside {
+        move_aside(&app_root, &staging.0, &extraction)?;
+    } else {
+        let databases = config
+            .sources
+            .sqlite
+            .iter()
+            .map(|source| PathBuf::from(&source.path))
+            .collect::<Vec<PathBuf>>();
+        extraction.swap(&staging.0, &app_root, &databases)?;
+    }
+    info!("Restored files moved into {}", app_root.display());
+
+    restore_dumps(config, &backups_to_restore, false)?;
+
+    script_context.phase = ScriptPhase::PostRestore;
+    script_context.result = Some(Ok(()));
+
+    if config.post_restore_script.is_empty() {
         info!("No post restore script");
     } else {
-        run_script(&config.post_restore_script);
+        run_script(config, &config.post_restore_script, &script_context)?;
+    }
+
+    prune_local_backups(global_config, config);
+
+    Ok(())
+}
+
+// renames app_root to a timestamped sibling and the staging directory into its place.
+// Renaming it back rolls the restore back, which happens right away if the second rename
+// fails.
+fn move_aside(app_root: &Path, staging: &Path, extraction: &Extraction) -> Result<(), Error> {
+    let aside = extraction.aside_path(app_root);
+
+    fs::rename(app_root, &aside)?;
+
+    if let Err(e) = fs::rename(staging, app_root) {
+        error!(
+            "Moving restored files into place failed, rolling back: {}",
+            e
+        );
+        fs::rename(&aside, app_root)?;
+        return Err(e);
     }
 
-    prune_local_backups(&config)
+    info!("Moved {} aside to {}", app_root.display(), aside.display());
+    Ok(())
+}
+
+// directory next to app_root the backups are unpacked into, on the same file system so
+// the restored files can be renamed into place. It is removed when the restore is done,
+// also when it failed.
+struct Staging(PathBuf);
+
+impl Staging {
+    fn create(app_root: &Path) -> Result<Staging, Error> {
+        let mut path = app_root.as_os_str().to_os_string();
+        path.push(".bkp-staging");
+        let staging = Staging(PathBuf::from(path));
+
+        // left behind by an interrupted restore, the app lock is held
+        if staging.0.exists() {
+            fs::remove_dir_all(&staging.0)?;
+        }
+        // e.g. when restoring on a new server
+        fs::create_dir_all(app_root)?;
+        fs::create_dir(&staging.0)?;
+        fs::set_permissions(&staging.0, fs::metadata(app_root)?.permissions())?;
+
+        Ok(staging)
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        match fs::remove_dir_all(&self.0) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                error!("Error removing {}: {}", self.0.display(), e)
+            }
+            _ => {}
+        }
+    }
 }
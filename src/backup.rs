@@ -1,15 +1,21 @@
-use std::path::PathBuf;
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
 
 use chrono::{DateTime, Utc};
 use log::{error, info};
 
 use crate::{
+    catalog::{local_backups, remote_backups, update_catalog},
     compress::compress_files,
     config::Config,
-    globalconfig::GLOBAL_CONFIG,
+    globalconfig::GlobalConfig,
+    index::{delete_remote_index, upload_index},
+    sources::sqlite::snapshot_database,
     storage::{
         fs::{delete_file, filter_files_newer_than, get_files_to_backup, list_files_in_dir},
-        s3::{delete_backup_from_remote, get_all_remote_backups, upload_backup_to_remote},
+        s3::{delete_backup_from_remote, upload_backup_to_remote},
     },
     time::parse_timestamp,
 };
@@ -20,6 +26,15 @@ pub enum BackupType {
     Incremental,
 }
 
+impl BackupType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BackupType::Full => "full",
+            BackupType::Incremental => "incremental",
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Backup {
     pub app_name: String,
@@ -31,13 +46,13 @@ pub struct Backup {
 }
 
 // backup naming [app_name]_[server_name]_[backup_type]_[timestamp].tar.gz
-pub fn parse_backup_from_path(path: &PathBuf) -> Backup {
+pub fn parse_backup_from_path(path: &Path) -> Backup {
     // println!("Parsing backup from path: {:?}", path);
     let file_name = path.file_name().unwrap().to_str().unwrap().to_string();
 
     let file_stem = file_name.split(".tar").collect::<Vec<&str>>()[0];
 
-    let parts = file_stem.split("_").collect::<Vec<&str>>();
+    let parts = file_stem.split('_').collect::<Vec<&str>>();
     let app_name = parts[0].to_string();
     let server_name = parts[1].to_string();
     let backup_type = match parts[2] {
@@ -48,7 +63,7 @@ pub fn parse_backup_from_path(path: &PathBuf) -> Backup {
     let time = parse_timestamp(parts[3].to_string()).unwrap();
 
     Backup {
-        path: path.clone(),
+        path: path.to_path_buf(),
         file_name,
         app_name,
         server_name,
@@ -57,8 +72,17 @@ pub fn parse_backup_from_path(path: &PathBuf) -> Backup {
     }
 }
 
-pub fn get_backup_path_with_extension(path: &PathBuf, extension: &str) -> PathBuf {
-    let mut new_path = path.clone();
+// the name of a backup without the .tar.gz extension, which remote backups are stored
+// without. The catalog and hook scripts use it for local and remote backups alike.
+pub fn backup_name(backup: &Backup) -> &str {
+    backup
+        .file_name
+        .strip_suffix(".tar.gz")
+        .unwrap_or(&backup.file_name)
+}
+
+pub fn get_backup_path_with_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut new_path = path.to_path_buf();
     new_path.set_extension(
         path.extension()
             .ok_or("No extension found")
@@ -79,8 +103,20 @@ fn parse_backups_from_paths(paths: Vec<PathBuf>) -> Vec<Backup> {
         .collect::<Vec<Backup>>()
 }
 
-pub fn get_all_local_backups() -> Vec<Backup> {
-    let files = list_files_in_dir(GLOBAL_CONFIG.local_storage_location.clone().into()).unwrap();
+// local backups as recorded in the catalog, newest first
+pub fn get_all_local_backups(global_config: &GlobalConfig) -> Vec<Backup> {
+    local_backups(global_config)
+}
+
+// walks local storage for backups, newest first
+pub fn scan_local_backups(local_storage_location: &Path) -> Vec<Backup> {
+    let files = list_files_in_dir(local_storage_location.to_path_buf()).unwrap();
+
+    // skip lock files and temporary tar files of backups in progress
+    let files = files
+        .into_iter()
+        .filter(|f| f.to_string_lossy().ends_with(".tar.gz"))
+        .collect::<Vec<PathBuf>>();
 
     let mut backups = parse_backups_from_paths(files);
 
@@ -91,8 +127,8 @@ pub fn get_all_local_backups() -> Vec<Backup> {
     backups
 }
 
-pub fn get_all_local_backups_for_app(config: &Config) -> Vec<Backup> {
-    let backups = get_all_local_backups();
+pub fn get_all_local_backups_for_app(global_config: &GlobalConfig, config: &Config) -> Vec<Backup> {
+    let backups = get_all_local_backups(global_config);
 
     // filter files beginning with app_name
     let backups: Vec<Backup> = backups
@@ -105,68 +141,132 @@ pub fn get_all_local_backups_for_app(config: &Config) -> Vec<Backup> {
     backups
 }
 
-pub fn do_full_backup(config: &Config) {
-    let paths = get_files_to_backup(&config);
+// the backups needed to restore backup_name, newest first: backup_name itself and the
+// backups before it back to the last full backup. backups have to be sorted newest first.
+pub fn get_backup_chain(backups: &[Backup], backup_name: &str) -> Vec<Backup> {
+    let mut chain: Vec<Backup> = Vec::new();
+
+    for backup in backups {
+        if chain.is_empty() && backup.file_name != backup_name {
+            continue;
+        }
+
+        chain.push(backup.clone());
+
+        if backup.backup_type == BackupType::Full {
+            break;
+        }
+    }
+
+    chain
+}
+
+// path of a new backup in local storage, without extension
+pub fn get_new_backup_file_path(
+    global_config: &GlobalConfig,
+    config: &Config,
+    backup_type: &BackupType,
+) -> PathBuf {
+    let backup_file_name = config.app_name.clone()
+        + "_"
+        + config.server_name.as_str()
+        + "_"
+        + backup_type.as_str()
+        + "_"
+        + Utc::now().to_rfc3339().as_str();
+
+    // create path for new backup file, it should be config.local_storage_location + app_name + timestamp + .tar
+    PathBuf::from(global_config.local_storage_location.clone()).join(backup_file_name.as_str())
+}
+
+// creates the archive at backup_file_path (from get_new_backup_file_path),
+// uploading is done separately with upload_backup
+pub fn do_full_backup(config: &Config, backup_file_path: &Path) -> Result<(), io::Error> {
+    let paths = get_files_to_backup(config);
 
-    do_backup(config, &paths, "full");
+    do_backup(config, &paths, backup_file_path)
 }
 
 pub fn get_files_changed_since_backup(
     config: &Config,
     last_backup_time: &DateTime<Utc>,
 ) -> Vec<PathBuf> {
-    let paths = get_files_to_backup(&config);
+    let paths = get_files_to_backup(config);
 
     // println!("Paths: {:?}", paths);
     // println!("Last backup time: {:?}", last_backup_time);
 
     // filter paths using filter_files_newer_than and lastBackupTime
-    let paths = match filter_files_newer_than(&paths, last_backup_time) {
+    match filter_files_newer_than(&paths, last_backup_time) {
         Ok(paths) => paths,
         Err(e) => {
             panic!("Error: Couldn't filter paths based on modified time {}", e);
         }
-    };
-
-    paths
+    }
 
     // paths.len() > 0
 }
 
-pub fn do_incremental_backup(config: &Config, paths: &Vec<PathBuf>) {
-    do_backup(&config, &paths, "incremental");
+pub fn do_incremental_backup(
+    config: &Config,
+    paths: &[PathBuf],
+    backup_file_path: &Path,
+) -> Result<(), io::Error> {
+    do_backup(config, paths, backup_file_path)
 }
 
-fn do_backup(config: &Config, paths: &Vec<PathBuf>, backup_type: &str) {
+fn do_backup(config: &Config, paths: &[PathBuf], backup_file_path: &Path) -> Result<(), io::Error> {
+    let command_outputs = config.sources.command_outputs();
+
     // if paths is empty, return with message
-    if paths.is_empty() {
-        panic!("No files to backup");
+    if paths.is_empty() && command_outputs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "No files to backup",
+        ));
     }
 
-    // save current dir
-    let current_dir = std::env::current_dir().unwrap();
-
-    // set current dir to app_root
-    std::env::set_current_dir(config.app_root.clone()).unwrap();
-
     // println!("Found {} files", paths.len());
     // println!("{:?}", paths);
 
-    let backup_file_name = config.app_name.clone()
-        + "_"
-        + config.server_name.as_str()
-        + "_"
-        + backup_type
-        + "_"
-        + Utc::now().to_rfc3339().as_str();
+    // println!("Backup file path: {:?}", backup_file_path);
 
-    // create path for new backup file, it should be config.local_storage_location + app_name + timestamp + .tar
-    let backup_file_path =
-        PathBuf::from(GLOBAL_CONFIG.local_storage_location.clone()).join(backup_file_name.as_str());
+    // sqlite databases among the paths are archived from a snapshot instead
+    let (database_paths, paths): (Vec<&PathBuf>, Vec<&PathBuf>) = paths.iter().partition(|p| {
+        config
+            .sources
+            .sqlite
+            .iter()
+            .any(|source| source.is_database_file(&config.app_root, p))
+    });
+
+    let mut snapshots = Snapshots(Vec::new());
+    for (index, source) in config.sources.sqlite.iter().enumerate() {
+        if !database_paths
+            .iter()
+            .any(|p| source.is_database_file(&config.app_root, p))
+        {
+            continue;
+        }
 
-    // println!("Backup file path: {:?}", backup_file_path);
+        let snapshot_path =
+            get_backup_path_with_extension(backup_file_path, &format!(".sqlite{}.tmp", index));
+        snapshots
+            .0
+            .push((snapshot_path.clone(), PathBuf::from(&source.path)));
+
+        snapshot_database(&source.db_path(&config.app_root), &snapshot_path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "Error creating snapshot of SQLite database {}: {}",
+                    source.path, e
+                ),
+            )
+        })?;
+    }
 
-    // remove prefix from paths
+    // remove prefix from paths, archive entries are named relative to app_root
     let paths = paths
         .iter()
         .map(|p| {
@@ -176,22 +276,78 @@ fn do_backup(config: &Config, paths: &Vec<PathBuf>, backup_type: &str) {
         })
         .collect::<Vec<PathBuf>>();
 
-    info!("Compressing {} files", paths.len());
+    info!(
+        "Compressing {} files, {} database snapshots and {} command outputs",
+        paths.len(),
+        snapshots.0.len(),
+        command_outputs.len()
+    );
 
     // call compress function with backup_file_path and paths
-    compress_files(&backup_file_path, &paths);
+    compress_files(
+        backup_file_path,
+        Path::new(&config.app_root),
+        &paths,
+        &snapshots.0,
+        &command_outputs,
+        &config.metadata,
+    )?;
+
+    let backup =
+        parse_backup_from_path(&get_backup_path_with_extension(backup_file_path, ".tar.gz"));
+    update_catalog(backup_file_path.parent().unwrap(), |catalog| {
+        catalog.add_local(&backup)
+    });
+    Ok(())
+}
 
-    // upload file to s3
-    upload_backup_to_remote(backup_file_path, backup_file_name);
+fn ignore_not_found(e: io::Error) -> Result<(), io::Error> {
+    match e.kind() {
+        io::ErrorKind::NotFound => Ok(()),
+        _ => Err(e),
+    }
+}
 
-    // restore current dir
-    std::env::set_current_dir(current_dir).unwrap();
+// temporary database snapshots of a backup, (snapshot path, name in archive).
+// They are removed when the backup is done, also when it panicked.
+struct Snapshots(Vec<(PathBuf, PathBuf)>);
+
+impl Drop for Snapshots {
+    fn drop(&mut self) {
+        for (snapshot_path, _) in &self.0 {
+            if let Err(e) = delete_file(snapshot_path).or_else(ignore_not_found) {
+                error!("Error deleting snapshot {}: {}", snapshot_path.display(), e);
+            }
+        }
+    }
+}
+
+pub fn upload_backup(global_config: &GlobalConfig, backup_file_path: PathBuf) {
+    let backup_file_name = backup_file_path
+        .file_name()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let backup = parse_backup_from_path(&get_backup_path_with_extension(
+        &backup_file_path,
+        ".tar.gz",
+    ));
+
+    // upload file to s3
+    upload_backup_to_remote(global_config, backup_file_path, backup_file_name);
+    update_catalog(
+        Path::new(&global_config.local_storage_location),
+        |catalog| catalog.add_remote(&backup),
+    );
+    upload_index(global_config, &backup);
 }
 
-pub fn get_last_backup_time(config: &Config) -> DateTime<Utc> {
-    let backups = get_all_local_backups_for_app(config);
+pub fn get_last_backup_time(global_config: &GlobalConfig, config: &Config) -> DateTime<Utc> {
+    let backups = get_all_local_backups_for_app(global_config, config);
 
-    let last_full_backup = &backups.iter().last().unwrap();
+    let last_full_backup = &backups.last().unwrap();
 
     last_full_backup.time
 }
@@ -208,8 +364,8 @@ pub fn get_last_backup_time(config: &Config) -> DateTime<Utc> {
 //     last_full_backup.time
 // }
 
-pub fn prune_local_backups(config: &Config) {
-    let backups = get_all_local_backups_for_app(config);
+pub fn prune_local_backups(global_config: &GlobalConfig, config: &Config) {
+    let backups = get_all_local_backups_for_app(global_config, config);
 
     let mut backups_to_keep = config.keep_full_local_backups;
 
@@ -220,7 +376,10 @@ pub fn prune_local_backups(config: &Config) {
             } else {
                 info!("Deleting local backup: {:?}", backup.path);
                 match delete_file(&backup.path) {
-                    Ok(_) => {}
+                    Ok(_) => update_catalog(
+                        Path::new(&global_config.local_storage_location),
+                        |catalog| catalog.remove_local(&backup),
+                    ),
                     Err(e) => {
                         error!("Error deleting file: {}", e);
                     }
@@ -230,8 +389,8 @@ pub fn prune_local_backups(config: &Config) {
     }
 }
 
-pub fn prune_remote_backups(config: &Config) {
-    let backups = get_all_remote_backups();
+pub fn prune_remote_backups(global_config: &GlobalConfig, config: &Config) {
+    let backups = remote_backups(global_config);
 
     let mut backups_to_keep = config.keep_full_remote_backups;
 
@@ -241,7 +400,12 @@ pub fn prune_remote_backups(config: &Config) {
                 backups_to_keep -= 1;
             } else {
                 info!("Deleting remote backup: {:?}", backup.path);
-                delete_backup_from_remote(&backup);
+                delete_backup_from_remote(global_config, &backup);
+                delete_remote_index(global_config, &backup);
+                update_catalog(
+                    Path::new(&global_config.local_storage_location),
+                    |catalog| catalog.remove_remote(&backup),
+                );
             }
         }
     }
@@ -0,0 +1,282 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::File,
+    io::{self, ErrorKind, Read, Write},
+    panic,
+    path::{Component, Path, PathBuf},
+};
+
+use chrono::{TimeZone, Utc};
+use flate2::read::GzDecoder;
+use log::warn;
+use tar::{Archive, EntryType};
+
+use crate::{
+    backup::{get_all_local_backups, get_all_local_backups_for_app, get_backup_chain, Backup},
+    catalog::remote_backups,
+    config::Config,
+    globalconfig::GlobalConfig,
+    manifest::Manifest,
+    sources::is_virtual_entry,
+    storage::s3::stream_backup_from_remote,
+};
+
+// an archive entry as listed by bkp ls
+#[derive(Debug, Clone)]
+pub struct EntryInfo {
+    pub path: PathBuf,
+    pub entry_type: EntryType,
+    pub mode: u32,
+    pub size: u64,
+    pub mtime: u64,
+    pub link_name: Option<PathBuf>,
+}
+
+impl fmt::Display for EntryInfo {
+    // like tar -tv: mode, size, mtime and path
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mtime = Utc
+            .timestamp_opt(self.mtime as i64, 0)
+            .single()
+            .map(|mtime| mtime.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+
+        write!(
+            f,
+            "{} {:>10} {} {}",
+            mode_string(self.entry_type, self.mode),
+            self.size,
+            mtime,
+            self.path.display()
+        )?;
+
+        match (&self.link_name, self.entry_type) {
+            (Some(link_name), EntryType::Link) => write!(f, " link to {}", link_name.display()),
+            (Some(link_name), _) => write!(f, " -> {}", link_name.display()),
+            (None, _) => Ok(()),
+        }
+    }
+}
+
+// where the chain of a backup was found
+pub struct BackupChain {
+    // newest first, like get_backup_chain
+    pub backups: Vec<Backup>,
+    pub remote: bool,
+}
+
+impl BackupChain {
+    // looks for the backup in local storage first, then in remote storage
+    pub fn find(
+        global_config: &GlobalConfig,
+        config: &Config,
+        backup_name: &str,
+    ) -> Result<BackupChain, io::Error> {
+        let local_backups = get_all_local_backups_for_app(global_config, config);
+        let backups = get_backup_chain(&local_backups, backup_name);
+        if !backups.is_empty() {
+            return Ok(BackupChain {
+                backups,
+                remote: false,
+            });
+        }
+
+        let mut remote_backups = remote_backups(global_config)
+            .into_iter()
+            .filter(|backup| backup.app_name == config.app_name)
+            .collect::<Vec<Backup>>();
+        remote_backups.reverse();
+
+        // remote backups are stored without the .tar.gz extension
+        let remote_name = backup_name.strip_suffix(".tar.gz").unwrap_or(backup_name);
+        let backups = get_backup_chain(&remote_backups, remote_name);
+        if backups.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!("Couldn't find backup {}", backup_name),
+            ));
+        }
+
+        Ok(BackupChain {
+            backups,
+            remote: true,
+        })
+    }
+
+    pub fn open(
+        &self,
+        global_config: &GlobalConfig,
+        backup: &Backup,
+    ) -> Result<Archive<GzDecoder<Box<dyn Read>>>, io::Error> {
+        open_archive(global_config, backup, self.remote)
+    }
+}
+
+// a backup in local storage, or only in remote storage
+#[derive(Debug, Clone)]
+pub struct Located {
+    pub backup: Backup,
+    pub remote: bool,
+}
+
+// backups in local storage and those which are only in remote storage, newest first
+pub fn find_backups(global_config: &GlobalConfig) -> Vec<Located> {
+    let mut backups = get_all_local_backups(global_config)
+        .into_iter()
+        .map(|backup| Located {
+            backup,
+            remote: false,
+        })
+        .collect::<Vec<Located>>();
+
+    // the s3 client panics when the remote storage is unreachable
+    match panic::catch_unwind(|| remote_backups(global_config)) {
+        Ok(remote_backups) => {
+            for backup in remote_backups {
+                // remote backups are stored without the .tar.gz extension
+                let local = backups.iter().any(|located| {
+                    located.backup.file_name.strip_suffix(".tar.gz") == Some(&backup.file_name)
+                });
+                if !local {
+                    backups.push(Located {
+                        backup,
+                        remote: true,
+                    });
+                }
+            }
+        }
+        Err(_) => warn!("Remote storage is unavailable, only local backups are used"),
+    }
+
+    backups.sort_by_key(|located| std::cmp::Reverse(located.backup.time));
+    backups
+}
+
+// remote backups are streamed, not downloaded
+pub fn open_archive(
+    global_config: &GlobalConfig,
+    backup: &Backup,
+    remote: bool,
+) -> Result<Archive<GzDecoder<Box<dyn Read>>>, io::Error> {
+    let reader: Box<dyn Read> = match remote {
+        true => Box::new(stream_backup_from_remote(global_config, backup)?),
+        false => Box::new(File::open(&backup.path)?),
+    };
+
+    Ok(Archive::new(GzDecoder::new(reader)))
+}
+
+pub enum Lookup {
+    Found,
+    // the content is stored with the path the hard link points to
+    HardLink(PathBuf),
+    NotFound,
+}
+
+// adds the entries of an archive below path to entries, replacing those of older archives
+pub fn list_archive<R: Read>(
+    archive: &mut Archive<R>,
+    path: &Path,
+    entries: &mut BTreeMap<PathBuf, EntryInfo>,
+) -> Result<(), io::Error> {
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_path = normalize_path(&entry.path()?);
+
+        if entry_path.as_os_str().is_empty()
+            || !entry_path.starts_with(path)
+            || (entry_path == Manifest::entry_name() && is_virtual_entry(&mut entry)?)
+        {
+            continue;
+        }
+
+        let header = entry.header();
+        let info = EntryInfo {
+            path: entry_path.clone(),
+            entry_type: header.entry_type(),
+            mode: header.mode().unwrap_or(0),
+            size: entry.size(),
+            mtime: header.mtime().unwrap_or(0),
+            link_name: entry.link_name()?.map(|link_name| link_name.to_path_buf()),
+        };
+        entries.insert(entry_path, info);
+    }
+
+    Ok(())
+}
+
+// copies the content of the file at path to out
+pub fn cat_archive<R: Read>(
+    archive: &mut Archive<R>,
+    path: &Path,
+    out: &mut dyn Write,
+) -> Result<Lookup, io::Error> {
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if normalize_path(&entry.path()?) != path {
+            continue;
+        }
+
+        let entry_type = entry.header().entry_type();
+        let link_name = entry.link_name()?.map(|link_name| link_name.to_path_buf());
+
+        return match (entry_type, link_name) {
+            (EntryType::Link, Some(link_name)) => Ok(Lookup::HardLink(normalize_path(&link_name))),
+            (EntryType::Symlink, Some(link_name)) => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is a symlink to {}", path.display(), link_name.display()),
+            )),
+            (EntryType::Directory, _) => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is a directory", path.display()),
+            )),
+            _ => {
+                io::copy(&mut entry, out)?;
+                Ok(Lookup::Found)
+            }
+        };
+    }
+
+    Ok(Lookup::NotFound)
+}
+
+// archive paths are relative to app_root, "./etc/app.conf" and "/etc/app.conf" are both
+// "etc/app.conf"
+pub fn normalize_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}
+
+fn mode_string(entry_type: EntryType, mode: u32) -> String {
+    let file_type = match entry_type {
+        EntryType::Directory => 'd',
+        EntryType::Symlink => 'l',
+        EntryType::Link => 'h',
+        EntryType::Char => 'c',
+        EntryType::Block => 'b',
+        EntryType::Fifo => 'p',
+        _ => '-',
+    };
+
+    let mut mode_string = String::from(file_type);
+    // (read, write, execute, special bit, special char) for user, group and other
+    let classes = [
+        (0o400, 0o200, 0o100, 0o4000, 's'),
+        (0o040, 0o020, 0o010, 0o2000, 's'),
+        (0o004, 0o002, 0o001, 0o1000, 't'),
+    ];
+    for (read, write, execute, special, special_char) in classes {
+        mode_string.push(if mode & read != 0 { 'r' } else { '-' });
+        mode_string.push(if mode & write != 0 { 'w' } else { '-' });
+        mode_string.push(match (mode & execute != 0, mode & special != 0) {
+            (true, true) => special_char,
+            (false, true) => special_char.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+
+    mode_string
+}
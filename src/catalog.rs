@@ -0,0 +1,539 @@
+use std::{
+    fs,
+    io::Error,
+    panic,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{error, info, warn};
+use rusqlite::{params, Connection, OptionalExtension, Transaction, TransactionBehavior};
+
+use crate::{
+    backup::{backup_name, parse_backup_from_path, scan_local_backups, Backup, BackupType},
+    diff::Tree,
+    globalconfig::GlobalConfig,
+    index::{index_to_bytes, parse_index},
+    storage::s3::get_all_remote_backups,
+};
+
+// the catalog keeps what is known about the backups in local and remote storage, so they
+// don't have to be listed for every command. It lives in local storage and is updated by
+// each backup, upload, prune and restore, bkp catalog rebuild recreates it from storage.
+const CATALOG_NAME: &str = ".catalog.sqlite";
+
+// several bkp runs may update the catalog at once, e.g. backup --all with --jobs
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS backups (
+        name TEXT PRIMARY KEY,
+        app_name TEXT NOT NULL,
+        server_name TEXT NOT NULL,
+        backup_type TEXT NOT NULL,
+        time TEXT NOT NULL,
+        base TEXT,
+        size INTEGER,
+        local_path TEXT,
+        remote INTEGER NOT NULL DEFAULT 0,
+        verified_at TEXT,
+        verification TEXT
+    );
+    CREATE INDEX IF NOT EXISTS backups_by_app ON backups (app_name, time);
+    CREATE TABLE IF NOT EXISTS indexes (
+        backup TEXT PRIMARY KEY,
+        content BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS state (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+";
+
+// changes of catalogs made by earlier versions, run once each. The user_version of a
+// catalog is the number of migrations it has, SCHEMA creates new catalogs with all of them.
+const MIGRATIONS: &[&str] = &[
+    // indexes were kept in a table called manifests
+    "DROP TABLE IF EXISTS manifests;",
+    // bases were chosen across servers, the server is the second part of the name
+    "ALTER TABLE backups ADD COLUMN server_name TEXT NOT NULL DEFAULT '';
+    UPDATE backups SET server_name = substr(
+        substr(name, instr(name, '_') + 1),
+        1,
+        instr(substr(name, instr(name, '_') + 1), '_') - 1
+    );
+    UPDATE backups SET base = (
+        SELECT previous.name FROM backups AS previous
+        WHERE previous.app_name = backups.app_name
+            AND previous.server_name = backups.server_name
+            AND previous.time < backups.time
+        ORDER BY previous.time DESC LIMIT 1
+    ) WHERE backup_type = 'incremental';",
+];
+
+// state keys, set when local storage was scanned and remote storage last listed
+const LOCAL_SCANNED: &str = "local_scanned";
+const REMOTE_LISTED: &str = "remote_listed";
+
+// how long a listing of remote storage is used before the bucket is listed again, it
+// changes without this host when other hosts upload or prune
+const REMOTE_LISTING_TTL: Duration = Duration::from_secs(5 * 60);
+
+// a backup as recorded in the catalog
+#[derive(Debug, Clone)]
+pub struct CatalogEntry {
+    pub name: String,
+    pub backup_type: BackupType,
+    pub time: String,
+    // the backup an incremental backup is based on
+    pub base: Option<String>,
+    pub size: Option<u64>,
+    pub local_path: Option<PathBuf>,
+    pub remote: bool,
+    pub verified_at: Option<String>,
+    // "ok" or why the last verification failed
+    pub verification: Option<String>,
+}
+
+pub struct Catalog {
+    connection: Connection,
+    local_storage_location: PathBuf,
+}
+
+impl Catalog {
+    // opens the catalog of local storage, a new one is filled from local storage right away
+    pub fn open(local_storage_location: &Path) -> Result<Catalog, Error> {
+        fs::create_dir_all(local_storage_location)?;
+        let connection =
+            Connection::open(local_storage_location.join(CATALOG_NAME)).map_err(catalog_error)?;
+        connection
+            .busy_timeout(BUSY_TIMEOUT)
+            .map_err(catalog_error)?;
+        migrate(&connection)?;
+        connection.execute_batch(SCHEMA).map_err(catalog_error)?;
+
+        let catalog = Catalog {
+            connection,
+            local_storage_location: local_storage_location.to_path_buf(),
+        };
+        if catalog.state(LOCAL_SCANNED)?.is_none() {
+            catalog.scan_local()?;
+        }
+
+        Ok(catalog)
+    }
+
+    // forgets everything and records the backups found in local and remote storage.
+    // Indexes are read from the archives again when they are needed.
+    pub fn rebuild(&self, global_config: &GlobalConfig) -> Result<(), Error> {
+        // the s3 client panics when the remote storage is unreachable
+        let remote_backups = panic::catch_unwind(|| get_all_remote_backups(global_config))
+            .map_err(|_| Error::other("Remote storage is unavailable"))?;
+
+        // without state a catalog interrupted here is filled again on the next open
+        self.connection
+            .execute_batch("DELETE FROM state; DELETE FROM backups; DELETE FROM indexes;")
+            .map_err(catalog_error)?;
+        self.scan_local()?;
+        self.record_remote(&remote_backups)?;
+
+        info!(
+            "Catalog rebuilt: {} local and {} remote backups",
+            self.local_backups()?.len(),
+            remote_backups.len()
+        );
+        Ok(())
+    }
+
+    fn scan_local(&self) -> Result<(), Error> {
+        for backup in scan_local_backups(&self.local_storage_location) {
+            self.add_local(&backup)?;
+        }
+        self.set_state(LOCAL_SCANNED)
+    }
+
+    // reconciles the catalog with a listing of remote storage, in one transaction so other
+    // bkp runs never see the remote backups half recorded
+    fn record_remote(&self, remote_backups: &[Backup]) -> Result<(), Error> {
+        let transaction = self
+            .connection
+            .unchecked_transaction()
+            .map_err(catalog_error)?;
+        self.connection
+            .execute("UPDATE backups SET remote = 0", [])
+            .map_err(catalog_error)?;
+        for backup in remote_backups {
+            self.add_remote(backup)?;
+        }
+        self.remove_unstored()?;
+        self.set_state(REMOTE_LISTED)?;
+        transaction.commit().map_err(catalog_error)
+    }
+
+    // a backup archive in local storage
+    pub fn add_local(&self, backup: &Backup) -> Result<(), Error> {
+        let size = fs::metadata(&backup.path)?.len();
+        self.insert(backup)?;
+        self.connection
+            .execute(
+                "UPDATE backups SET local_path = ?2, size = ?3 WHERE name = ?1",
+                params![
+                    backup_name(backup),
+                    backup.path.to_string_lossy(),
+                    size as i64
+                ],
+            )
+            .map_err(catalog_error)?;
+        Ok(())
+    }
+
+    // a backup uploaded to remote storage
+    pub fn add_remote(&self, backup: &Backup) -> Result<(), Error> {
+        self.insert(backup)?;
+        self.connection
+            .execute(
+                "UPDATE backups SET remote = 1 WHERE name = ?1",
+                params![backup_name(backup)],
+            )
+            .map_err(catalog_error)?;
+        Ok(())
+    }
+
+    pub fn remove_local(&self, backup: &Backup) -> Result<(), Error> {
+        self.connection
+            .execute(
+                "UPDATE backups SET local_path = NULL WHERE name = ?1",
+                params![backup_name(backup)],
+            )
+            .map_err(catalog_error)?;
+        self.remove_unstored()
+    }
+
+    pub fn remove_remote(&self, backup: &Backup) -> Result<(), Error> {
+        self.connection
+            .execute(
+                "UPDATE backups SET remote = 0 WHERE name = ?1",
+                params![backup_name(backup)],
+            )
+            .map_err(catalog_error)?;
+        self.remove_unstored()
+    }
+
+    // local backups, newest first like get_all_local_backups
+    pub fn local_backups(&self) -> Result<Vec<Backup>, Error> {
+        let paths = self.query_strings(
+            "SELECT local_path FROM backups WHERE local_path IS NOT NULL ORDER BY time DESC",
+        )?;
+        Ok(paths
+            .iter()
+            .map(|path| parse_backup_from_path(Path::new(path)))
+            .collect())
+    }
+
+    // remote backups, oldest first like get_all_remote_backups. Remote storage is listed
+    // again when the last listing is older than REMOTE_LISTING_TTL, uploads and prunes of
+    // this host are recorded as they happen. An unreachable remote storage panics like
+    // get_all_remote_backups unless there is an earlier listing to fall back to.
+    pub fn remote_backups(&self, global_config: &GlobalConfig) -> Result<Vec<Backup>, Error> {
+        let listed = self.state(REMOTE_LISTED)?;
+        let fresh = listed.as_deref().is_some_and(|listed| {
+            DateTime::parse_from_rfc3339(listed).is_ok_and(|listed| {
+                Utc::now()
+                    .signed_duration_since(listed)
+                    .to_std()
+                    .unwrap_or_default()
+                    < REMOTE_LISTING_TTL
+            })
+        });
+
+        if !fresh {
+            match panic::catch_unwind(|| get_all_remote_backups(global_config)) {
+                Ok(remote_backups) => self.record_remote(&remote_backups)?,
+                Err(_) if listed.is_some() => {
+                    warn!("Remote storage is unavailable, using the remote backups of the catalog")
+                }
+                Err(e) => panic::resume_unwind(e),
+            }
+        }
+
+        let names =
+            self.query_strings("SELECT name FROM backups WHERE remote = 1 ORDER BY time")?;
+        Ok(names
+            .iter()
+            .map(|name| parse_backup_from_path(Path::new(name)))
+            .collect())
+    }
+
+    // all backups, of one app or of all of them, oldest first
+    pub fn entries(&self, app_name: Option<&str>) -> Result<Vec<CatalogEntry>, Error> {
+        let mut statement = self
+            .connection
+            .prepare(
+                "SELECT name, backup_type, time, base, size, local_path, remote, verified_at,
+                    verification
+                FROM backups WHERE ?1 IS NULL OR app_name = ?1 ORDER BY app_name, time",
+            )
+            .map_err(catalog_error)?;
+
+        let entries = statement
+            .query_map(params![app_name], |row| {
+                Ok(CatalogEntry {
+                    name: row.get(0)?,
+                    backup_type: match row.get::<_, String>(1)?.as_str() {
+                        "full" => BackupType::Full,
+                        _ => BackupType::Incremental,
+                    },
+                    time: row.get(2)?,
+                    base: row.get(3)?,
+                    size: row.get::<_, Option<i64>>(4)?.map(|size| size as u64),
+                    local_path: row.get::<_, Option<String>>(5)?.map(PathBuf::from),
+                    remote: row.get(6)?,
+                    verified_at: row.get(7)?,
+                    verification: row.get(8)?,
+                })
+            })
+            .map_err(catalog_error)?
+            .collect::<Result<Vec<CatalogEntry>, rusqlite::Error>>()
+            .map_err(catalog_error)?;
+
+        Ok(entries)
+    }
+
+    // the index of the files of a backup, see index.rs
+    pub fn index(&self, backup: &Backup) -> Result<Option<Tree>, Error> {
+        let content: Option<Vec<u8>> = self
+            .connection
+            .query_row(
+                "SELECT content FROM indexes WHERE backup = ?1",
+                params![backup_name(backup)],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(catalog_error)?;
+
+        content.map(|content| parse_index(&content)).transpose()
+    }
+
+    pub fn set_index(&self, backup: &Backup, tree: &Tree) -> Result<(), Error> {
+        self.connection
+            .execute(
+                "INSERT OR REPLACE INTO indexes (backup, content) VALUES (?1, ?2)",
+                params![backup_name(backup), index_to_bytes(tree)],
+            )
+            .map_err(catalog_error)?;
+        Ok(())
+    }
+
+    // the outcome of checking the files of a backup against its manifest
+    pub fn set_verification(
+        &self,
+        backup: &Backup,
+        result: Result<(), String>,
+    ) -> Result<(), Error> {
+        let verification = match result {
+            Ok(()) => "ok".to_string(),
+            Err(e) => e,
+        };
+        self.connection
+            .execute(
+                "UPDATE backups SET verified_at = ?2, verification = ?3 WHERE name = ?1",
+                params![
+                    backup_name(backup),
+                    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+                    verification
+                ],
+            )
+            .map_err(catalog_error)?;
+        Ok(())
+    }
+
+    fn insert(&self, backup: &Backup) -> Result<(), Error> {
+        let name = backup_name(backup);
+        let time = backup.time.to_rfc3339_opts(SecondsFormat::Nanos, true);
+        let inserted = self
+            .connection
+            .execute(
+                "INSERT OR IGNORE INTO backups (name, app_name, server_name, backup_type, time)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    name,
+                    backup.app_name,
+                    backup.server_name,
+                    backup.backup_type.as_str(),
+                    time
+                ],
+            )
+            .map_err(catalog_error)?;
+        if inserted == 0 {
+            return Ok(());
+        }
+
+        // a backup older than the newest one of its app and server is the new base of the
+        // next one
+        let next = self
+            .connection
+            .query_row(
+                "SELECT name FROM backups WHERE app_name = ?1 AND server_name = ?2 AND time > ?3
+                ORDER BY time LIMIT 1",
+                params![backup.app_name, backup.server_name, time],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()
+            .map_err(catalog_error)?;
+
+        self.update_base(name)?;
+        if let Some(next) = next {
+            self.update_base(&next)?;
+        }
+        Ok(())
+    }
+
+    // incremental backups are based on the backup of the app before them, made by the same
+    // server
+    fn update_base(&self, name: &str) -> Result<(), Error> {
+        self.connection
+            .execute(
+                "UPDATE backups SET base = (
+                    SELECT previous.name FROM backups AS previous
+                    WHERE previous.app_name = backups.app_name
+                        AND previous.server_name = backups.server_name
+                        AND previous.time < backups.time
+                    ORDER BY previous.time DESC LIMIT 1
+                ) WHERE name = ?1 AND backup_type = 'incremental'",
+                params![name],
+            )
+            .map_err(catalog_error)?;
+        Ok(())
+    }
+
+    // forgets backups which are neither in local nor in remote storage anymore, backups
+    // based on them get the backup before as their base
+    fn remove_unstored(&self) -> Result<(), Error> {
+        self.connection
+            .execute_batch(
+                "DELETE FROM backups WHERE local_path IS NULL AND remote = 0;
+                DELETE FROM indexes WHERE backup NOT IN (SELECT name FROM backups);",
+            )
+            .map_err(catalog_error)?;
+
+        let orphans = self.query_strings(
+            "SELECT name FROM backups
+            WHERE base IS NOT NULL AND base NOT IN (SELECT name FROM backups)",
+        )?;
+        for name in orphans {
+            self.update_base(&name)?;
+        }
+        Ok(())
+    }
+
+    fn state(&self, key: &str) -> Result<Option<String>, Error> {
+        self.connection
+            .query_row(
+                "SELECT value FROM state WHERE key = ?1",
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(catalog_error)
+    }
+
+    fn set_state(&self, key: &str) -> Result<(), Error> {
+        self.connection
+            .execute(
+                "INSERT OR REPLACE INTO state (key, value) VALUES (?1, ?2)",
+                params![key, Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)],
+            )
+            .map_err(catalog_error)?;
+        Ok(())
+    }
+
+    fn query_strings(&self, sql: &str) -> Result<Vec<String>, Error> {
+        let mut statement = self.connection.prepare(sql).map_err(catalog_error)?;
+        let strings = statement
+            .query_map([], |row| row.get(0))
+            .map_err(catalog_error)?
+            .collect::<Result<Vec<String>, rusqlite::Error>>()
+            .map_err(catalog_error)?;
+        Ok(strings)
+    }
+}
+
+// local backups from the catalog, newest first. Local storage is walked instead when the
+// catalog can't be read.
+pub fn local_backups(global_config: &GlobalConfig) -> Vec<Backup> {
+    let location = Path::new(&global_config.local_storage_location);
+    match Catalog::open(location).and_then(|catalog| catalog.local_backups()) {
+        Ok(backups) => backups,
+        Err(e) => {
+            warn!("Catalog unavailable, listing local storage: {}", e);
+            scan_local_backups(location)
+        }
+    }
+}
+
+// remote backups from the catalog, oldest first. Remote storage is listed instead when the
+// catalog can't be read.
+pub fn remote_backups(global_config: &GlobalConfig) -> Vec<Backup> {
+    let location = Path::new(&global_config.local_storage_location);
+    match Catalog::open(location).and_then(|catalog| catalog.remote_backups(global_config)) {
+        Ok(backups) => backups,
+        Err(e) => {
+            warn!("Catalog unavailable, listing remote storage: {}", e);
+            get_all_remote_backups(global_config)
+        }
+    }
+}
+
+// records a change in the catalog of local storage. Failing to is logged but not an error
+// of the operation, bkp catalog rebuild brings the catalog up to date again.
+pub fn update_catalog(
+    local_storage_location: &Path,
+    update: impl FnOnce(&Catalog) -> Result<(), Error>,
+) {
+    if let Err(e) = Catalog::open(local_storage_location).and_then(|catalog| update(&catalog)) {
+        error!("Error updating the catalog: {}", e);
+    }
+}
+
+// runs the migrations the catalog doesn't have yet, one bkp run at a time. A new catalog
+// is created here, so no other run migrates its tables meanwhile.
+fn migrate(connection: &Connection) -> Result<(), Error> {
+    let user_version = |connection: &Connection| {
+        connection
+            .pragma_query_value(None, "user_version", |row| row.get::<_, usize>(0))
+            .map_err(catalog_error)
+    };
+    if user_version(connection)? >= MIGRATIONS.len() {
+        return Ok(());
+    }
+
+    let transaction = Transaction::new_unchecked(connection, TransactionBehavior::Immediate)
+        .map_err(catalog_error)?;
+    // another run may have migrated it while this one waited for the transaction
+    let version = user_version(connection)?;
+    if version < MIGRATIONS.len() {
+        let exists = connection
+            .query_row(
+                "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE name = 'backups')",
+                [],
+                |row| row.get::<_, bool>(0),
+            )
+            .map_err(catalog_error)?;
+        match exists {
+            true => MIGRATIONS[version..]
+                .iter()
+                .try_for_each(|migration| connection.execute_batch(migration))
+                .map_err(catalog_error)?,
+            false => connection.execute_batch(SCHEMA).map_err(catalog_error)?,
+        }
+        connection
+            .pragma_update(None, "user_version", MIGRATIONS.len())
+            .map_err(catalog_error)?;
+    }
+    transaction.commit().map_err(catalog_error)
+}
+
+fn catalog_error(e: rusqlite::Error) -> Error {
+    Error::other(e)
+}
@@ -1,13 +1,48 @@
+use std::{
+    io::{self, Error},
+    path::{Path, PathBuf},
+    process::exit,
+};
+
+use bkp::{
+    actions::{list_catalog, log_backup_summary, rebuild_catalog},
+    backup_all, cat,
+    config::{get_all_configs, get_config_from_app_name},
+    configcheck::check_configs,
+    daemon::run_daemon,
+    diff,
+    diff::DiffTarget,
+    extract::{ConflictPolicy, RestoreOptions},
+    find, full_backup,
+    globalconfig::{find_global_config_path, load_global_config, GlobalConfig},
+    history, incremental_backup, list, ls,
+    mount::mount,
+    recover::{recover, RecoveryPoint},
+    restore, BackupType, Config,
+};
+use chrono::{DateTime, NaiveDate, Utc};
 use clap::{Args, Parser, Subcommand};
 use log::{error, info};
 
-use crate::actions::{full_backup, incremental_backup, list, restore};
+use crate::logger::create_logger;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
+
+    /// Global config file, overrides BKP_CONFIG and the default locations
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Waits for locks held by other bkp runs instead of failing
+    #[arg(long, global = true, overrides_with = "no_wait")]
+    wait: bool,
+
+    /// Fails right away if another bkp run holds a lock (default)
+    #[arg(long, global = true, overrides_with = "wait")]
+    no_wait: bool,
 }
 
 #[derive(Subcommand, Debug)]
@@ -16,18 +51,137 @@ enum Commands {
     List { app_name: Option<String> },
     /// Backs apps up according to config file
     Backup(Backup),
+    /// Runs backups of all apps according to their schedule
+    Daemon {
+        /// Number of apps backed up concurrently
+        #[arg(long, default_value_t = 1, value_parser = parse_jobs)]
+        jobs: usize,
+    },
+    /// Validates the global config and app configs
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+    /// Shows or rebuilds the catalog of backups in local and remote storage
+    Catalog {
+        #[command(subcommand)]
+        command: CatalogCommands,
+    },
+    /// Lists the files of a backup without restoring it
+    Ls {
+        app_name: String,
+        backup_name: String,
+        /// Only lists this file or the files below this directory
+        path: Option<PathBuf>,
+    },
+    /// Writes a file of a backup to stdout without restoring it
+    Cat {
+        app_name: String,
+        backup_name: String,
+        file: PathBuf,
+    },
+    /// Shows the files added, removed and modified since a backup
+    Diff {
+        app_name: String,
+        backup_name: String,
+        /// The later backup to compare with
+        #[arg(required_unless_present = "live")]
+        other_backup_name: Option<String>,
+
+        /// Compares with the files a backup would contain now
+        #[arg(long, conflicts_with = "other_backup_name")]
+        live: bool,
+
+        /// Shows a unified diff of modified text files up to 64 KiB
+        #[arg(long, short)]
+        unified: bool,
+    },
+    /// Lists the backups containing paths which match a glob, like 'etc/**/*.conf'
+    Find { app_name: String, pattern: String },
+    /// Shows the backups in which a file was added, modified or removed
+    History { app_name: String, file: PathBuf },
+    /// Mounts all backups read-only as /<app>/<server>/<backup time>/ until unmounted, needs root
+    Mount { mountpoint: PathBuf },
     /// Restores an app from a specific backup
     Restore {
         app_name: String,
         backup_name: String,
+
+        /// Lists the files which would be created, overwritten, renamed, skipped or deleted
+        #[arg(long)]
+        dry_run: bool,
+
+        /// What happens to existing files: overwrite, skip-existing, keep-newer or rename
+        #[arg(long, value_name = "POLICY", default_value = "overwrite")]
+        conflict: ConflictPolicy,
+
+        /// Moves the current app_root to a timestamped directory next to it before restoring
+        #[arg(long)]
+        move_aside: bool,
+    },
+    /// Lists the apps a server backed up and restores them with their stored configs
+    Recover {
+        /// The server_name of the backups
+        #[arg(long)]
+        server: String,
+
+        /// Recovers this app, can be repeated
+        #[arg(long = "app", value_name = "APP_NAME")]
+        apps: Vec<String>,
+
+        /// Recovers all apps of the server
+        #[arg(long, conflicts_with = "apps")]
+        all: bool,
+
+        /// Restores this backup instead of the latest one
+        #[arg(long, value_name = "BACKUP_NAME", conflicts_with_all = ["all", "before"])]
+        backup: Option<String>,
+
+        /// Restores the latest backup made before this time, RFC 3339 or a date in UTC
+        #[arg(long, value_name = "TIME", value_parser = parse_before)]
+        before: Option<DateTime<Utc>>,
+
+        /// Lists the files which would be restored without changing anything
+        #[arg(long)]
+        dry_run: bool,
     },
 }
 
+#[derive(Subcommand, Debug)]
+enum ConfigCommands {
+    /// Checks all configs and reports problems with file and line
+    Check,
+}
+
+#[derive(Subcommand, Debug)]
+enum CatalogCommands {
+    /// Lists the catalogued backups with location, size and last verification
+    List { app_name: Option<String> },
+    /// Recreates the catalog from the backups in local and remote storage
+    Rebuild,
+}
+
 #[derive(Debug, Args)]
 #[command(args_conflicts_with_subcommands = true)]
 struct Backup {
     #[command(subcommand)]
     command: Option<BackupTypes>,
+
+    /// Backs up all configured apps
+    #[arg(long)]
+    all: bool,
+
+    /// Runs incremental instead of full backups when used with --all
+    #[arg(long, requires = "all")]
+    incremental: bool,
+
+    /// Number of apps backed up concurrently
+    #[arg(long, default_value_t = 1, requires = "all", value_parser = parse_jobs)]
+    jobs: usize,
+
+    /// Number of concurrent uploads, defaults to --jobs
+    #[arg(long, requires = "all", value_parser = parse_jobs)]
+    upload_jobs: Option<usize>,
 }
 
 #[derive(Debug, Subcommand)]
@@ -36,9 +190,36 @@ enum BackupTypes {
     Incremental { app_name: String },
 }
 
-pub fn parse_args() -> () {
+pub fn parse_args() {
     let args = Cli::parse();
 
+    let global_config_path = match find_global_config_path(args.config.as_deref()) {
+        Ok(global_config_path) => global_config_path,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1)
+        }
+    };
+
+    // config check has to work with a broken global config, so it runs before loading it
+    if let Some(Commands::Config {
+        command: ConfigCommands::Check,
+    }) = &args.command
+    {
+        check_config(&global_config_path);
+        return;
+    }
+
+    let global_config = match load_global_config(&global_config_path) {
+        Ok(global_config) => global_config,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1)
+        }
+    };
+
+    create_logger(&global_config.log_file_location);
+
     // info!("{:?}", args);
 
     match &args.command {
@@ -50,12 +231,46 @@ pub fn parse_args() -> () {
                 Some(BackupTypes::Full { app_name }) => {
                     info!("Running full backup of {}", app_name);
 
-                    full_backup(app_name);
+                    let config = config_or_exit(&global_config, app_name);
+                    exit_on_error(full_backup(&global_config, &config, args.wait));
                 }
                 Some(BackupTypes::Incremental { app_name }) => {
                     info!("Running incremental backup of {}", app_name);
 
-                    incremental_backup(app_name);
+                    let config = config_or_exit(&global_config, app_name);
+                    exit_on_error(incremental_backup(&global_config, &config, args.wait));
+                }
+                None if backup.all => {
+                    let backup_type = match backup.incremental {
+                        true => BackupType::Incremental,
+                        false => BackupType::Full,
+                    };
+                    let configs = get_all_configs(&global_config);
+
+                    info!(
+                        "Running {:?} backup of {} apps with {} jobs",
+                        backup_type,
+                        configs.len(),
+                        backup.jobs
+                    );
+
+                    let summaries = backup_all(
+                        &global_config,
+                        &configs,
+                        &backup_type,
+                        backup.jobs,
+                        backup.upload_jobs.unwrap_or(backup.jobs),
+                        args.wait,
+                    );
+
+                    match summaries {
+                        Ok(summaries) => {
+                            if log_backup_summary(&summaries) > 0 {
+                                exit(1);
+                            }
+                        }
+                        Err(e) => exit_on_error(Err(e)),
+                    }
                 }
                 None => {
                     info!("Please specify backup type");
@@ -65,16 +280,198 @@ pub fn parse_args() -> () {
         Some(Commands::Restore {
             app_name,
             backup_name,
+            dry_run,
+            conflict,
+            move_aside,
         }) => {
             info!("Running restore of {} from {}", app_name, backup_name);
 
-            restore(app_name, backup_name);
+            let config = config_or_exit(&global_config, app_name);
+            let options = RestoreOptions {
+                dry_run: *dry_run,
+                conflict: *conflict,
+                move_aside: *move_aside,
+            };
+            exit_on_error(restore(
+                &global_config,
+                &config,
+                backup_name,
+                &options,
+                args.wait,
+            ));
+        }
+        Some(Commands::Recover {
+            server,
+            apps,
+            all,
+            backup,
+            before,
+            dry_run,
+        }) => {
+            let point = match (backup, before) {
+                (Some(backup), _) => RecoveryPoint::Backup(backup.clone()),
+                (_, Some(before)) => RecoveryPoint::Before(*before),
+                _ => RecoveryPoint::Latest,
+            };
+            let options = RestoreOptions {
+                dry_run: *dry_run,
+                ..Default::default()
+            };
+            exit_on_error(recover(
+                &global_config,
+                server,
+                apps,
+                *all,
+                &point,
+                &options,
+                args.wait,
+                &mut io::stdout().lock(),
+            ));
+        }
+        Some(Commands::Ls {
+            app_name,
+            backup_name,
+            path,
+        }) => {
+            let config = config_or_exit(&global_config, app_name);
+            exit_on_error(ls(
+                &global_config,
+                &config,
+                backup_name,
+                path.as_deref(),
+                &mut io::stdout().lock(),
+            ));
+        }
+        Some(Commands::Cat {
+            app_name,
+            backup_name,
+            file,
+        }) => {
+            let config = config_or_exit(&global_config, app_name);
+            exit_on_error(cat(
+                &global_config,
+                &config,
+                backup_name,
+                file,
+                &mut io::stdout().lock(),
+            ));
+        }
+        Some(Commands::Diff {
+            app_name,
+            backup_name,
+            other_backup_name,
+            live: _,
+            unified,
+        }) => {
+            let config = config_or_exit(&global_config, app_name);
+            let target = match other_backup_name {
+                Some(other_backup_name) => DiffTarget::Backup(other_backup_name.clone()),
+                None => DiffTarget::Live,
+            };
+            exit_on_error(diff(
+                &global_config,
+                &config,
+                backup_name,
+                &target,
+                *unified,
+                &mut io::stdout().lock(),
+            ));
+        }
+        Some(Commands::Find { app_name, pattern }) => {
+            let config = config_or_exit(&global_config, app_name);
+            exit_on_error(find(
+                &global_config,
+                &config,
+                pattern,
+                &mut io::stdout().lock(),
+            ));
+        }
+        Some(Commands::History { app_name, file }) => {
+            let config = config_or_exit(&global_config, app_name);
+            exit_on_error(history(
+                &global_config,
+                &config,
+                file,
+                &mut io::stdout().lock(),
+            ));
+        }
+        Some(Commands::Mount { mountpoint }) => {
+            exit_on_error(mount(&global_config, mountpoint));
+        }
+        Some(Commands::Daemon { jobs }) => {
+            exit_on_error(run_daemon(&global_config_path, *jobs));
+        }
+        Some(Commands::Catalog {
+            command: CatalogCommands::List { app_name },
+        }) => {
+            exit_on_error(list_catalog(
+                &global_config,
+                app_name.as_deref(),
+                &mut io::stdout().lock(),
+            ));
         }
+        Some(Commands::Catalog {
+            command: CatalogCommands::Rebuild,
+        }) => {
+            exit_on_error(rebuild_catalog(&global_config, args.wait));
+        }
+        Some(Commands::Config { .. }) => {}
         Some(Commands::List { app_name }) => {
-            list(app_name);
+            exit_on_error(list(&global_config, app_name));
         }
         None => {
             error!("No command");
         }
     }
 }
+
+// --before takes a full timestamp or a date, which means midnight UTC
+fn parse_before(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| DateTime::from_utc(date.and_hms_opt(0, 0, 0).unwrap(), Utc))
+        .map_err(|_| "must be an RFC 3339 time like 2024-05-01T12:00:00Z or a date".to_string())
+}
+
+// a limit of 0 jobs would never run anything
+fn parse_jobs(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(jobs) => Ok(jobs),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn config_or_exit(global_config: &GlobalConfig, app_name: &str) -> Config {
+    match get_config_from_app_name(global_config, app_name) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            exit(1);
+        }
+    }
+}
+
+fn exit_on_error(result: Result<(), Error>) {
+    if let Err(e) = result {
+        error!("{}", e);
+        exit(1);
+    }
+}
+
+fn check_config(global_config_path: &Path) {
+    let problems = check_configs(global_config_path);
+
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+
+    if !problems.is_empty() {
+        eprintln!("Found {} problems", problems.len());
+        exit(1);
+    }
+
+    println!("Config is valid");
+}
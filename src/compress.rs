@@ -1,83 +1,310 @@
 extern crate tar;
-use log::{error, info};
-use std::io::{self};
-use std::{fs::File, path::PathBuf};
-use tar::{Archive, Builder};
+use chrono::Utc;
+use log::{error, info, warn};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+};
+use tar::{Archive, Builder, EntryType, Header};
 
-use crate::backup::get_backup_path_with_extension;
-
-// todo: convert to Result
-pub fn compress_files(archive_path: &PathBuf, paths: &Vec<PathBuf>) {
-    println!("archive_path: {:?}", archive_path);
+use crate::{
+    backup::get_backup_path_with_extension,
+    config::Config,
+    extract::{
+        check_entry, is_special_file, report_refusals, unpack_special_file, Extraction,
+        PlannedAction, Refusal,
+    },
+    manifest::Manifest,
+    metadata::{configure_unpack, unpack_dir_xattrs, MetadataPolicy, MetadataWriter},
+    scripts::spawn_output_logger,
+    sources::{
+        append_virtual_entry_mark, is_virtual_entry, sqlite::restore_database, CommandOutput,
+    },
+};
 
+// paths are relative to source_root and are stored in the archive under that relative name,
+// so the process current dir is never changed and archives can be built from several threads.
+// renamed_files are (path on disk, name in archive) pairs, e.g. snapshots of databases,
+// the stdout of command_outputs is streamed into the archive
+pub fn compress_files(
+    archive_path: &Path,
+    source_root: &Path,
+    paths: &[PathBuf],
+    renamed_files: &[(PathBuf, PathBuf)],
+    command_outputs: &[CommandOutput],
+    metadata: &MetadataPolicy,
+) -> Result<(), io::Error> {
     let tar_archive_path = get_backup_path_with_extension(archive_path, ".tar");
 
-    let tar_file_writer = match File::create(&tar_archive_path) {
-        Ok(file) => file,
-        Err(e) => panic!("Error creating archive_path: {}", e),
-    };
+    let tar_file_writer = File::create(&tar_archive_path)?;
 
     info!("Creating archive: {}", tar_archive_path.display());
 
-    let mut tar_builder = Builder::new(tar_file_writer.try_clone().unwrap());
+    let mut tar_builder = Builder::new(tar_file_writer);
+    let mut metadata_writer = MetadataWriter::new(metadata);
+    metadata_writer.configure(&mut tar_builder);
 
     for path in paths {
         info!("Adding path to archive: {}", path.display());
-        match tar_builder.append_path(path) {
+        match metadata_writer.append(&mut tar_builder, &source_root.join(path), path) {
             Ok(_) => (),
             Err(e) => error!("Error appending path to archive: {}", e),
         }
     }
 
-    match tar_builder.finish() {
-        Ok(_) => (),
-        Err(e) => {
-            error!("Error finishing archive: {}", e);
-            return;
-        }
+    for (path, name) in renamed_files {
+        info!("Adding {} to archive as {}", path.display(), name.display());
+        metadata_writer
+            .append_renamed(&mut tar_builder, path, name)
+            .map_err(|e| append_error(name, e))?;
+    }
+
+    for command_output in command_outputs {
+        info!(
+            "Adding output of {:?} to archive as {}",
+            command_output.argv,
+            command_output.name.display()
+        );
+        append_command_output(&mut tar_builder, command_output)
+            .map_err(|e| append_error(&command_output.name, e))?;
     }
 
-    let mut tar_file_reader = File::open(&tar_archive_path).unwrap();
+    metadata_writer
+        .append_manifest(&mut tar_builder)
+        .map_err(|e| append_error(Path::new("manifest"), e))?;
+
+    tar_builder.finish()?;
+
+    let mut tar_file_reader = File::open(&tar_archive_path)?;
 
     let gz_archive = get_backup_path_with_extension(archive_path, ".tar.gz");
 
-    let tar_gz_file = File::create(gz_archive).unwrap();
+    let tar_gz_file = File::create(gz_archive)?;
     let mut gz_encoder = flate2::write::GzEncoder::new(tar_gz_file, flate2::Compression::default());
 
-    io::copy(&mut tar_file_reader, &mut gz_encoder).unwrap();
+    io::copy(&mut tar_file_reader, &mut gz_encoder)?;
 
-    gz_encoder.try_finish().unwrap();
-    gz_encoder.finish().unwrap();
+    gz_encoder.try_finish()?;
+    gz_encoder.finish()?;
 
-    std::fs::remove_file(&tar_archive_path).unwrap();
+    std::fs::remove_file(&tar_archive_path)?;
 
     info!("Archive created successfully");
+    Ok(())
+}
+
+fn append_error(name: &Path, e: io::Error) -> io::Error {
+    io::Error::new(
+        e.kind(),
+        format!("Error appending {} to archive: {}", name.display(), e),
+    )
+}
+
+fn append_command_output(
+    tar_builder: &mut Builder<File>,
+    command_output: &CommandOutput,
+) -> Result<(), io::Error> {
+    let argv = &command_output.argv;
+    if argv.is_empty() {
+        return Err(io::Error::other("empty command"));
+    }
+
+    let mut child = Command::new(&argv[0])
+        .args(&argv[1..])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+
+    let status = thread::scope(|scope| {
+        spawn_output_logger(scope, stderr, "source", true);
+
+        // stdout is dropped by append_stream, a command still writing then gets EPIPE
+        // instead of blocking the wait below
+        let appended = append_stream(tar_builder, &command_output.name, stdout);
+        let status = child.wait()?;
+        let size = appended?;
+        info!("Added {} bytes", size);
+
+        Ok::<_, io::Error>(status)
+    })?;
+
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "{:?} failed with {}",
+            argv, status
+        )));
+    }
+
+    Ok(())
 }
 
-pub fn decompress_archive(archive: PathBuf, app_root: PathBuf) {
-    let mut tar_gz_file_reader = File::open(&archive).unwrap();
+// tar headers hold the size of the entry, which is only known once the stream ended. The
+// entry is appended empty and its header is patched after the data was written, which
+// works because the tar file is written to disk before it is compressed.
+fn append_stream<R: Read>(
+    tar_builder: &mut Builder<File>,
+    name: &Path,
+    mut reader: R,
+) -> Result<u64, io::Error> {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(EntryType::Regular);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp() as u64);
+    header.set_size(0);
+    append_virtual_entry_mark(tar_builder)?;
+    tar_builder.append_data(&mut header, name, io::empty())?;
+
+    let tar_file = tar_builder.get_mut();
+    let data_start = tar_file.stream_position()?;
+    let size = io::copy(&mut reader, tar_file)?;
+    let padding = (BLOCK_SIZE - size % BLOCK_SIZE) % BLOCK_SIZE;
+    tar_file.write_all(&vec![0; padding as usize])?;
+    let data_end = tar_file.stream_position()?;
+
+    header.set_size(size);
+    header.set_cksum();
+    tar_file.seek(SeekFrom::Start(data_start - BLOCK_SIZE))?;
+    tar_file.write_all(header.as_bytes())?;
+    tar_file.seek(SeekFrom::Start(data_end))?;
 
-    let tar_file_path = archive.with_file_name("tmp.tar");
-    let tar_file_writer = match File::create(&tar_file_path) {
-        Ok(file) => file,
-        Err(e) => panic!("Error creating archive_path: {}", e),
-    };
+    Ok(size)
+}
+
+const BLOCK_SIZE: u64 = 512;
 
-    let mut gz_decoder = flate2::write::GzDecoder::new(&tar_file_writer);
+// the archive is decoded as a stream, without a shared temporary tar file next to it.
+// Every entry is checked before it is unpacked into target, refused entries are skipped and
+// reported, and make the restore fail. sqlite snapshots are swapped in atomically.
+pub fn decompress_archive(
+    archive: PathBuf,
+    target: PathBuf,
+    config: &Config,
+    extraction: &mut Extraction,
+) -> Result<(), io::Error> {
+    let tar_gz_file_reader = File::open(&archive)?;
 
-    io::copy(&mut tar_gz_file_reader, &mut gz_decoder).unwrap();
+    let gz_decoder = flate2::read::GzDecoder::new(tar_gz_file_reader);
 
-    gz_decoder.try_finish().unwrap();
-    gz_decoder.finish().unwrap();
+    let mut tar_archive = Archive::new(gz_decoder);
+    configure_unpack(&mut tar_archive, &config.metadata);
 
-    let tar_file_reader = File::open(&tar_file_path).unwrap();
+    let (refusals, manifest) = unpack_entries(&mut tar_archive, &target, config, extraction)?;
+    report_refusals(&archive, &refusals)?;
 
-    let mut tar_archive = Archive::new(tar_file_reader);
+    match manifest {
+        Some(manifest) => extraction.manifest.merge(manifest),
+        None => warn!(
+            "{} has no manifest, its files can't be verified",
+            archive.display()
+        ),
+    }
 
-    match tar_archive.unpack(app_root) {
-        Ok(_) => println!("Backup unpacked successfully"),
-        Err(e) => println!("Error unpacking archive: {}", e),
+    if !extraction.options.dry_run {
+        info!("Backup unpacked successfully");
+    }
+    Ok(())
+}
+
+fn unpack_entries<R: Read>(
+    tar_archive: &mut Archive<R>,
+    target: &Path,
+    config: &Config,
+    extraction: &mut Extraction,
+) -> Result<(Vec<Refusal>, Option<Manifest>), io::Error> {
+    let databases = config
+        .sources
+        .sqlite
+        .iter()
+        .map(|source| PathBuf::from(&source.path))
+        .collect::<Vec<PathBuf>>();
+
+    let mut refusals: Vec<Refusal> = Vec::new();
+    let mut manifest: Option<Manifest> = None;
+
+    for entry in tar_archive.entries()? {
+        let mut entry = entry?;
+
+        let path = match check_entry(&entry, target, &config.restore) {
+            Ok(Some(path)) => path,
+            Ok(None) => continue,
+            Err(refusal) => {
+                refusals.push(refusal);
+                continue;
+            }
+        };
+
+        if is_virtual_entry(&mut entry)? {
+            if path == Manifest::entry_name() {
+                manifest = Some(Manifest::read(&mut entry)?);
+            }
+            continue;
+        }
+
+        let action = extraction.decide(&entry, target, &path);
+        if extraction.options.dry_run || action == PlannedAction::Skip {
+            continue;
+        }
+        // staged renames happen when the files are swapped into place
+        if action == PlannedAction::Rename && !extraction.is_staged() {
+            let existing = target.join(&path);
+            fs::rename(&existing, extraction.aside_path(&existing))?;
+        }
+
+        if databases.contains(&path) {
+            restore_database(&mut entry, &target.join(&path))?;
+        } else if is_special_file(entry.header().entry_type()) {
+            unpack_special_file(&entry, &target.join(&path))?;
+        } else {
+            entry.unpack_in(target)?;
+            if entry.header().entry_type().is_dir() {
+                unpack_dir_xattrs(&mut entry, &target.join(&path), &config.metadata)?;
+            }
+        }
+    }
+
+    Ok((refusals, manifest))
+}
+
+// calls read with the entry name of the archive, None if there is no such entry
+pub fn read_archive_entry<T>(
+    archive: &Path,
+    name: &Path,
+    read: impl FnOnce(&mut dyn Read) -> Result<T, io::Error>,
+) -> Result<Option<T>, io::Error> {
+    find_archive_entry(archive, name, false, read)
+}
+
+// like read_archive_entry, for entries produced by bkp like dumps
+pub fn read_virtual_entry<T>(
+    archive: &Path,
+    name: &Path,
+    read: impl FnOnce(&mut dyn Read) -> Result<T, io::Error>,
+) -> Result<Option<T>, io::Error> {
+    find_archive_entry(archive, name, true, read)
+}
+
+fn find_archive_entry<T>(
+    archive: &Path,
+    name: &Path,
+    only_virtual: bool,
+    read: impl FnOnce(&mut dyn Read) -> Result<T, io::Error>,
+) -> Result<Option<T>, io::Error> {
+    let gz_decoder = flate2::read::GzDecoder::new(File::open(archive)?);
+    let mut tar_archive = Archive::new(gz_decoder);
+
+    for entry in tar_archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()? == name && (!only_virtual || is_virtual_entry(&mut entry)?) {
+            return read(&mut entry).map(Some);
+        }
     }
 
-    std::fs::remove_file(&tar_file_path).unwrap();
+    Ok(None)
 }
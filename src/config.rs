@@ -1,44 +1,159 @@
-use std::{path::PathBuf, process::exit};
+use std::{
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+};
 
 use log::error;
 use serde::Deserialize;
+use toml::value::Table;
 
 use crate::{
-    globalconfig::GLOBAL_CONFIG,
+    extract::RestorePolicy,
+    globalconfig::GlobalConfig,
+    metadata::MetadataPolicy,
+    scripts::Script,
+    sources::Sources,
     storage::fs::{list_files_in_dir, read_file_to_string},
 };
 
+// fields with a serde default are optional, they can also be set for all apps
+// in the [defaults] section of the global config
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
     pub app_name: String,
+    #[serde(default = "get_hostname")]
     pub server_name: String,
 
     pub app_root: String,
     // pub server_name: String,
+    // an app can also consist of sources only
+    #[serde(default)]
     pub included_paths: Vec<String>,
+    #[serde(default)]
     pub excluded_paths: Vec<String>,
+    #[serde(default)]
+    pub sources: Sources,
+    #[serde(default)]
+    pub metadata: MetadataPolicy,
+    #[serde(default)]
+    pub restore: RestorePolicy,
+
+    // empty script means no script
+    #[serde(default)]
+    pub pre_backup_script: Script,
+    #[serde(default)]
+    pub post_backup_script: Script,
+    #[serde(default)]
+    pub pre_restore_script: Script,
+    #[serde(default)]
+    pub post_restore_script: Script,
+    // on_failure_script runs after a failed backup, finally_script after every backup
+    // that got as far as the pre backup script, whatever the outcome
+    #[serde(default)]
+    pub on_failure_script: Script,
+    #[serde(default)]
+    pub finally_script: Script,
 
-    pub pre_backup_script: String,
-    pub post_backup_script: String,
-    pub pre_restore_script: String,
-    pub post_restore_script: String,
+    // apply to all hooks which don't set their own. Timeouts are in seconds, no timeout by
+    // default, relative working directories are relative to app_root
+    #[serde(default)]
+    pub script_timeout: Option<u64>,
+    #[serde(default = "default_script_shell")]
+    pub script_shell: String,
+    #[serde(default)]
+    pub script_working_dir: Option<String>,
 
+    #[serde(default = "default_keep_full_local_backups")]
     pub keep_full_local_backups: i16,
+    #[serde(default = "default_keep_full_remote_backups")]
     pub keep_full_remote_backups: i16,
+
+    // cron expressions used by `bkp daemon`, e.g. '0 3 * * *'
+    pub schedule: Option<String>,
+    pub incremental_schedule: Option<String>,
+    // prunes old backups, besides the prune after every full backup
+    pub prune_schedule: Option<String>,
+}
+
+pub fn get_hostname() -> String {
+    gethostname::gethostname().to_string_lossy().to_string()
+}
+
+fn default_script_shell() -> String {
+    String::from("sh")
+}
+
+// retention when neither the app config nor the defaults set it
+pub const DEFAULT_KEEP_FULL_LOCAL_BACKUPS: i16 = 1;
+pub const DEFAULT_KEEP_FULL_REMOTE_BACKUPS: i16 = 5;
+
+fn default_keep_full_local_backups() -> i16 {
+    DEFAULT_KEEP_FULL_LOCAL_BACKUPS
+}
+
+fn default_keep_full_remote_backups() -> i16 {
+    DEFAULT_KEEP_FULL_REMOTE_BACKUPS
+}
+
+// values set in the app config take precedence over the defaults. Tables like [sources]
+// and [metadata] are merged key by key, any other value, arrays included, is replaced.
+pub fn merge_defaults(app_config: &Table, defaults: &Table) -> Table {
+    let mut config = defaults.clone();
+
+    for (key, value) in app_config {
+        let merged = match (config.get(key), value) {
+            (Some(toml::Value::Table(default)), toml::Value::Table(table)) => {
+                toml::Value::Table(merge_defaults(table, default))
+            }
+            _ => value.clone(),
+        };
+        config.insert(key.clone(), merged);
+    }
+
+    config
+}
+
+pub fn resolve_config(app_config: &Table, defaults: &Table) -> Result<Config, toml::de::Error> {
+    toml::Value::Table(merge_defaults(app_config, defaults)).try_into()
 }
 
-fn parse_configs(path: PathBuf) -> Vec<Config> {
-    let config_files = list_files_in_dir(path);
+pub fn parse_config_with_defaults(
+    content: &str,
+    defaults: &Table,
+) -> Result<Config, toml::de::Error> {
+    let app_config: Table = toml::from_str(content)?;
+    resolve_config(&app_config, defaults)
+}
+
+pub fn parse_config(global_config: &GlobalConfig, path: &Path) -> Result<Config, Error> {
+    let config = read_file_to_string(path)?;
+    parse_config_with_defaults(config.as_str(), &global_config.defaults)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
 
+// a broken config file only affects its own app, it is logged and skipped so
+// commands for other apps keep working. `bkp config check` reports the details.
+fn parse_configs(global_config: &GlobalConfig, path: PathBuf) -> Vec<Config> {
     let mut configs: Vec<Config> = Vec::new();
 
-    for config_file in config_files.unwrap() {
-        let config = read_file_to_string(config_file.as_path());
-        match toml::from_str(config.as_str()) {
+    for (index, app_config) in global_config.apps.iter().enumerate() {
+        match resolve_config(app_config, &global_config.defaults) {
+            Ok(config) => configs.push(config),
+            Err(e) => {
+                error!(
+                    "Error parsing app {} of the global config: {}",
+                    index + 1,
+                    e
+                );
+            }
+        }
+    }
+
+    for config_file in get_config_files(&path) {
+        match parse_config(global_config, config_file.as_path()) {
             Ok(config) => configs.push(config),
             Err(e) => {
-                error!("Error parsing config file: {}", e);
-                exit(1)
+                error!("Error parsing config file {}: {}", config_file.display(), e);
             }
         }
     }
@@ -46,18 +161,66 @@ fn parse_configs(path: PathBuf) -> Vec<Config> {
     configs
 }
 
-pub fn get_all_configs() -> Vec<Config> {
-    parse_configs(PathBuf::from(GLOBAL_CONFIG.config_files_location.clone()))
+// like a conf.d directory, only *.toml files are read, in name order.
+// A missing directory is fine when all apps are defined in the global config.
+pub fn get_config_files(path: &Path) -> Vec<PathBuf> {
+    if !path.exists() {
+        return Vec::new();
+    }
+
+    let mut config_files = list_files_in_dir(path.to_path_buf())
+        .unwrap()
+        .into_iter()
+        .filter(|f| f.extension().is_some_and(|e| e == "toml"))
+        .collect::<Vec<PathBuf>>();
+
+    config_files.sort();
+
+    config_files
+}
+
+pub fn get_all_configs(global_config: &GlobalConfig) -> Vec<Config> {
+    parse_configs(
+        global_config,
+        PathBuf::from(global_config.config_files_location.clone()),
+    )
 }
 
-pub fn get_config_from_app_name(app_name: &String) -> Config {
-    let configs = parse_configs(PathBuf::from(GLOBAL_CONFIG.config_files_location.clone()));
+pub fn get_config_from_app_name(
+    global_config: &GlobalConfig,
+    app_name: &str,
+) -> Result<Config, Error> {
+    let configs = get_all_configs(global_config);
 
     for config in configs {
-        if config.app_name == *app_name {
-            return config;
+        if config.app_name == app_name {
+            return Ok(config);
+        }
+    }
+
+    Err(Error::new(
+        ErrorKind::NotFound,
+        format!("No config found for app_name: {}", app_name),
+    ))
+}
+
+// the app config as written, merged with the defaults. Unlike Config it only has the
+// fields which are set, not the serde defaults.
+pub fn get_config_table(global_config: &GlobalConfig, app_name: &str) -> Option<Table> {
+    let mut app_configs = global_config.apps.clone();
+    let path = PathBuf::from(global_config.config_files_location.clone());
+    for config_file in get_config_files(&path) {
+        // broken config files are skipped like in parse_configs
+        if let Some(app_config) = read_file_to_string(&config_file)
+            .ok()
+            .and_then(|content| toml::from_str::<Table>(&content).ok())
+        {
+            app_configs.push(app_config);
         }
     }
 
-    panic!("No config found for app_name: {}", app_name);
+    app_configs
+        .into_iter()
+        .find(|app_config| app_config.get("app_name").and_then(|v| v.as_str()) == Some(app_name))
+        .map(|app_config| merge_defaults(&app_config, &global_config.defaults))
 }
@@ -0,0 +1,600 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use glob::glob;
+use serde::Deserialize;
+use toml::{Spanned, Value};
+
+use crate::{
+    config::{get_config_files, parse_config_with_defaults, resolve_config, Config},
+    daemon::parse_schedule,
+    globalconfig::{load_global_config, GlobalConfig},
+    scripts::Script,
+    sources::Sources,
+    storage::fs::read_file_to_string,
+};
+
+#[derive(Debug, Clone)]
+pub struct ConfigProblem {
+    pub file: PathBuf,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+fn format_location(file: &Path, line: Option<usize>) -> String {
+    match line {
+        Some(line) => format!("{}:{}", file.display(), line),
+        None => file.display().to_string(),
+    }
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}",
+            format_location(&self.file, self.line),
+            self.message
+        )
+    }
+}
+
+type SpannedTable = BTreeMap<String, Spanned<Value>>;
+
+// the lines of the keys in the nested tables of an app config, tables defined with a
+// [header] have no span of their own so their keys are read one level down
+#[derive(Deserialize, Default)]
+struct NestedSpans {
+    #[serde(default)]
+    sources: SourceSpans,
+    #[serde(default)]
+    metadata: SpannedTable,
+    #[serde(default)]
+    restore: SpannedTable,
+}
+
+#[derive(Deserialize, Default)]
+struct SourceSpans {
+    #[serde(default)]
+    sqlite: Vec<SpannedTable>,
+    #[serde(default)]
+    dump: Vec<SpannedTable>,
+    #[serde(default)]
+    command: Vec<SpannedTable>,
+}
+
+#[derive(Deserialize)]
+struct InlineAppSpans<T> {
+    #[serde(default = "Vec::new")]
+    apps: Vec<T>,
+}
+
+// line of every key set in a config, by its dotted path, e.g. "sources.dump[0].name"
+#[derive(Default)]
+struct KeyLines(HashMap<String, usize>);
+
+impl KeyLines {
+    fn get(&self, key: &str) -> Option<usize> {
+        self.0.get(key).copied()
+    }
+
+    fn insert_table(&mut self, content: &str, prefix: &str, table: &SpannedTable) {
+        for (key, value) in table {
+            // tables have an empty span
+            if value.end() > value.start() {
+                let line = content[..value.start()].matches('\n').count() + 1;
+                self.0.insert(format!("{}{}", prefix, key), line);
+            }
+        }
+    }
+
+    fn insert_nested(&mut self, content: &str, nested: &NestedSpans) {
+        self.insert_table(content, "metadata.", &nested.metadata);
+        self.insert_table(content, "restore.", &nested.restore);
+        for (kind, tables) in [
+            ("sqlite", &nested.sources.sqlite),
+            ("dump", &nested.sources.dump),
+            ("command", &nested.sources.command),
+        ] {
+            for (index, table) in tables.iter().enumerate() {
+                self.insert_table(content, &format!("sources.{}[{}].", kind, index), table);
+            }
+        }
+    }
+
+    // a config that doesn't parse has no lines, its parse error is reported instead
+    fn from_file(content: &str) -> KeyLines {
+        let mut lines = KeyLines::default();
+        if let Ok(table) = toml::from_str::<SpannedTable>(content) {
+            lines.insert_table(content, "", &table);
+        }
+        if let Ok(nested) = toml::from_str::<NestedSpans>(content) {
+            lines.insert_nested(content, &nested);
+        }
+        lines
+    }
+
+    // the lines of each [[apps]] entry of the global config
+    fn from_inline_apps(content: &str) -> Vec<KeyLines> {
+        let tables = toml::from_str::<InlineAppSpans<SpannedTable>>(content)
+            .map(|spans| spans.apps)
+            .unwrap_or_default();
+        let nested = toml::from_str::<InlineAppSpans<NestedSpans>>(content)
+            .map(|spans| spans.apps)
+            .unwrap_or_default();
+
+        tables
+            .iter()
+            .enumerate()
+            .map(|(index, table)| {
+                let mut lines = KeyLines::default();
+                lines.insert_table(content, "", table);
+                if let Some(nested) = nested.get(index) {
+                    lines.insert_nested(content, nested);
+                }
+                lines
+            })
+            .collect()
+    }
+}
+
+// collects problems of one config file, pointing them at the line where the key is set
+struct FileChecker<'a> {
+    file: &'a Path,
+    lines: KeyLines,
+    problems: &'a mut Vec<ConfigProblem>,
+}
+
+impl FileChecker<'_> {
+    fn key_line(&self, key: &str) -> Option<usize> {
+        self.lines.get(key)
+    }
+
+    fn report(&mut self, key: Option<&str>, message: String) {
+        let line = key.and_then(|key| self.key_line(key));
+        self.problems.push(ConfigProblem {
+            file: self.file.to_path_buf(),
+            line,
+            message,
+        });
+    }
+
+    fn check_parsed<T>(&mut self, parsed: Result<T, toml::de::Error>) -> Option<T> {
+        match parsed {
+            Ok(parsed) => Some(parsed),
+            Err(e) => {
+                self.problems.push(ConfigProblem {
+                    file: self.file.to_path_buf(),
+                    line: e.line_col().map(|(line, _)| line + 1),
+                    message: e.to_string(),
+                });
+                None
+            }
+        }
+    }
+
+    fn check_dir(&mut self, key: &str, path: &str) {
+        if !Path::new(path).is_dir() {
+            self.report(
+                Some(key),
+                format!("{} '{}' is not an existing directory", key, path),
+            );
+        }
+    }
+
+    fn check_script(&mut self, key: &str, script: &Script, app_root: &str) {
+        if let Some(working_dir) = &script.working_dir {
+            self.check_dir(
+                key,
+                &Path::new(app_root).join(working_dir).to_string_lossy(),
+            );
+        }
+
+        // shell commands can be anything, so only the program itself is checked
+        if let Some(command) = script.program() {
+            self.check_program(key, command);
+        }
+    }
+
+    fn check_program(&mut self, key: &str, command: &str) {
+        // commands without a path are looked up in PATH
+        if !command.contains('/') {
+            return;
+        }
+
+        let path = Path::new(command);
+        if !path.is_file() {
+            self.report(Some(key), format!("{} '{}' does not exist", key, command));
+        } else if !is_executable(path) {
+            self.report(
+                Some(key),
+                format!("{} '{}' is not executable", key, command),
+            );
+        }
+    }
+
+    fn check_sources(&mut self, sources: &Sources, app_root: &str) {
+        let mut names: HashSet<String> = HashSet::new();
+
+        for (index, source) in sources.sqlite.iter().enumerate() {
+            let key = format!("sources.sqlite[{}].path", index);
+            if Path::new(&source.path).is_absolute() {
+                self.report(
+                    Some(&key),
+                    format!("{} '{}' must be relative to app_root", key, source.path),
+                );
+            } else if !source.db_path(app_root).is_file() {
+                self.report(
+                    Some(&key),
+                    format!("{} '{}' is not an existing file", key, source.path),
+                );
+            }
+        }
+
+        let dumps = sources
+            .dump
+            .iter()
+            .map(|source| ("dump", &source.name, &source.command));
+        let commands = sources
+            .command
+            .iter()
+            .map(|source| ("command", &source.name, &source.command));
+        let mut indexes: HashMap<&str, usize> = HashMap::new();
+
+        for (kind, name, command) in dumps.chain(commands) {
+            let index = indexes.entry(kind).or_default();
+            let prefix = format!("sources.{}[{}]", kind, index);
+            *index += 1;
+
+            // the name is a file name in the archive
+            let name_key = format!("{}.name", prefix);
+            if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+                self.report(
+                    Some(&name_key),
+                    format!("{} '{}' must be a file name without '/'", name_key, name),
+                );
+            } else if !names.insert(format!("{}/{}", kind, name)) {
+                self.report(
+                    Some(&name_key),
+                    format!("{} '{}' is already used", name_key, name),
+                );
+            }
+
+            self.check_command(&format!("{}.command", prefix), command);
+        }
+
+        for (index, source) in sources.dump.iter().enumerate() {
+            // without a restore command the dump is kept but not restored
+            if !source.restore_command.is_empty() {
+                self.check_command(
+                    &format!("sources.dump[{}].restore_command", index),
+                    &source.restore_command,
+                );
+            }
+        }
+    }
+
+    fn check_command(&mut self, key: &str, command: &[String]) {
+        match command.first() {
+            Some(program) => self.check_program(key, program),
+            None => self.report(Some(key), format!("{} must not be empty", key)),
+        }
+    }
+
+    fn check_retention(&mut self, key: &str, value: i16) {
+        if value < 0 {
+            self.report(
+                Some(key),
+                format!("{} must not be negative, found {}", key, value),
+            );
+        }
+    }
+
+    fn check_name(&mut self, key: &str, value: &str) {
+        if !is_valid_name(value) {
+            self.report(
+                Some(key),
+                format!(
+                    "{} '{}' must be non empty and must not contain '_' or '/'",
+                    key, value
+                ),
+            );
+        }
+    }
+}
+
+// backup file names are split on '_' to get the app and server name back
+fn is_valid_name(value: &str) -> bool {
+    !value.is_empty() && !value.contains('_') && !value.contains('/')
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    path.metadata()
+        .map(|m| m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(_path: &Path) -> bool {
+    true
+}
+
+fn check_global_config(path: &Path, problems: &mut Vec<ConfigProblem>) -> Option<GlobalConfig> {
+    let content = match read_file_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            problems.push(ConfigProblem {
+                file: path.to_path_buf(),
+                line: None,
+                message: format!("Unable to read file: {}", e),
+            });
+            return None;
+        }
+    };
+
+    let mut checker = FileChecker {
+        file: path,
+        lines: KeyLines::from_file(&content),
+        problems,
+    };
+
+    let mut global_config: GlobalConfig = checker.check_parsed(toml::from_str(&content))?;
+
+    let explicit_config_files_location = !global_config.config_files_location.is_empty();
+    if !explicit_config_files_location {
+        // resolved the same way as when loading the global config
+        global_config.config_files_location = load_global_config(path)
+            .map(|global_config| global_config.config_files_location)
+            .unwrap_or_default();
+    }
+
+    // the default conf.d may be missing when all apps are defined in the global config
+    let required = explicit_config_files_location || global_config.apps.is_empty();
+    if required || Path::new(&global_config.config_files_location).exists() {
+        checker.check_dir(
+            "config_files_location",
+            &global_config.config_files_location,
+        );
+    }
+    checker.check_dir(
+        "local_storage_location",
+        &global_config.local_storage_location,
+    );
+
+    let log_dir = Path::new(&global_config.log_file_location).parent();
+    if !log_dir.is_some_and(|dir| dir.as_os_str().is_empty() || dir.is_dir()) {
+        checker.report(
+            Some("log_file_location"),
+            format!(
+                "directory of log_file_location '{}' does not exist",
+                global_config.log_file_location
+            ),
+        );
+    }
+
+    if !global_config.remote_storage_address.starts_with("http://")
+        && !global_config.remote_storage_address.starts_with("https://")
+    {
+        checker.report(
+            Some("remote_storage_address"),
+            format!(
+                "remote_storage_address '{}' must start with http:// or https://",
+                global_config.remote_storage_address
+            ),
+        );
+    }
+
+    Some(global_config)
+}
+
+fn check_app_config_file(
+    global_config: &GlobalConfig,
+    path: &Path,
+    problems: &mut Vec<ConfigProblem>,
+) -> Option<(Config, Option<usize>)> {
+    let content = match read_file_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            problems.push(ConfigProblem {
+                file: path.to_path_buf(),
+                line: None,
+                message: format!("Unable to read file: {}", e),
+            });
+            return None;
+        }
+    };
+
+    let mut checker = FileChecker {
+        file: path,
+        lines: KeyLines::from_file(&content),
+        problems,
+    };
+
+    let parsed = parse_config_with_defaults(&content, &global_config.defaults);
+    let config = checker.check_parsed(parsed)?;
+    check_app_config(checker, config)
+}
+
+fn check_inline_app_configs(
+    global_config: &GlobalConfig,
+    path: &Path,
+    problems: &mut Vec<ConfigProblem>,
+) -> Vec<(Config, Option<usize>)> {
+    let content = read_file_to_string(path).unwrap_or_default();
+    let mut app_lines = KeyLines::from_inline_apps(&content).into_iter();
+
+    let mut configs: Vec<(Config, Option<usize>)> = Vec::new();
+
+    for app_config in &global_config.apps {
+        let mut checker = FileChecker {
+            file: path,
+            lines: app_lines.next().unwrap_or_default(),
+            problems: &mut *problems,
+        };
+
+        let parsed = resolve_config(app_config, &global_config.defaults);
+        let Some(config) = checker.check_parsed(parsed) else {
+            continue;
+        };
+
+        if let Some(config) = check_app_config(checker, config) {
+            configs.push(config);
+        }
+    }
+
+    configs
+}
+
+// returns the config with the line of its app_name, used to report duplicates
+fn check_app_config(mut checker: FileChecker, config: Config) -> Option<(Config, Option<usize>)> {
+    checker.check_name("app_name", &config.app_name);
+    if checker.key_line("server_name").is_some() {
+        checker.check_name("server_name", &config.server_name);
+    } else if !is_valid_name(&config.server_name) {
+        // set in [defaults] or not at all, then it is the hostname
+        checker.report(
+            None,
+            format!(
+                "server_name '{}' from [defaults] or the hostname must be non empty and \
+                 must not contain '_' or '/'",
+                config.server_name
+            ),
+        );
+    }
+    checker.check_dir("app_root", &config.app_root);
+    checker.check_sources(&config.sources, &config.app_root);
+
+    for included_path in &config.included_paths {
+        let pattern = config.app_root.clone() + included_path.as_str();
+        match glob(&pattern) {
+            Ok(mut entries) => {
+                if entries.next().is_none() {
+                    checker.report(
+                        Some("included_paths"),
+                        format!("included path '{}' doesn't match any file", included_path),
+                    );
+                }
+            }
+            Err(e) => checker.report(
+                Some("included_paths"),
+                format!(
+                    "included path '{}' is not a valid glob: {}",
+                    included_path, e
+                ),
+            ),
+        }
+    }
+
+    for excluded_path in &config.excluded_paths {
+        if let Err(e) = glob::Pattern::new(excluded_path) {
+            checker.report(
+                Some("excluded_paths"),
+                format!(
+                    "excluded path '{}' is not a valid glob: {}",
+                    excluded_path, e
+                ),
+            );
+        }
+    }
+
+    checker.check_script(
+        "pre_backup_script",
+        &config.pre_backup_script,
+        &config.app_root,
+    );
+    checker.check_script(
+        "post_backup_script",
+        &config.post_backup_script,
+        &config.app_root,
+    );
+    checker.check_script(
+        "pre_restore_script",
+        &config.pre_restore_script,
+        &config.app_root,
+    );
+    checker.check_script(
+        "post_restore_script",
+        &config.post_restore_script,
+        &config.app_root,
+    );
+    checker.check_script(
+        "on_failure_script",
+        &config.on_failure_script,
+        &config.app_root,
+    );
+    checker.check_script("finally_script", &config.finally_script, &config.app_root);
+    if let Some(working_dir) = &config.script_working_dir {
+        checker.check_dir(
+            "script_working_dir",
+            &Path::new(&config.app_root)
+                .join(working_dir)
+                .to_string_lossy(),
+        );
+    }
+
+    checker.check_retention("keep_full_local_backups", config.keep_full_local_backups);
+    checker.check_retention("keep_full_remote_backups", config.keep_full_remote_backups);
+
+    for (key, schedule) in [
+        ("schedule", &config.schedule),
+        ("incremental_schedule", &config.incremental_schedule),
+        ("prune_schedule", &config.prune_schedule),
+    ] {
+        if let Some(Err(e)) = schedule.as_deref().map(parse_schedule) {
+            checker.report(Some(key), e.to_string());
+        }
+    }
+
+    let app_name_line = checker.key_line("app_name");
+    Some((config, app_name_line))
+}
+
+// validates the global config and all app configs, returns every problem found
+pub fn check_configs(global_config_path: &Path) -> Vec<ConfigProblem> {
+    let mut problems: Vec<ConfigProblem> = Vec::new();
+
+    let Some(global_config) = check_global_config(global_config_path, &mut problems) else {
+        return problems;
+    };
+
+    let mut configs = check_inline_app_configs(&global_config, global_config_path, &mut problems)
+        .into_iter()
+        .map(|(config, line)| (config, global_config_path.to_path_buf(), line))
+        .collect::<Vec<(Config, PathBuf, Option<usize>)>>();
+
+    for config_file in get_config_files(Path::new(&global_config.config_files_location)) {
+        if let Some((config, line)) =
+            check_app_config_file(&global_config, &config_file, &mut problems)
+        {
+            configs.push((config, config_file, line));
+        }
+    }
+
+    let mut app_names: HashMap<String, String> = HashMap::new();
+
+    for (config, file, line) in configs {
+        match app_names.get(&config.app_name) {
+            Some(first_location) => {
+                problems.push(ConfigProblem {
+                    file,
+                    line,
+                    message: format!(
+                        "app_name '{}' is already used in {}",
+                        config.app_name, first_location
+                    ),
+                });
+            }
+            None => {
+                app_names.insert(config.app_name.clone(), format_location(&file, line));
+            }
+        }
+    }
+
+    problems
+}
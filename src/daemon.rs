@@ -0,0 +1,277 @@
+use std::{
+    io::{Error, ErrorKind},
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use chrono::{DateTime, Local, Utc};
+use cron::Schedule;
+use log::{error, info, warn};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+
+use crate::{
+    actions::{backup_all_until, log_backup_summary, prune, BackupStatus},
+    backup::{get_all_local_backups_for_app, BackupType},
+    config::{get_all_configs, Config},
+    globalconfig::{load_global_config, GlobalConfig},
+};
+
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+// apps locked by another run are tried again after this many seconds
+const LOCKED_RETRY_SECONDS: i64 = 60;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Task {
+    Backup(BackupType),
+    Prune,
+}
+
+struct ScheduledTask {
+    config: Config,
+    task: Task,
+    schedule: Schedule,
+    next_run: Option<DateTime<Local>>,
+}
+
+// accepts both the classic 5 field cron format and the 6/7 field format with seconds
+pub fn parse_schedule(expression: &str) -> Result<Schedule, Error> {
+    let expression = match expression.split_whitespace().count() {
+        5 => "0 ".to_string() + expression,
+        _ => expression.to_string(),
+    };
+
+    Schedule::from_str(expression.as_str()).map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid schedule '{}': {}", expression, e),
+        )
+    })
+}
+
+// the first scheduled time after the last run, so runs missed while the daemon was down
+// are caught up right away (once, not for every missed slot). Without a last run the
+// first scheduled time from now.
+pub fn first_run(
+    schedule: &Schedule,
+    last_run: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Local>> {
+    schedule
+        .after(&last_run.unwrap_or(now).with_timezone(&Local))
+        .next()
+}
+
+fn get_scheduled_tasks(global_config: &GlobalConfig) -> Vec<ScheduledTask> {
+    let mut scheduled_tasks: Vec<ScheduledTask> = Vec::new();
+
+    for config in get_all_configs(global_config) {
+        let schedules = [
+            (&config.schedule, Task::Backup(BackupType::Full)),
+            (
+                &config.incremental_schedule,
+                Task::Backup(BackupType::Incremental),
+            ),
+            (&config.prune_schedule, Task::Prune),
+        ];
+
+        for (expression, task) in schedules {
+            let Some(expression) = expression else {
+                continue;
+            };
+
+            let schedule = match parse_schedule(expression) {
+                Ok(schedule) => schedule,
+                Err(e) => {
+                    error!("{}: {}", config.app_name, e);
+                    continue;
+                }
+            };
+
+            // prunes aren't recorded, a missed prune waits for its next time
+            let last_run = match &task {
+                Task::Backup(backup_type) => get_all_local_backups_for_app(global_config, &config)
+                    .into_iter()
+                    .filter(|b| b.backup_type == *backup_type)
+                    .map(|b| b.time)
+                    .max(),
+                Task::Prune => None,
+            };
+
+            let next_run = first_run(&schedule, last_run, Utc::now());
+
+            match next_run {
+                Some(next_run) => info!("{}: next {:?} at {}", config.app_name, task, next_run),
+                None => warn!("{}: schedule {} never runs", config.app_name, expression),
+            }
+
+            scheduled_tasks.push(ScheduledTask {
+                config: config.clone(),
+                task,
+                schedule,
+                next_run,
+            });
+        }
+    }
+
+    scheduled_tasks
+}
+
+fn reschedule(scheduled_task: &mut ScheduledTask, locked: bool) {
+    scheduled_task.next_run = match locked {
+        true => Some(Local::now() + chrono::Duration::seconds(LOCKED_RETRY_SECONDS)),
+        // failed runs are not retried until their next scheduled time
+        false => scheduled_task.schedule.after(&Local::now()).next(),
+    };
+
+    if let Some(next_run) = scheduled_task.next_run {
+        info!(
+            "{}: next {:?} at {}",
+            scheduled_task.config.app_name, scheduled_task.task, next_run
+        );
+    }
+}
+
+fn run_due_tasks(
+    global_config: &GlobalConfig,
+    scheduled_tasks: &mut [ScheduledTask],
+    jobs: usize,
+    stop: &AtomicBool,
+) {
+    let now = Local::now();
+
+    for task in [
+        Task::Backup(BackupType::Full),
+        Task::Backup(BackupType::Incremental),
+        Task::Prune,
+    ] {
+        if stop.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let due = scheduled_tasks
+            .iter_mut()
+            .filter(|s| s.task == task && s.next_run.is_some_and(|t| t <= now))
+            .collect::<Vec<&mut ScheduledTask>>();
+
+        if due.is_empty() {
+            continue;
+        }
+
+        match &task {
+            Task::Backup(backup_type) => {
+                run_due_backups(global_config, due, backup_type, jobs, stop)
+            }
+            Task::Prune => {
+                for scheduled_task in due {
+                    if stop.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    let result = prune(global_config, &scheduled_task.config, false);
+                    if let Err(e) = &result {
+                        error!(
+                            "{}: scheduled prune failed: {}",
+                            scheduled_task.config.app_name, e
+                        );
+                    }
+                    let locked = matches!(&result, Err(e) if e.kind() == ErrorKind::WouldBlock);
+                    reschedule(scheduled_task, locked);
+                }
+            }
+        }
+    }
+}
+
+fn run_due_backups(
+    global_config: &GlobalConfig,
+    due: Vec<&mut ScheduledTask>,
+    backup_type: &BackupType,
+    jobs: usize,
+    stop: &AtomicBool,
+) {
+    let configs = due
+        .iter()
+        .map(|s| s.config.clone())
+        .collect::<Vec<Config>>();
+
+    info!(
+        "Running scheduled {:?} backup of {} apps",
+        backup_type,
+        configs.len()
+    );
+
+    // never waits for locks, an app locked by a manual run is tried again a bit later
+    let summaries = match backup_all_until(
+        global_config,
+        &configs,
+        backup_type,
+        jobs,
+        jobs,
+        false,
+        stop,
+    ) {
+        Ok(summaries) => {
+            log_backup_summary(&summaries);
+            summaries
+        }
+        Err(e) => {
+            error!("Scheduled backup failed: {}", e);
+            Vec::new()
+        }
+    };
+
+    for scheduled_task in due {
+        let status = summaries
+            .iter()
+            .find(|s| s.app_name == scheduled_task.config.app_name)
+            .map(|s| &s.status);
+        match status {
+            // cancelled when the daemon stops, runs on the next start
+            Some(BackupStatus::Cancelled) => {}
+            // the repository was locked, nothing ran
+            None => reschedule(scheduled_task, true),
+            Some(status) => reschedule(scheduled_task, matches!(status, BackupStatus::Locked(_))),
+        }
+    }
+}
+
+// runs scheduled backups and prunes until SIGTERM or SIGINT. Apps which haven't started yet
+// are cancelled then, a backup in progress is always finished and no half-written archive is
+// left behind. SIGHUP reloads the global config and app configs.
+pub fn run_daemon(global_config_path: &Path, jobs: usize) -> Result<(), Error> {
+    let terminate = Arc::new(AtomicBool::new(false));
+    let reload = Arc::new(AtomicBool::new(false));
+
+    signal_hook::flag::register(SIGTERM, Arc::clone(&terminate))?;
+    signal_hook::flag::register(SIGINT, Arc::clone(&terminate))?;
+    signal_hook::flag::register(SIGHUP, Arc::clone(&reload))?;
+
+    info!("Starting daemon");
+    let mut global_config = load_global_config(global_config_path)?;
+    let mut scheduled_tasks = get_scheduled_tasks(&global_config);
+
+    while !terminate.load(Ordering::Relaxed) {
+        if reload.swap(false, Ordering::Relaxed) {
+            info!("Reloading configs");
+            // a broken global config keeps the previous one running
+            match load_global_config(global_config_path) {
+                Ok(reloaded) => global_config = reloaded,
+                Err(e) => error!("{}", e),
+            }
+            scheduled_tasks = get_scheduled_tasks(&global_config);
+        }
+
+        run_due_tasks(&global_config, &mut scheduled_tasks, jobs, &terminate);
+
+        thread::sleep(TICK_INTERVAL);
+    }
+
+    info!("Daemon stopped");
+
+    Ok(())
+}
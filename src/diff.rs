@@ -0,0 +1,606 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    fs::{self, Metadata},
+    io::{self, ErrorKind, Read},
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use flate2::CrcReader;
+use tar::{Archive, EntryType};
+
+use crate::{
+    backup::Backup,
+    browse::{normalize_path, open_archive, BackupChain},
+    config::Config,
+    globalconfig::GlobalConfig,
+    manifest::{file_crc, Manifest, ManifestEntry},
+    sources::is_virtual_entry,
+    storage::fs::get_files_to_backup,
+};
+
+// larger files are only reported as modified, never shown as a unified diff
+pub const UNIFIED_DIFF_MAX_SIZE: u64 = 64 * 1024;
+
+// lines of unchanged context around each change of a unified diff
+const CONTEXT_LINES: usize = 3;
+
+// beyond this many compared line pairs the whole file is shown as replaced
+const MAX_LCS_CELLS: usize = 4_000_000;
+
+// what bkp diff compares a backup with
+#[derive(Debug, Clone)]
+pub enum DiffTarget {
+    Backup(String),
+    Live,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Kind {
+    File,
+    Dir,
+    Symlink(PathBuf),
+    // device nodes and fifos
+    Other,
+}
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Kind::File => "file",
+            Kind::Dir => "directory",
+            Kind::Symlink(_) => "symlink",
+            Kind::Other => "other",
+        }
+    }
+}
+
+// where the content of a file can be read from
+#[derive(Debug, Clone)]
+pub enum Content {
+    // the index of the archive in the chain and the entry holding the data, which is
+    // the target for hard links
+    Archive(usize, PathBuf),
+    Live(PathBuf),
+}
+
+// a path of a backup or of the live tree. Mode holds the permission bits only, crc is
+// None when it couldn't be determined.
+#[derive(Debug, Clone)]
+pub struct FileState {
+    pub kind: Kind,
+    pub mode: u32,
+    pub size: u64,
+    pub crc: Option<u32>,
+    pub mtime: u64,
+    pub content: Option<Content>,
+}
+
+pub type Tree = BTreeMap<PathBuf, FileState>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Added,
+    Removed,
+    // descriptions of what differs
+    Modified(Vec<String>),
+}
+
+impl Change {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Change::Added => "added",
+            Change::Removed => "removed",
+            Change::Modified(_) => "modified",
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Added => write!(f, "A"),
+            Change::Removed => write!(f, "D"),
+            Change::Modified(_) => write!(f, "M"),
+        }
+    }
+}
+
+// the files of a backup as restoring it would create them, from the full backup up to
+// the backup itself
+pub fn backup_tree(global_config: &GlobalConfig, chain: &BackupChain) -> Result<Tree, io::Error> {
+    let mut tree = Tree::new();
+
+    for (index, backup) in chain.backups.iter().enumerate().rev() {
+        tree.extend(archive_tree(global_config, backup, chain.remote, index)?);
+    }
+
+    Ok(tree)
+}
+
+// the entries of a single archive, index is its position in the chain. Checksums come
+// from the archive manifest, archives written before manifests existed are read a second
+// time to compute them.
+pub fn archive_tree(
+    global_config: &GlobalConfig,
+    backup: &Backup,
+    remote: bool,
+    index: usize,
+) -> Result<Tree, io::Error> {
+    let (mut entries, manifest) =
+        scan_archive(&mut open_archive(global_config, backup, remote)?, index)?;
+
+    let crcs: HashMap<PathBuf, u32> = match manifest {
+        Some(manifest) => manifest
+            .entries
+            .into_iter()
+            .filter_map(|(path, entry)| match entry {
+                ManifestEntry::File { crc, .. } => Some((path, crc)),
+                _ => None,
+            })
+            .collect(),
+        None => archive_crcs(&mut open_archive(global_config, backup, remote)?)?,
+    };
+
+    for (path, state) in entries.iter_mut() {
+        if let Some(Content::Archive(_, content_path)) = &state.content {
+            state.crc = crcs.get(path).or_else(|| crcs.get(content_path)).copied();
+        }
+    }
+
+    Ok(entries)
+}
+
+// the files a backup of the app would contain now
+pub fn live_tree(config: &Config) -> Result<Tree, io::Error> {
+    let app_root = Path::new(&config.app_root);
+    let mut tree = Tree::new();
+
+    for path in get_files_to_backup(config) {
+        let relative = normalize_path(path.strip_prefix(app_root).unwrap_or(&path));
+        // journals are part of the database snapshot, not backed up themselves
+        let journal = config.sources.sqlite.iter().any(|source| {
+            source.is_database_file(&config.app_root, &path)
+                && path != source.db_path(&config.app_root)
+        });
+        if relative.as_os_str().is_empty() || journal {
+            continue;
+        }
+
+        let metadata = match config.metadata.symlinks {
+            true => fs::symlink_metadata(&path),
+            false => fs::metadata(&path),
+        };
+        let metadata = match metadata {
+            Ok(metadata) => metadata,
+            // removed since it was listed
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+
+        tree.insert(relative, live_state(&path, &metadata)?);
+    }
+
+    Ok(tree)
+}
+
+fn live_state(path: &Path, metadata: &Metadata) -> Result<FileState, io::Error> {
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_file() {
+        Kind::File
+    } else if file_type.is_dir() {
+        Kind::Dir
+    } else if file_type.is_symlink() {
+        Kind::Symlink(fs::read_link(path)?)
+    } else {
+        Kind::Other
+    };
+
+    let (size, crc, content) = match kind {
+        Kind::File => (
+            metadata.len(),
+            Some(file_crc(path)?),
+            Some(Content::Live(path.to_path_buf())),
+        ),
+        _ => (0, None, None),
+    };
+
+    Ok(FileState {
+        kind,
+        mode: metadata.permissions().mode() & 0o7777,
+        size,
+        crc,
+        mtime: metadata.mtime().max(0) as u64,
+        content,
+    })
+}
+
+// the entries of an archive without checksums, and its manifest
+fn scan_archive<R: Read>(
+    archive: &mut Archive<R>,
+    index: usize,
+) -> Result<(Tree, Option<Manifest>), io::Error> {
+    let mut entries = Tree::new();
+    let mut manifest: Option<Manifest> = None;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = normalize_path(&entry.path()?);
+
+        if is_virtual_entry(&mut entry)? {
+            if path == Manifest::entry_name() {
+                manifest = Some(Manifest::read(&mut entry)?);
+            }
+            continue;
+        }
+        if path.as_os_str().is_empty() {
+            continue;
+        }
+
+        let header = entry.header();
+        let mode = header.mode().unwrap_or(0) & 0o7777;
+        let mtime = header.mtime().unwrap_or(0);
+        let link_name = entry.link_name()?.map(|link_name| link_name.to_path_buf());
+
+        let state = match (header.entry_type(), link_name) {
+            (EntryType::Directory, _) => FileState {
+                kind: Kind::Dir,
+                mode,
+                size: 0,
+                crc: None,
+                mtime,
+                content: None,
+            },
+            (EntryType::Symlink, Some(link_name)) => FileState {
+                kind: Kind::Symlink(link_name),
+                mode,
+                size: 0,
+                crc: None,
+                mtime,
+                content: None,
+            },
+            // hard links point to an earlier entry of the same archive
+            (EntryType::Link, Some(link_name)) => {
+                let target = normalize_path(&link_name);
+                FileState {
+                    kind: Kind::File,
+                    mode,
+                    size: entries.get(&target).map(|state| state.size).unwrap_or(0),
+                    crc: None,
+                    mtime,
+                    content: Some(Content::Archive(index, target)),
+                }
+            }
+            (entry_type, _) if entry_type.is_file() => FileState {
+                kind: Kind::File,
+                mode,
+                size: entry.size(),
+                crc: None,
+                mtime,
+                content: Some(Content::Archive(index, path.clone())),
+            },
+            _ => FileState {
+                kind: Kind::Other,
+                mode,
+                size: 0,
+                crc: None,
+                mtime,
+                content: None,
+            },
+        };
+        entries.insert(path, state);
+    }
+
+    Ok((entries, manifest))
+}
+
+// checksums of the regular files of an archive
+fn archive_crcs<R: Read>(archive: &mut Archive<R>) -> Result<HashMap<PathBuf, u32>, io::Error> {
+    let mut crcs: HashMap<PathBuf, u32> = HashMap::new();
+
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let path = normalize_path(&entry.path()?);
+        let mut reader = CrcReader::new(entry);
+        io::copy(&mut reader, &mut io::sink())?;
+        crcs.insert(path, reader.crc().sum());
+    }
+
+    Ok(crcs)
+}
+
+// the changes from old to new, ordered by path
+pub fn compare(old: &Tree, new: &Tree) -> Vec<(PathBuf, Change)> {
+    let mut changes: Vec<(PathBuf, Change)> = Vec::new();
+
+    for (path, old_state) in old {
+        match new.get(path) {
+            None => changes.push((path.clone(), Change::Removed)),
+            Some(new_state) => {
+                let differences = differences(old_state, new_state);
+                if !differences.is_empty() {
+                    changes.push((path.clone(), Change::Modified(differences)));
+                }
+            }
+        }
+    }
+
+    for path in new.keys() {
+        if !old.contains_key(path) {
+            changes.push((path.clone(), Change::Added));
+        }
+    }
+
+    changes.sort_by(|(a, _), (b, _)| a.cmp(b));
+    changes
+}
+
+// what differs between two versions of a path
+pub fn differences(old: &FileState, new: &FileState) -> Vec<String> {
+    let mut differences: Vec<String> = Vec::new();
+
+    match (&old.kind, &new.kind) {
+        (Kind::File, Kind::File) => {
+            if old.size != new.size {
+                differences.push(format!("size {} -> {}", old.size, new.size));
+            } else if let (Some(old_crc), Some(new_crc)) = (old.crc, new.crc) {
+                if old_crc != new_crc {
+                    differences.push(format!("crc32 {:08x} -> {:08x}", old_crc, new_crc));
+                }
+            }
+        }
+        (Kind::Symlink(old_target), Kind::Symlink(new_target)) if old_target != new_target => {
+            differences.push(format!(
+                "target {} -> {}",
+                old_target.display(),
+                new_target.display()
+            ));
+        }
+        (old_kind, new_kind) if old_kind.as_str() != new_kind.as_str() => {
+            differences.push(format!("{} -> {}", old_kind.as_str(), new_kind.as_str()));
+            return differences;
+        }
+        _ => {}
+    }
+
+    // the permissions of symlinks are meaningless
+    if old.mode != new.mode && !matches!(new.kind, Kind::Symlink(_)) {
+        differences.push(format!("mode {:04o} -> {:04o}", old.mode, new.mode));
+    }
+
+    differences
+}
+
+// files worth a unified diff, regular files on both sides whose content differs and
+// which are small enough
+pub fn unified_diff_candidates<'a>(
+    changes: &'a [(PathBuf, Change)],
+    old: &Tree,
+    new: &Tree,
+) -> Vec<&'a PathBuf> {
+    changes
+        .iter()
+        .filter(|(path, change)| {
+            let (Some(old), Some(new)) = (old.get(path), new.get(path)) else {
+                return false;
+            };
+            matches!(change, Change::Modified(_))
+                && old.kind == Kind::File
+                && new.kind == Kind::File
+                && (old.size != new.size || old.crc != new.crc)
+                && old.size <= UNIFIED_DIFF_MAX_SIZE
+                && new.size <= UNIFIED_DIFF_MAX_SIZE
+        })
+        .map(|(path, _)| path)
+        .collect()
+}
+
+// reads the content of the given paths of a tree, with one pass over each archive
+pub fn read_contents(
+    global_config: &GlobalConfig,
+    chain: Option<&BackupChain>,
+    tree: &Tree,
+    paths: &[&PathBuf],
+) -> Result<HashMap<PathBuf, Vec<u8>>, io::Error> {
+    let mut contents: HashMap<PathBuf, Vec<u8>> = HashMap::new();
+    // for each archive the entries to read and the paths they are the content of
+    let mut wanted: BTreeMap<usize, HashMap<PathBuf, Vec<PathBuf>>> = BTreeMap::new();
+
+    for path in paths {
+        match tree.get(*path).and_then(|state| state.content.as_ref()) {
+            Some(Content::Live(live_path)) => {
+                contents.insert(path.to_path_buf(), fs::read(live_path)?);
+            }
+            Some(Content::Archive(index, content_path)) => wanted
+                .entry(*index)
+                .or_default()
+                .entry(content_path.clone())
+                .or_default()
+                .push(path.to_path_buf()),
+            None => {}
+        }
+    }
+
+    let Some(chain) = chain else {
+        return Ok(contents);
+    };
+    for (index, mut entries) in wanted {
+        let mut archive = chain.open(global_config, &chain.backups[index])?;
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let Some(paths) = entries.remove(&normalize_path(&entry.path()?)) else {
+                continue;
+            };
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content)?;
+            for path in paths {
+                contents.insert(path, content.clone());
+            }
+            if entries.is_empty() {
+                break;
+            }
+        }
+    }
+
+    Ok(contents)
+}
+
+// text is valid utf-8 without null bytes
+pub fn as_text(content: &[u8]) -> Option<&str> {
+    match content.contains(&0) {
+        true => None,
+        false => std::str::from_utf8(content).ok(),
+    }
+}
+
+enum Line {
+    // the line of old, which is the same in new
+    Equal(usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+// a unified diff like diff -u, empty when both are equal
+pub fn unified_diff(old_label: &str, new_label: &str, old: &str, new: &str) -> String {
+    let old_lines = old.split_inclusive('\n').collect::<Vec<&str>>();
+    let new_lines = new.split_inclusive('\n').collect::<Vec<&str>>();
+    let script = edit_script(&old_lines, &new_lines);
+
+    // positions in the script of the changed lines, with context they form the hunks
+    let changed = script
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, Line::Equal(..)))
+        .map(|(position, _)| position)
+        .collect::<Vec<usize>>();
+    if changed.is_empty() {
+        return String::new();
+    }
+
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for position in changed {
+        let start = position.saturating_sub(CONTEXT_LINES);
+        let end = (position + CONTEXT_LINES + 1).min(script.len());
+        match hunks.last_mut() {
+            Some((_, last_end)) if start <= *last_end => *last_end = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let mut diff = format!("--- {}\n+++ {}\n", old_label, new_label);
+    for (start, end) in hunks {
+        // the lines before the hunk on each side
+        let (old_before, new_before) =
+            script[..start]
+                .iter()
+                .fold((0, 0), |(o, n), line| match line {
+                    Line::Equal(..) => (o + 1, n + 1),
+                    Line::Delete(_) => (o + 1, n),
+                    Line::Insert(_) => (o, n + 1),
+                });
+        let hunk = &script[start..end];
+        let old_count = hunk
+            .iter()
+            .filter(|line| !matches!(line, Line::Insert(_)))
+            .count();
+        let new_count = hunk
+            .iter()
+            .filter(|line| !matches!(line, Line::Delete(_)))
+            .count();
+
+        diff.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            hunk_range(old_before, old_count),
+            hunk_range(new_before, new_count)
+        ));
+        for line in hunk {
+            let (prefix, text) = match line {
+                Line::Equal(i) => (' ', old_lines[*i]),
+                Line::Delete(i) => ('-', old_lines[*i]),
+                Line::Insert(j) => ('+', new_lines[*j]),
+            };
+            diff.push(prefix);
+            diff.push_str(text);
+            if !text.ends_with('\n') {
+                diff.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+    }
+
+    diff
+}
+
+// like diff -u, an empty range starts at the line before it
+fn hunk_range(before: usize, count: usize) -> String {
+    match count {
+        0 => format!("{},0", before),
+        1 => format!("{}", before + 1),
+        _ => format!("{},{}", before + 1, count),
+    }
+}
+
+// the shortest way from old to new along their longest common subsequence of lines
+fn edit_script(old: &[&str], new: &[&str]) -> Vec<Line> {
+    let prefix = old
+        .iter()
+        .zip(new)
+        .take_while(|(old_line, new_line)| old_line == new_line)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(old_line, new_line)| old_line == new_line)
+        .count();
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+    let (n, m) = (old_middle.len(), new_middle.len());
+
+    let mut script: Vec<Line> = (0..prefix).map(Line::Equal).collect();
+
+    if (n + 1) * (m + 1) > MAX_LCS_CELLS {
+        script.extend((0..n).map(|i| Line::Delete(prefix + i)));
+        script.extend((0..m).map(|j| Line::Insert(prefix + j)));
+    } else {
+        // lengths[i][j] is the length of the common subsequence of old[i..] and new[j..]
+        let mut lengths = vec![0u32; (n + 1) * (m + 1)];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lengths[i * (m + 1) + j] = match old_middle[i] == new_middle[j] {
+                    true => lengths[(i + 1) * (m + 1) + j + 1] + 1,
+                    false => lengths[(i + 1) * (m + 1) + j].max(lengths[i * (m + 1) + j + 1]),
+                };
+            }
+        }
+
+        let (mut i, mut j) = (0, 0);
+        while i < n || j < m {
+            if i < n && j < m && old_middle[i] == new_middle[j] {
+                script.push(Line::Equal(prefix + i));
+                i += 1;
+                j += 1;
+            } else if j == m
+                || (i < n && lengths[(i + 1) * (m + 1) + j] >= lengths[i * (m + 1) + j + 1])
+            {
+                script.push(Line::Delete(prefix + i));
+                i += 1;
+            } else {
+                script.push(Line::Insert(prefix + j));
+                j += 1;
+            }
+        }
+    }
+
+    let old_suffix = old.len() - suffix;
+    script.extend((0..suffix).map(|k| Line::Equal(old_suffix + k)));
+    script
+}
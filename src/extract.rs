@@ -0,0 +1,628 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    ffi::CString,
+    fmt, fs,
+    io::{self, Read},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+use chrono::Utc;
+use log::{error, info};
+use serde::Deserialize;
+use tar::{Entry, EntryType};
+
+use crate::{manifest::Manifest, sources::sqlite::journal_paths};
+
+// what restore accepts from an archive besides regular files, directories and links which
+// stay inside the restored root, in toml the [restore] section of the app config
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct RestorePolicy {
+    // device nodes and fifos
+    #[serde(default)]
+    pub allow_special_files: bool,
+    // symlinks with an absolute target or a target outside app_root. Hard links always
+    // have to stay inside, a link to e.g. /etc/shadow would make it readable.
+    #[serde(default)]
+    pub allow_external_symlinks: bool,
+}
+
+// what happens to a file of the backup which already exists in app_root
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ConflictPolicy {
+    #[default]
+    Overwrite,
+    SkipExisting,
+    // skip files which were modified after the backed up version
+    KeepNewer,
+    // keep the existing file renamed to <name>.before-restore-<timestamp>
+    Rename,
+}
+
+impl ConflictPolicy {
+    pub const NAMES: [&'static str; 4] = ["overwrite", "skip-existing", "keep-newer", "rename"];
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<ConflictPolicy, String> {
+        match name {
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            "skip-existing" => Ok(ConflictPolicy::SkipExisting),
+            "keep-newer" => Ok(ConflictPolicy::KeepNewer),
+            "rename" => Ok(ConflictPolicy::Rename),
+            _ => Err(format!(
+                "Unknown conflict policy {}, expected one of {}",
+                name,
+                ConflictPolicy::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RestoreOptions {
+    // only report what the restore would do
+    pub dry_run: bool,
+    pub conflict: ConflictPolicy,
+    // move the current app_root to <app_root>.before-restore-<timestamp> and restore into
+    // an empty directory, so the restore can be rolled back by renaming it back
+    pub move_aside: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlannedAction {
+    Create,
+    Overwrite,
+    Rename,
+    Skip,
+    // files which are moved aside and not part of the backup
+    Delete,
+}
+
+impl PlannedAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PlannedAction::Create => "create",
+            PlannedAction::Overwrite => "overwrite",
+            PlannedAction::Rename => "rename",
+            PlannedAction::Skip => "skip",
+            PlannedAction::Delete => "delete",
+        }
+    }
+}
+
+// state of a restore across all archives of its chain. Conflicts are decided against the
+// tree as it was before the restore, a file restored from an earlier archive of the chain
+// is simply overwritten by later ones.
+pub struct Extraction {
+    pub options: RestoreOptions,
+    pub plan: BTreeMap<PathBuf, PlannedAction>,
+    // merged manifests of the unpacked archives
+    pub manifest: Manifest,
+    restored: HashSet<PathBuf>,
+    aside_suffix: String,
+    // set when unpacking into a staging directory, conflicts are decided against app_root
+    // and resolved when the staged files are swapped into place
+    app_root: Option<PathBuf>,
+}
+
+impl Extraction {
+    pub fn new(options: RestoreOptions) -> Extraction {
+        Extraction {
+            options,
+            plan: BTreeMap::new(),
+            manifest: Manifest::default(),
+            restored: HashSet::new(),
+            aside_suffix: format!(".before-restore-{}", Utc::now().format("%Y%m%dT%H%M%S")),
+            app_root: None,
+        }
+    }
+
+    pub fn staged(options: RestoreOptions, app_root: &Path) -> Extraction {
+        Extraction {
+            app_root: Some(app_root.to_path_buf()),
+            ..Extraction::new(options)
+        }
+    }
+
+    pub fn is_staged(&self) -> bool {
+        self.app_root.is_some()
+    }
+
+    // appended to the names of files and directories which are moved aside
+    pub fn aside_path(&self, path: &Path) -> PathBuf {
+        let mut aside_path = path.as_os_str().to_os_string();
+        aside_path.push(&self.aside_suffix);
+        PathBuf::from(aside_path)
+    }
+
+    // decides what happens to an entry and records it in the plan
+    pub fn decide<R: Read>(
+        &mut self,
+        entry: &Entry<R>,
+        target: &Path,
+        path: &Path,
+    ) -> PlannedAction {
+        if self.restored.contains(path) {
+            return PlannedAction::Overwrite;
+        }
+
+        // with move_aside the restore starts from an empty directory
+        let root = self.app_root.as_deref().unwrap_or(target);
+        let existing = match self.options.move_aside {
+            true => None,
+            false => root.join(path).symlink_metadata().ok(),
+        };
+
+        let action = match existing {
+            None => PlannedAction::Create,
+            // directories are merged
+            Some(metadata) if metadata.is_dir() => return PlannedAction::Overwrite,
+            Some(metadata) => match self.options.conflict {
+                ConflictPolicy::Overwrite => PlannedAction::Overwrite,
+                ConflictPolicy::SkipExisting => PlannedAction::Skip,
+                ConflictPolicy::KeepNewer => {
+                    let entry_mtime = entry.header().mtime().unwrap_or(0) as i64;
+                    match metadata.mtime() > entry_mtime {
+                        true => PlannedAction::Skip,
+                        false => PlannedAction::Overwrite,
+                    }
+                }
+                ConflictPolicy::Rename => PlannedAction::Rename,
+            },
+        };
+
+        if action != PlannedAction::Skip {
+            self.restored.insert(path.to_path_buf());
+        }
+        self.plan.insert(path.to_path_buf(), action);
+
+        action
+    }
+
+    // with move_aside, everything in root which is not restored is gone afterwards
+    pub fn plan_deletions(&mut self, root: &Path) -> Result<(), io::Error> {
+        let kept = self
+            .plan
+            .keys()
+            .flat_map(|path| path.ancestors())
+            .map(Path::to_path_buf)
+            .collect::<HashSet<PathBuf>>();
+
+        let mut dirs = vec![PathBuf::new()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(root.join(&dir))? {
+                let entry = entry?;
+                let path = dir.join(entry.file_name());
+
+                if !kept.contains(&path) {
+                    // a directory is listed once for everything below it
+                    self.plan.insert(path, PlannedAction::Delete);
+                } else if entry.file_type()?.is_dir() {
+                    dirs.push(path);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // checks the staged files against the manifests, skipped files are not staged
+    pub fn verify(&self, staging: &Path) -> Result<(), io::Error> {
+        let mismatches = self.manifest.verify(staging, |path| {
+            self.plan.get(path) == Some(&PlannedAction::Skip)
+        });
+
+        for mismatch in &mismatches {
+            error!("Restored file does not match the manifest: {}", mismatch);
+        }
+
+        match mismatches.len() {
+            0 => Ok(()),
+            count => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} restored files do not match the manifest", count),
+            )),
+        }
+    }
+
+    // moves the staged files into app_root. Existing directories are merged, files are
+    // replaced with a rename each, so every file is either the old or the restored one.
+    // The journals of replaced databases would be applied to the restored ones. If a rename
+    // fails, the ones done so far are undone in reverse order and app_root is as before.
+    pub fn swap(
+        &self,
+        staging: &Path,
+        app_root: &Path,
+        databases: &[PathBuf],
+    ) -> Result<(), io::Error> {
+        let mut swapped = Swapped::default();
+
+        if let Err(e) = self.swap_into(staging, app_root, databases, &mut swapped) {
+            error!(
+                "Moving restored files into place failed, rolling back: {}",
+                e
+            );
+            swapped.undo();
+            return Err(e);
+        }
+
+        for path in &swapped.replaced {
+            if let Err(e) = fs::remove_file(path) {
+                error!("Unable to remove replaced file {}: {}", path.display(), e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn swap_into(
+        &self,
+        staging: &Path,
+        app_root: &Path,
+        databases: &[PathBuf],
+        swapped: &mut Swapped,
+    ) -> Result<(), io::Error> {
+        let mut dirs = vec![PathBuf::new()];
+        // set once everything is in place, a read-only directory would refuse the renames
+        // into it
+        let mut permissions = Vec::new();
+
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(staging.join(&dir))? {
+                let entry = entry?;
+                let path = dir.join(entry.file_name());
+                let staged = entry.path();
+                let target = app_root.join(&path);
+
+                let staged_dir = entry.file_type()?.is_dir();
+                let existing = target.symlink_metadata().ok();
+
+                match existing {
+                    None => {}
+                    // symlinks to directories are replaced, never followed
+                    Some(existing) if existing.is_dir() && staged_dir => {
+                        permissions.push((target, existing.permissions(), entry.metadata()?));
+                        dirs.push(path);
+                        continue;
+                    }
+                    Some(existing) => {
+                        let rename = self.plan.get(&path) == Some(&PlannedAction::Rename);
+                        if rename || existing.is_dir() {
+                            swapped.rename(&target, &self.aside_path(&target))?;
+                        } else {
+                            swapped.replace(&target)?;
+                        }
+                    }
+                }
+
+                if databases.contains(&path) {
+                    for journal in journal_paths(&target) {
+                        if journal.symlink_metadata().is_ok() {
+                            swapped.replace(&journal)?;
+                        }
+                    }
+                }
+                swapped.rename(&staged, &target)?;
+            }
+        }
+
+        for (target, old, staged) in permissions {
+            fs::set_permissions(&target, staged.permissions())?;
+            swapped.permissions.push((target, old));
+        }
+
+        Ok(())
+    }
+
+    pub fn log_plan(&self) {
+        let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+
+        for (path, action) in &self.plan {
+            info!("{:<9} {}", action.as_str(), path.display());
+            *counts.entry(action.as_str()).or_default() += 1;
+        }
+
+        let summary = counts
+            .iter()
+            .map(|(action, count)| format!("{} {}", count, action))
+            .collect::<Vec<String>>()
+            .join(", ");
+        info!(
+            "Restore would {}",
+            if summary.is_empty() {
+                "change nothing".to_string()
+            } else {
+                summary
+            }
+        );
+    }
+}
+
+// what swap changed in app_root so far. Files which are replaced are renamed away first
+// and only removed once the swap succeeded, so the old tree can be restored.
+#[derive(Default)]
+struct Swapped {
+    renames: Vec<(PathBuf, PathBuf)>,
+    replaced: Vec<PathBuf>,
+    permissions: Vec<(PathBuf, fs::Permissions)>,
+}
+
+impl Swapped {
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<(), io::Error> {
+        fs::rename(from, to)?;
+        self.renames.push((from.to_path_buf(), to.to_path_buf()));
+        Ok(())
+    }
+
+    fn replace(&mut self, path: &Path) -> Result<(), io::Error> {
+        let mut replaced = path.as_os_str().to_os_string();
+        replaced.push(".bkp-replaced");
+        let replaced = PathBuf::from(replaced);
+
+        self.rename(path, &replaced)?;
+        self.replaced.push(replaced);
+        Ok(())
+    }
+
+    fn undo(self) {
+        for (path, permissions) in self.permissions {
+            if let Err(e) = fs::set_permissions(&path, permissions) {
+                error!("Unable to reset permissions of {}: {}", path.display(), e);
+            }
+        }
+
+        for (from, to) in self.renames.iter().rev() {
+            if let Err(e) = fs::rename(to, from) {
+                error!(
+                    "Unable to move {} back to {}: {}",
+                    to.display(),
+                    from.display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+// an archive entry which was not restored
+#[derive(Debug, Clone)]
+pub struct Refusal {
+    pub path: String,
+    pub reason: String,
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.reason)
+    }
+}
+
+// returns the path of the entry relative to root if it can be restored. Entries which are
+// only the root itself, like './', are None. Every entry is checked on its own, against the
+// archive header and against what is on disk below root at the time it is unpacked.
+pub fn check_entry<R: Read>(
+    entry: &Entry<R>,
+    root: &Path,
+    policy: &RestorePolicy,
+) -> Result<Option<PathBuf>, Refusal> {
+    let raw_path = entry.path_bytes();
+    let refuse = |reason: String| Refusal {
+        path: String::from_utf8_lossy(&raw_path).to_string(),
+        reason,
+    };
+
+    let path = entry
+        .path()
+        .map_err(|e| refuse(format!("invalid path: {}", e)))?
+        .to_path_buf();
+    let path = check_relative_path(&path).map_err(refuse)?;
+    let Some(path) = path else {
+        return Ok(None);
+    };
+
+    let entry_type = entry.header().entry_type();
+    match entry_type {
+        EntryType::Regular
+        | EntryType::Continuous
+        | EntryType::GNUSparse
+        | EntryType::Directory => {}
+        EntryType::Symlink => {
+            let target = link_name(entry).map_err(refuse)?;
+            let base = path.parent().unwrap_or(Path::new(""));
+            if !policy.allow_external_symlinks && escapes_root(base, &target) {
+                return Err(refuse(format!(
+                    "symlink to {} points outside of the restored directory",
+                    target.display()
+                )));
+            }
+        }
+        EntryType::Link => {
+            // hard link targets are archive paths, relative to root
+            let target = link_name(entry).map_err(refuse)?;
+            if escapes_root(Path::new(""), &target) {
+                return Err(refuse(format!(
+                    "hard link to {} points outside of the restored directory",
+                    target.display()
+                )));
+            }
+            // an external symlink restored by an earlier entry must not lead the target out
+            check_on_disk(root, &target)
+                .map_err(|e| refuse(format!("hard link to {}: {}", target.display(), e)))?;
+        }
+        entry_type if is_special_file(entry_type) => {
+            if !policy.allow_special_files {
+                return Err(refuse(format!(
+                    "special file ({}) is not allowed",
+                    entry_type_name(entry_type)
+                )));
+            }
+        }
+        _ => {
+            return Err(refuse(format!(
+                "unsupported entry type {}",
+                entry_type_name(entry_type)
+            )));
+        }
+    }
+
+    check_on_disk(root, &path).map_err(refuse)?;
+
+    Ok(Some(path))
+}
+
+// archive paths have to be relative and must not leave the root
+fn check_relative_path(path: &Path) -> Result<Option<PathBuf>, String> {
+    let mut relative = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => return Err("path contains '..'".to_string()),
+            Component::RootDir | Component::Prefix(_) => return Err("absolute path".to_string()),
+        }
+    }
+
+    match relative.as_os_str().is_empty() {
+        true => Ok(None),
+        false => Ok(Some(relative)),
+    }
+}
+
+// a symlink or directory already on disk, possibly restored from an earlier entry,
+// must not redirect the entry outside of root. A root which doesn't exist yet, like the
+// app_root of a dry run on a new server, has nothing on disk below it.
+fn check_on_disk(root: &Path, path: &Path) -> Result<(), String> {
+    let root = match root.canonicalize() {
+        Ok(root) => root,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("unable to resolve {}: {}", root.display(), e)),
+    };
+
+    let Some(parent) = path.parent() else {
+        return Ok(());
+    };
+
+    // the deepest existing ancestor decides where the entry ends up
+    let mut ancestor = root.join(parent);
+    while ancestor.symlink_metadata().is_err() {
+        match ancestor.parent() {
+            Some(parent) => ancestor = parent.to_path_buf(),
+            None => return Ok(()),
+        }
+    }
+
+    let resolved = ancestor
+        .canonicalize()
+        .map_err(|e| format!("unable to resolve {}: {}", ancestor.display(), e))?;
+    if !resolved.starts_with(&root) {
+        return Err(format!(
+            "parent directory {} resolves to {} outside of the restored directory",
+            ancestor.display(),
+            resolved.display()
+        ));
+    }
+
+    Ok(())
+}
+
+fn link_name<R: Read>(entry: &Entry<R>) -> Result<PathBuf, String> {
+    match entry.link_name() {
+        Ok(Some(target)) => Ok(target.to_path_buf()),
+        Ok(None) => Err("link without a target".to_string()),
+        Err(e) => Err(format!("invalid link target: {}", e)),
+    }
+}
+
+// whether target, relative to the directory base inside root, resolves outside of root.
+// Only the names are looked at, what is on disk is checked by check_on_disk.
+fn escapes_root(base: &Path, target: &Path) -> bool {
+    let mut depth = base
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .count();
+
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => match depth.checked_sub(1) {
+                Some(parent_depth) => depth = parent_depth,
+                None => return true,
+            },
+            Component::RootDir | Component::Prefix(_) => return true,
+        }
+    }
+
+    false
+}
+
+fn entry_type_name(entry_type: EntryType) -> String {
+    match entry_type {
+        EntryType::Char => "character device".to_string(),
+        EntryType::Block => "block device".to_string(),
+        EntryType::Fifo => "fifo".to_string(),
+        other => format!("{:?}", other),
+    }
+}
+
+pub fn is_special_file(entry_type: EntryType) -> bool {
+    matches!(
+        entry_type,
+        EntryType::Char | EntryType::Block | EntryType::Fifo
+    )
+}
+
+// tar unpacks device nodes and fifos as regular files, allowed ones are created with mknod
+pub fn unpack_special_file<R: Read>(entry: &Entry<R>, path: &Path) -> Result<(), io::Error> {
+    let header = entry.header();
+    let mode = header.mode()? & 0o7777;
+    let file_type = match header.entry_type() {
+        EntryType::Char => libc::S_IFCHR,
+        EntryType::Block => libc::S_IFBLK,
+        _ => libc::S_IFIFO,
+    };
+    // fifos have no device number, archives may leave the fields empty
+    let device = match header.entry_type() {
+        EntryType::Fifo => 0,
+        _ => libc::makedev(
+            header.device_major()?.unwrap_or(0),
+            header.device_minor()?.unwrap_or(0),
+        ),
+    };
+
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    match unsafe { libc::mknod(c_path.as_ptr(), file_type | mode as libc::mode_t, device) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+// logs every refusal, the restore fails if anything was refused
+pub fn report_refusals(archive: &Path, refusals: &[Refusal]) -> Result<(), io::Error> {
+    if refusals.is_empty() {
+        return Ok(());
+    }
+
+    for refusal in refusals {
+        error!("Refused to restore {}", refusal);
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "Refused to restore {} entries of {}",
+            refusals.len(),
+            archive.display()
+        ),
+    ))
+}
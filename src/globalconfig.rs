@@ -1,33 +1,111 @@
-use std::process::exit;
+use std::{
+    env,
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+};
 
-use log::error;
-use once_cell::sync::Lazy;
 use serde::Deserialize;
+use toml::value::Table;
 
-use crate::storage::fs::read_file_to_string;
-#[derive(Deserialize, Debug)]
+use crate::{secret::Secret, storage::fs::read_file_to_string};
+#[derive(Deserialize, Debug, Clone)]
 pub struct GlobalConfig {
+    // defaults to the conf.d directory next to the global config file
+    #[serde(default)]
     pub config_files_location: String,
     pub local_storage_location: String,
     pub remote_storage_address: String,
-    pub remote_storage_access_id: String,
-    pub remote_storage_secret_key: String,
+    pub remote_storage_access_id: Secret,
+    pub remote_storage_secret_key: Secret,
     pub log_file_location: String,
+
+    // default values for optional fields of all app configs
+    #[serde(default)]
+    pub defaults: Table,
+
+    // app configs defined inline as [[apps]], in addition to the config files
+    #[serde(default)]
+    pub apps: Vec<Table>,
 }
-pub static GLOBAL_CONFIG: Lazy<GlobalConfig> = Lazy::new(|| load_global_config());
 
 const GLOBAL_CONFIG_FILENAME: &str = ".bkpconfig";
+const GLOBAL_CONFIG_ENV: &str = "BKP_CONFIG";
+const XDG_GLOBAL_CONFIG_PATH: &str = "bkp/config.toml";
+const SYSTEM_GLOBAL_CONFIG_PATH: &str = "/etc/bkp/config.toml";
+const CONFIG_FILES_DIR: &str = "conf.d";
+
+// the global config is looked up in this order: the explicit path (--config), the BKP_CONFIG env
+// var, ~/.bkpconfig, $XDG_CONFIG_HOME/bkp/config.toml (~/.config if unset) and /etc/bkp/config.toml
+pub fn find_global_config_path(explicit_path: Option<&Path>) -> Result<PathBuf, Error> {
+    if let Some(path) = explicit_path {
+        return Ok(path.to_path_buf());
+    }
+
+    if let Some(path) = env::var_os(GLOBAL_CONFIG_ENV) {
+        return Ok(PathBuf::from(path));
+    }
+
+    let home_dir = home::home_dir();
+    let xdg_config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| home_dir.as_ref().map(|home| home.join(".config")));
 
-pub fn load_global_config() -> GlobalConfig {
-    let home_dir = home::home_dir().unwrap().join(GLOBAL_CONFIG_FILENAME);
-    let config = read_file_to_string(&home_dir);
-    match toml::from_str(config.as_str()) {
-        Ok(config) => config,
-        Err(e) => {
-            error!("Error parsing global config file: {}", e);
-            exit(1)
-        }
+    let candidates = [
+        home_dir.map(|home| home.join(GLOBAL_CONFIG_FILENAME)),
+        xdg_config_home.map(|dir| dir.join(XDG_GLOBAL_CONFIG_PATH)),
+        Some(PathBuf::from(SYSTEM_GLOBAL_CONFIG_PATH)),
+    ];
+
+    candidates
+        .into_iter()
+        .flatten()
+        .find(|path| path.is_file())
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "No global config found, pass --config, set {} or create ~/{}, $XDG_CONFIG_HOME/{} or {}",
+                    GLOBAL_CONFIG_ENV,
+                    GLOBAL_CONFIG_FILENAME,
+                    XDG_GLOBAL_CONFIG_PATH,
+                    SYSTEM_GLOBAL_CONFIG_PATH
+                ),
+            )
+        })
+}
+
+pub fn parse_global_config(config: &str) -> Result<GlobalConfig, Error> {
+    toml::from_str(config).map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Error parsing global config file: {}", e),
+        )
+    })
+}
+
+pub fn load_global_config(path: &Path) -> Result<GlobalConfig, Error> {
+    let config = read_file_to_string(path).map_err(|e| {
+        Error::new(
+            e.kind(),
+            format!(
+                "Unable to read global config file {}: {}",
+                path.display(),
+                e
+            ),
+        )
+    })?;
+    let mut global_config = parse_global_config(config.as_str())?;
+
+    if global_config.config_files_location.is_empty() {
+        global_config.config_files_location = path
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join(CONFIG_FILES_DIR)
+            .to_string_lossy()
+            .to_string();
     }
+
+    Ok(global_config)
 }
 
 // fn get_global_config() -> GlobalConfig {
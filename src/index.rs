@@ -0,0 +1,214 @@
+use std::{
+    ffi::OsStr,
+    io::{self, ErrorKind},
+    os::unix::ffi::OsStrExt,
+    panic,
+    path::{Path, PathBuf},
+};
+
+use log::{error, warn};
+
+use crate::{
+    backup::{parse_backup_from_path, Backup},
+    catalog::Catalog,
+    diff::{archive_tree, FileState, Kind, Tree},
+    globalconfig::GlobalConfig,
+    manifest::{escape, unescape},
+    storage::s3::{delete_remote_object, get_remote_object, put_remote_object},
+};
+
+// the index of a backup lists the files of its archive, so bkp find and bkp history don't
+// have to read every archive. It is kept in the catalog and as index/<backup> in the
+// bucket, with one line per entry: type, size, crc32 (- for
+// anything but files), mode, mtime and path, tab separated, followed by the target of
+// symlinks.
+const REMOTE_INDEX_PREFIX: &str = "index/";
+
+pub fn index_to_bytes(tree: &Tree) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::new();
+
+    for (path, state) in tree {
+        let kind = match state.kind {
+            Kind::File => "file",
+            Kind::Dir => "dir",
+            Kind::Symlink(_) => "symlink",
+            Kind::Other => "other",
+        };
+        let crc = state
+            .crc
+            .map(|crc| format!("{:08x}", crc))
+            .unwrap_or_else(|| "-".to_string());
+        let fields = format!(
+            "{}\t{}\t{}\t{:o}\t{}\t",
+            kind, state.size, crc, state.mode, state.mtime
+        );
+
+        bytes.extend_from_slice(fields.as_bytes());
+        bytes.extend_from_slice(&escape(path.as_os_str().as_bytes()));
+        if let Kind::Symlink(target) = &state.kind {
+            bytes.push(b'\t');
+            bytes.extend_from_slice(&escape(target.as_os_str().as_bytes()));
+        }
+        bytes.push(b'\n');
+    }
+
+    bytes
+}
+
+pub fn parse_index(bytes: &[u8]) -> Result<Tree, io::Error> {
+    let mut tree = Tree::new();
+
+    for line in bytes.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+        let fields = line.split(|b| *b == b'\t').collect::<Vec<&[u8]>>();
+        let (kind, size, crc, mode, mtime, path) = match fields.as_slice() {
+            [kind, size, crc, mode, mtime, path, rest @ ..] if rest.len() <= 1 => {
+                let kind = match (*kind, rest) {
+                    (b"file", []) => Kind::File,
+                    (b"dir", []) => Kind::Dir,
+                    (b"symlink", [target]) => Kind::Symlink(bytes_to_path(target)),
+                    (b"other", []) => Kind::Other,
+                    _ => return Err(invalid_line(line)),
+                };
+                let crc = match *crc {
+                    b"-" => None,
+                    crc => Some(parse_field(crc, 16, line)? as u32),
+                };
+                (
+                    kind,
+                    parse_field(size, 10, line)?,
+                    crc,
+                    parse_field(mode, 8, line)? as u32,
+                    parse_field(mtime, 10, line)?,
+                    bytes_to_path(path),
+                )
+            }
+            _ => return Err(invalid_line(line)),
+        };
+
+        tree.insert(
+            path,
+            FileState {
+                kind,
+                mode,
+                size,
+                crc,
+                mtime,
+                content: None,
+            },
+        );
+    }
+
+    Ok(tree)
+}
+
+// the index of a backup. Missing indexes are built from the archive, which is streamed for
+// remote backups, and kept in the catalog for the next time.
+pub fn load_index(
+    global_config: &GlobalConfig,
+    backup: &Backup,
+    remote: bool,
+) -> Result<Tree, io::Error> {
+    let catalog = Catalog::open(Path::new(&global_config.local_storage_location))?;
+    if let Some(tree) = catalog.index(backup)? {
+        return Ok(tree);
+    }
+
+    if remote {
+        // the s3 client panics when the remote storage is unreachable
+        let key = remote_index_key(backup);
+        if let Ok(Ok(Some(content))) =
+            panic::catch_unwind(|| get_remote_object(global_config, &key))
+        {
+            let tree = parse_index(content.as_bytes())?;
+            catalog.set_index(backup, &tree)?;
+            return Ok(tree);
+        }
+    }
+
+    let tree = archive_tree(global_config, backup, remote, 0)?;
+    catalog.set_index(backup, &tree)?;
+    if remote {
+        upload_index(global_config, backup);
+    }
+
+    Ok(tree)
+}
+
+// indexes a new backup in local storage, backup_file_path is without extension as from
+// get_new_backup_file_path
+pub fn index_backup(
+    global_config: &GlobalConfig,
+    backup_file_path: &Path,
+) -> Result<(), io::Error> {
+    let mut archive = backup_file_path.as_os_str().to_os_string();
+    archive.push(".tar.gz");
+    let backup = parse_backup_from_path(Path::new(&archive));
+
+    let tree = archive_tree(global_config, &backup, false, 0)?;
+    Catalog::open(Path::new(&global_config.local_storage_location))?.set_index(&backup, &tree)
+}
+
+// copies the index of a backup from the catalog to the bucket, backups work without it
+pub fn upload_index(global_config: &GlobalConfig, backup: &Backup) {
+    let tree = Catalog::open(Path::new(&global_config.local_storage_location))
+        .and_then(|catalog| catalog.index(backup));
+    let tree = match tree {
+        Ok(Some(tree)) => tree,
+        Ok(None) => {
+            warn!("No index of {} to upload", backup.file_name);
+            return;
+        }
+        Err(e) => {
+            warn!("No index of {} to upload: {}", backup.file_name, e);
+            return;
+        }
+    };
+
+    let key = remote_index_key(backup);
+    let content = String::from_utf8_lossy(&index_to_bytes(&tree)).to_string();
+    if panic::catch_unwind(|| put_remote_object(global_config, &key, &content)).is_err() {
+        error!("Error uploading the index of {}", backup.file_name);
+    }
+}
+
+pub fn delete_remote_index(global_config: &GlobalConfig, backup: &Backup) {
+    let key = remote_index_key(backup);
+    match panic::catch_unwind(|| delete_remote_object(global_config, &key)) {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => error!(
+            "Error deleting the remote index of {}: {}",
+            backup.file_name, e
+        ),
+        Err(_) => error!("Error deleting the remote index of {}", backup.file_name),
+    }
+}
+
+// remote backups are stored without the .tar.gz extension, both share one index name
+fn index_name(backup: &Backup) -> &str {
+    backup
+        .file_name
+        .strip_suffix(".tar.gz")
+        .unwrap_or(&backup.file_name)
+}
+
+fn remote_index_key(backup: &Backup) -> String {
+    format!("{}{}", REMOTE_INDEX_PREFIX, index_name(backup))
+}
+
+fn bytes_to_path(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(OsStr::from_bytes(&unescape(bytes)))
+}
+
+fn parse_field(field: &[u8], radix: u32, line: &[u8]) -> Result<u64, io::Error> {
+    std::str::from_utf8(field)
+        .ok()
+        .and_then(|field| u64::from_str_radix(field, radix).ok())
+        .ok_or_else(|| invalid_line(line))
+}
+
+fn invalid_line(line: &[u8]) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("Invalid index line: {}", String::from_utf8_lossy(line)),
+    )
+}
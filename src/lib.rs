@@ -0,0 +1,40 @@
+//! bkp is a simple backup utility with file level deduplication. Backups are
+//! saved locally, and remotely via s3.
+//!
+//! The library exposes the same operations as the `bkp` binary, but every
+//! operation takes its [`GlobalConfig`] and [`Config`] explicitly, so it can be
+//! embedded in other tools.
+
+pub mod actions;
+pub mod backup;
+pub mod browse;
+pub mod catalog;
+pub mod compress;
+pub mod config;
+pub mod configcheck;
+pub mod daemon;
+pub mod diff;
+pub mod extract;
+pub mod globalconfig;
+pub mod index;
+pub mod lock;
+pub mod manifest;
+pub mod metadata;
+pub mod mount;
+pub mod recover;
+pub mod scripts;
+pub mod secret;
+pub mod semaphore;
+pub mod sources;
+pub mod storage;
+pub mod time;
+
+pub use crate::{
+    actions::{
+        backup_all, cat, diff, find, full_backup, history, incremental_backup, list, ls, prune,
+        restore,
+    },
+    backup::{Backup, BackupType},
+    config::Config,
+    globalconfig::GlobalConfig,
+};
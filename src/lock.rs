@@ -0,0 +1,283 @@
+use std::{
+    fs::{create_dir_all, hard_link, remove_file, write},
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+};
+
+use chrono::Utc;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::get_hostname,
+    globalconfig::GlobalConfig,
+    storage::{
+        fs::read_file_to_string,
+        s3::{create_remote_object, delete_remote_object, get_remote_object},
+    },
+    time::parse_timestamp,
+};
+
+// locks held by another host can't be checked for a live process, they are
+// considered stale once they are older than this
+const STALE_LOCK_AGE_HOURS: i64 = 24;
+const LOCK_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const LOCKS_DIR: &str = ".locks";
+const REPOSITORY_LOCK_NAME: &str = "repository";
+
+// tells apart the temporary lock files of threads of the same process
+static TMP_LOCK_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LockInfo {
+    pub pid: u32,
+    pub host: String,
+    pub operation: String,
+    pub created: String,
+}
+
+#[derive(Debug)]
+pub enum Lock {
+    Local(PathBuf),
+    Remote(Box<GlobalConfig>, String),
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        match self {
+            Lock::Local(path) => {
+                if let Err(e) = remove_file(&path) {
+                    warn!("Unable to remove lock file {}: {}", path.display(), e);
+                }
+            }
+            // errors are only logged, panicking in drop would abort a run that is already
+            // unwinding
+            Lock::Remote(global_config, key) => {
+                if let Err(e) = delete_remote_object(global_config, key) {
+                    error!("Unable to remove remote lock {}: {}", key, e);
+                }
+            }
+        }
+    }
+}
+
+impl LockInfo {
+    fn new(operation: &str) -> LockInfo {
+        LockInfo {
+            pid: process::id(),
+            host: get_hostname(),
+            operation: operation.to_string(),
+            created: Utc::now().to_rfc3339(),
+        }
+    }
+
+    fn is_stale(&self) -> bool {
+        if self.host == get_hostname() {
+            return !process_exists(self.pid);
+        }
+
+        match parse_timestamp(self.created.clone()) {
+            Some(created) => Utc::now() - created > chrono::Duration::hours(STALE_LOCK_AGE_HOURS),
+            None => true,
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "{} (pid {} on {} since {})",
+            self.operation, self.pid, self.host, self.created
+        )
+    }
+}
+
+#[cfg(unix)]
+fn process_exists(pid: u32) -> bool {
+    // signal 0 only checks whether the process exists
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_exists(_pid: u32) -> bool {
+    true
+}
+
+fn get_lock_path(global_config: &GlobalConfig, name: &str) -> PathBuf {
+    PathBuf::from(&global_config.local_storage_location)
+        .join(LOCKS_DIR)
+        .join(name.to_string() + ".lock")
+}
+
+fn get_remote_lock_key(app_name: &str) -> String {
+    format!("locks/{}.lock", app_name)
+}
+
+fn lock_error(name: &str, holder: &LockInfo) -> Error {
+    Error::new(
+        ErrorKind::WouldBlock,
+        format!("{} is locked by {}", name, holder.describe()),
+    )
+}
+
+// the lock file is written under a temporary name and hard linked into place,
+// so other processes never see a lock file without its content
+fn try_create_lock_file(path: &Path, info: &LockInfo) -> Result<bool, Error> {
+    let tmp_path = path.with_extension(format!(
+        "lock.{}.{}",
+        info.pid,
+        TMP_LOCK_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    write(&tmp_path, toml::to_string(info).unwrap())?;
+
+    let result = hard_link(&tmp_path, path);
+    remove_file(&tmp_path)?;
+
+    match result {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn read_lock_file(path: &Path) -> Option<LockInfo> {
+    let content = read_file_to_string(path).ok()?;
+    toml::from_str(content.as_str()).ok()
+}
+
+fn acquire_local_lock(
+    global_config: &GlobalConfig,
+    name: &str,
+    operation: &str,
+    wait: bool,
+) -> Result<Lock, Error> {
+    let path = get_lock_path(global_config, name);
+    create_dir_all(path.parent().unwrap())?;
+
+    let info = LockInfo::new(operation);
+    let mut waiting = false;
+
+    loop {
+        if try_create_lock_file(&path, &info)? {
+            return Ok(Lock::Local(path));
+        }
+
+        let holder = match read_lock_file(&path) {
+            Some(holder) => holder,
+            // lock was released in the meantime, or is unreadable
+            None if path.exists() => {
+                warn!("Removing unreadable lock file {}", path.display());
+                remove_file(&path)?;
+                continue;
+            }
+            None => continue,
+        };
+
+        if holder.is_stale() {
+            warn!(
+                "Removing stale lock of {} held by {}",
+                name,
+                holder.describe()
+            );
+            remove_file(&path)?;
+            continue;
+        }
+
+        if !wait {
+            return Err(lock_error(name, &holder));
+        }
+
+        if !waiting {
+            info!("Waiting for lock of {} held by {}", name, holder.describe());
+            waiting = true;
+        }
+        thread::sleep(LOCK_POLL_INTERVAL);
+    }
+}
+
+// per app lock, held during backup, prune and restore of the app
+pub fn lock_app(
+    global_config: &GlobalConfig,
+    app_name: &str,
+    operation: &str,
+    wait: bool,
+) -> Result<Lock, Error> {
+    acquire_local_lock(global_config, app_name, operation, wait)
+}
+
+// per repository lock, held by runs that touch all apps in local storage
+pub fn lock_repository(
+    global_config: &GlobalConfig,
+    operation: &str,
+    wait: bool,
+) -> Result<Lock, Error> {
+    acquire_local_lock(global_config, REPOSITORY_LOCK_NAME, operation, wait)
+}
+
+// lock object in the shared bucket, so servers backing up the same app don't
+// prune what another one is uploading. The object is created with a conditional put,
+// which is atomic on storage supporting it (AWS S3, MinIO). Elsewhere the lock is best
+// effort: the object is read back after writing to detect a concurrent writer, but two
+// hosts can still both take it. Replacing a stale lock is best effort everywhere.
+// Without a reachable remote storage there is no lock: local backups and prunes go on, and
+// their uploads and remote deletions fail on their own.
+pub fn lock_remote_app(
+    global_config: &GlobalConfig,
+    app_name: &str,
+    operation: &str,
+    wait: bool,
+) -> Result<Option<Lock>, Error> {
+    let key = get_remote_lock_key(app_name);
+    let info = LockInfo::new(operation);
+    let content = toml::to_string(&info).unwrap();
+    let mut waiting = false;
+
+    loop {
+        let existing = match get_remote_object(global_config, &key) {
+            Ok(existing) => existing,
+            Err(e) if e.kind() == ErrorKind::NotConnected => {
+                warn!(
+                    "Not taking lock {}, remote storage is unreachable: {}",
+                    key, e
+                );
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        let holder = existing.map(|content| {
+            toml::from_str::<LockInfo>(content.as_str()).map_err(|_| content.trim().to_string())
+        });
+
+        match holder {
+            Some(Ok(holder)) if holder == info => {
+                return Ok(Some(Lock::Remote(Box::new(global_config.clone()), key)))
+            }
+            Some(Ok(holder)) if !holder.is_stale() => {
+                if !wait {
+                    return Err(lock_error(&key, &holder));
+                }
+
+                if !waiting {
+                    info!("Waiting for lock of {} held by {}", key, holder.describe());
+                    waiting = true;
+                }
+                thread::sleep(LOCK_POLL_INTERVAL);
+            }
+            Some(Ok(holder)) => {
+                warn!("Removing stale lock {} held by {}", key, holder.describe());
+                delete_remote_object(global_config, &key)?;
+            }
+            Some(Err(content)) => {
+                warn!("Removing unreadable lock {}: {}", key, content);
+                delete_remote_object(global_config, &key)?;
+            }
+            // read back on the next pass, also when another host created it first
+            None => {
+                create_remote_object(global_config, &key, &content)?;
+            }
+        }
+    }
+}
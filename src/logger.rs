@@ -4,29 +4,71 @@ extern crate simplelog;
 
 // use log::{debug, error, info};
 
-use log::{debug, error};
+use log::{debug, error, Log, Metadata, Record};
 use simplelog::*;
 
 use std::{fs::OpenOptions, process::exit};
 
-use crate::globalconfig::GLOBAL_CONFIG;
+use bkp::secret::redact;
 
-pub fn create_logger() {
-    match CombinedLogger::init(vec![
+// replaces secret values in every log line before passing it on, so credentials
+// never end up on the terminal or in the log file
+struct RedactingLogger {
+    inner: Box<CombinedLogger>,
+}
+
+impl Log for RedactingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let message = redact(&record.args().to_string());
+
+        self.inner.log(
+            &Record::builder()
+                .args(format_args!("{}", message))
+                .metadata(record.metadata().clone())
+                .target(record.target())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+pub fn create_logger(log_file_location: &str) {
+    // backups of several apps can run in parallel, each in a thread named after the app,
+    // so thread names are logged to keep the lines of each app apart
+    let config = ConfigBuilder::new()
+        .set_thread_level(LevelFilter::Error)
+        .set_thread_mode(ThreadLogMode::Names)
+        .build();
+
+    let inner = CombinedLogger::new(vec![
         TermLogger::new(
             LevelFilter::Info,
-            Config::default(),
+            config.clone(),
             TerminalMode::Mixed,
             ColorChoice::Auto,
         ),
         WriteLogger::new(
             LevelFilter::Info,
-            Config::default(),
+            config,
             // File::create("bkp.log").unwrap(),
             match OpenOptions::new()
                 .create(true)
                 .append(true)
-                .open(GLOBAL_CONFIG.log_file_location.to_string())
+                .open(log_file_location)
             {
                 Ok(file) => file,
                 Err(e) => {
@@ -34,7 +76,11 @@ pub fn create_logger() {
                 }
             },
         ),
-    ]) {
+    ]);
+
+    log::set_max_level(inner.level());
+
+    match log::set_boxed_logger(Box::new(RedactingLogger { inner })) {
         Ok(_) => debug!("Logger initialized"),
         Err(e) => {
             error!("Unable to initialize logger: {}", e);
@@ -0,0 +1,189 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, ErrorKind, Read},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use flate2::CrcReader;
+
+use crate::sources::VIRTUAL_ENTRY_DIR;
+
+const MANIFEST_NAME: &str = "manifest";
+
+// what an archive entry was when it was backed up
+#[derive(Debug, Clone, PartialEq)]
+pub enum ManifestEntry {
+    File { size: u64, crc: u32 },
+    Dir,
+    Symlink,
+    // device nodes and fifos
+    Other,
+}
+
+// the entries of an archive, stored in the archive as .bkp/manifest with one line per
+// entry: file <size> <crc32>, dir, symlink or other, followed by the path, tab separated
+#[derive(Debug, Clone, Default)]
+pub struct Manifest {
+    pub entries: BTreeMap<PathBuf, ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn entry_name() -> PathBuf {
+        Path::new(VIRTUAL_ENTRY_DIR).join(MANIFEST_NAME)
+    }
+
+    pub fn insert(&mut self, path: &Path, entry: ManifestEntry) {
+        self.entries.insert(path.to_path_buf(), entry);
+    }
+
+    // entries of later archives of a chain replace those of earlier ones
+    pub fn merge(&mut self, manifest: Manifest) {
+        self.entries.extend(manifest.entries);
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+
+        for (path, entry) in &self.entries {
+            let kind = match entry {
+                ManifestEntry::File { size, crc } => format!("file\t{}\t{:08x}", size, crc),
+                ManifestEntry::Dir => "dir".to_string(),
+                ManifestEntry::Symlink => "symlink".to_string(),
+                ManifestEntry::Other => "other".to_string(),
+            };
+            bytes.extend_from_slice(kind.as_bytes());
+            bytes.push(b'\t');
+            bytes.extend_from_slice(&escape(path.as_os_str().as_bytes()));
+            bytes.push(b'\n');
+        }
+
+        bytes
+    }
+
+    pub fn read<R: Read + ?Sized>(reader: &mut R) -> Result<Manifest, io::Error> {
+        let mut manifest = Manifest::default();
+
+        for line in BufReader::new(reader).split(b'\n') {
+            let line = line?;
+            let fields = line.split(|b| *b == b'\t').collect::<Vec<&[u8]>>();
+
+            let (entry, path) = match fields.as_slice() {
+                [b"file", size, crc, path] => {
+                    let size = parse_field(size, 10)?;
+                    let crc = parse_field(crc, 16)? as u32;
+                    (ManifestEntry::File { size, crc }, path)
+                }
+                [b"dir", path] => (ManifestEntry::Dir, path),
+                [b"symlink", path] => (ManifestEntry::Symlink, path),
+                [b"other", path] => (ManifestEntry::Other, path),
+                _ => return Err(invalid_line(&line)),
+            };
+
+            let path = PathBuf::from(OsStr::from_bytes(&unescape(path)));
+            manifest.entries.insert(path, entry);
+        }
+
+        Ok(manifest)
+    }
+
+    // compares the entries with the files below root, returns a description of each
+    // mismatch. Paths for which skip returns true are not checked.
+    pub fn verify(&self, root: &Path, skip: impl Fn(&Path) -> bool) -> Vec<String> {
+        let mut mismatches: Vec<String> = Vec::new();
+
+        for (path, entry) in &self.entries {
+            if skip(path) {
+                continue;
+            }
+
+            if let Err(reason) = verify_entry(&root.join(path), entry) {
+                mismatches.push(format!("{}: {}", path.display(), reason));
+            }
+        }
+
+        mismatches
+    }
+}
+
+fn verify_entry(path: &Path, entry: &ManifestEntry) -> Result<(), String> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Err("missing".to_string()),
+        Err(e) => return Err(e.to_string()),
+    };
+
+    match entry {
+        ManifestEntry::File { size, crc } => {
+            if !metadata.is_file() {
+                return Err("not a regular file".to_string());
+            }
+            if metadata.len() != *size {
+                return Err(format!("size is {}, expected {}", metadata.len(), size));
+            }
+            let actual = file_crc(path).map_err(|e| e.to_string())?;
+            if actual != *crc {
+                return Err(format!("crc32 is {:08x}, expected {:08x}", actual, crc));
+            }
+        }
+        ManifestEntry::Dir if !metadata.is_dir() => return Err("not a directory".to_string()),
+        ManifestEntry::Symlink if !metadata.is_symlink() => return Err("not a symlink".to_string()),
+        _ => {}
+    }
+
+    Ok(())
+}
+
+pub fn file_crc(path: &Path) -> Result<u32, io::Error> {
+    let mut reader = CrcReader::new(File::open(path)?);
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(reader.crc().sum())
+}
+
+fn parse_field(field: &[u8], radix: u32) -> Result<u64, io::Error> {
+    std::str::from_utf8(field)
+        .ok()
+        .and_then(|field| u64::from_str_radix(field, radix).ok())
+        .ok_or_else(|| invalid_line(field))
+}
+
+fn invalid_line(line: &[u8]) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("Invalid manifest line: {}", String::from_utf8_lossy(line)),
+    )
+}
+
+// paths may contain any byte but '\0', tabs and newlines separate the fields
+pub fn escape(path: &[u8]) -> Vec<u8> {
+    let mut escaped: Vec<u8> = Vec::with_capacity(path.len());
+    for byte in path {
+        match byte {
+            b'\\' => escaped.extend_from_slice(b"\\\\"),
+            b'\t' => escaped.extend_from_slice(b"\\t"),
+            b'\n' => escaped.extend_from_slice(b"\\n"),
+            byte => escaped.push(*byte),
+        }
+    }
+    escaped
+}
+
+pub fn unescape(path: &[u8]) -> Vec<u8> {
+    let mut unescaped: Vec<u8> = Vec::with_capacity(path.len());
+    let mut bytes = path.iter();
+    while let Some(byte) = bytes.next() {
+        match (byte, bytes.clone().next()) {
+            (b'\\', Some(b't')) => unescaped.push(b'\t'),
+            (b'\\', Some(b'n')) => unescaped.push(b'\n'),
+            (b'\\', Some(b'\\')) => unescaped.push(b'\\'),
+            (byte, _) => {
+                unescaped.push(*byte);
+                continue;
+            }
+        }
+        bytes.next();
+    }
+    unescaped
+}
@@ -0,0 +1,303 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, ErrorKind, Read},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use chrono::Utc;
+use flate2::CrcReader;
+use serde::Deserialize;
+use tar::{Archive, Builder, Entry, EntryType, Header, HeaderMode};
+
+use crate::{
+    manifest::{Manifest, ManifestEntry},
+    sources::append_virtual_entry_mark,
+};
+
+// pax header keys of extended attributes, the same as GNU tar and bsdtar use
+const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+// which file metadata is kept in backups and restored, in toml the [metadata] section of
+// the app config. Everything is preserved by default, each flag can be disabled.
+#[derive(Deserialize, Debug, Clone)]
+pub struct MetadataPolicy {
+    // store symlinks as links, otherwise the file they point to is stored
+    #[serde(default = "enabled")]
+    pub symlinks: bool,
+    // store further paths of a file with several hard links as links to the first one
+    #[serde(default = "enabled")]
+    pub hard_links: bool,
+    // restore uid and gid, only possible when restoring as root
+    #[serde(default = "enabled")]
+    pub ownership: bool,
+    // restore setuid, setgid and sticky bits, otherwise only rwx bits are restored
+    #[serde(default = "enabled")]
+    pub permissions: bool,
+    // restore modification times
+    #[serde(default = "enabled")]
+    pub mtime: bool,
+    // store and restore extended attributes, which includes POSIX ACLs
+    #[serde(default = "enabled")]
+    pub xattrs: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl Default for MetadataPolicy {
+    fn default() -> MetadataPolicy {
+        MetadataPolicy {
+            symlinks: true,
+            hard_links: true,
+            ownership: true,
+            permissions: true,
+            mtime: true,
+            xattrs: true,
+        }
+    }
+}
+
+// appends files to an archive according to the policy, remembering files with several
+// hard links so only their first path is stored with content. Every appended entry is
+// recorded in the manifest, with the crc32 of the content which was actually archived.
+pub struct MetadataWriter<'a> {
+    policy: &'a MetadataPolicy,
+    hard_links: HashMap<(u64, u64), PathBuf>,
+    manifest: Manifest,
+}
+
+impl MetadataWriter<'_> {
+    pub fn new(policy: &MetadataPolicy) -> MetadataWriter<'_> {
+        MetadataWriter {
+            policy,
+            hard_links: HashMap::new(),
+            manifest: Manifest::default(),
+        }
+    }
+
+    pub fn configure<W: io::Write>(&self, tar_builder: &mut Builder<W>) {
+        tar_builder.follow_symlinks(!self.policy.symlinks);
+        tar_builder.mode(HeaderMode::Complete);
+    }
+
+    // everything which can fail is read before the first header of the entry is written, the
+    // pax header with the xattrs would otherwise apply to the entry appended after it
+    pub fn append<W: io::Write>(
+        &mut self,
+        tar_builder: &mut Builder<W>,
+        path: &Path,
+        name: &Path,
+    ) -> Result<(), io::Error> {
+        let metadata = match self.policy.symlinks {
+            true => fs::symlink_metadata(path)?,
+            false => fs::metadata(path)?,
+        };
+
+        let xattrs = match self.policy.xattrs {
+            true => self.read_xattrs(path)?,
+            false => Vec::new(),
+        };
+
+        let mut header = Header::new_gnu();
+        header.set_metadata_in_mode(&metadata, HeaderMode::Complete);
+
+        if self.policy.hard_links && metadata.is_file() && metadata.nlink() > 1 {
+            let inode = (metadata.dev(), metadata.ino());
+            if let Some(target) = self.hard_links.get(&inode) {
+                header.set_entry_type(EntryType::Link);
+                header.set_size(0);
+                append_xattrs(tar_builder, &xattrs)?;
+                tar_builder.append_link(&mut header, name, target)?;
+
+                if let Some(entry) = self.manifest.entries.get(target).cloned() {
+                    self.manifest.insert(name, entry);
+                }
+                return Ok(());
+            }
+        }
+
+        let file_type = metadata.file_type();
+        let entry = if file_type.is_file() {
+            let file = fs::File::open(path)?;
+            append_xattrs(tar_builder, &xattrs)?;
+            self.append_file(tar_builder, file, name, header, &metadata)?
+        } else if file_type.is_dir() {
+            header.set_size(0);
+            append_xattrs(tar_builder, &xattrs)?;
+            tar_builder.append_data(&mut header, name, io::empty())?;
+            ManifestEntry::Dir
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(path)?;
+            header.set_size(0);
+            append_xattrs(tar_builder, &xattrs)?;
+            tar_builder.append_link(&mut header, name, target)?;
+            ManifestEntry::Symlink
+        } else {
+            set_special_file(&mut header, path, &metadata)?;
+            append_xattrs(tar_builder, &xattrs)?;
+            tar_builder.append_data(&mut header, name, io::empty())?;
+            ManifestEntry::Other
+        };
+
+        if self.policy.hard_links && file_type.is_file() && metadata.nlink() > 1 {
+            self.hard_links
+                .insert((metadata.dev(), metadata.ino()), name.to_path_buf());
+        }
+        self.manifest.insert(name, entry);
+        Ok(())
+    }
+
+    // appends a file stored under another name, like a database snapshot
+    pub fn append_renamed<W: io::Write>(
+        &mut self,
+        tar_builder: &mut Builder<W>,
+        path: &Path,
+        name: &Path,
+    ) -> Result<(), io::Error> {
+        let metadata = fs::metadata(path)?;
+        let file = fs::File::open(path)?;
+
+        let mut header = Header::new_gnu();
+        header.set_metadata_in_mode(&metadata, HeaderMode::Complete);
+        let entry = self.append_file(tar_builder, file, name, header, &metadata)?;
+        self.manifest.insert(name, entry);
+        Ok(())
+    }
+
+    fn append_file<W: io::Write>(
+        &mut self,
+        tar_builder: &mut Builder<W>,
+        file: fs::File,
+        name: &Path,
+        mut header: Header,
+        metadata: &fs::Metadata,
+    ) -> Result<ManifestEntry, io::Error> {
+        let mut reader = CrcReader::new(file);
+        tar_builder.append_data(&mut header, name, &mut reader)?;
+
+        Ok(ManifestEntry::File {
+            size: metadata.len(),
+            crc: reader.crc().sum(),
+        })
+    }
+
+    // appends the manifest of everything appended so far as .bkp/manifest
+    pub fn append_manifest<W: io::Write>(
+        &self,
+        tar_builder: &mut Builder<W>,
+    ) -> Result<(), io::Error> {
+        let manifest = self.manifest.to_bytes();
+
+        let mut header = Header::new_gnu();
+        header.set_size(manifest.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(Utc::now().timestamp() as u64);
+        append_virtual_entry_mark(tar_builder)?;
+        tar_builder.append_data(&mut header, Manifest::entry_name(), manifest.as_slice())
+    }
+
+    fn read_xattrs(&self, path: &Path) -> Result<Vec<(String, Vec<u8>)>, io::Error> {
+        let names = match self.policy.symlinks {
+            true => xattr::list(path),
+            false => xattr::list_deref(path),
+        };
+        // file systems without xattr support have no xattrs to keep
+        let names = match names {
+            Ok(names) => names,
+            Err(e) if e.kind() == ErrorKind::Unsupported => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut xattrs: Vec<(String, Vec<u8>)> = Vec::new();
+        for name in names {
+            let value = match self.policy.symlinks {
+                true => xattr::get(path, &name)?,
+                false => xattr::get_deref(path, &name)?,
+            };
+
+            if let (Some(value), Some(name)) = (value, name.to_str()) {
+                xattrs.push((PAX_XATTR_PREFIX.to_string() + name, value));
+            }
+        }
+
+        Ok(xattrs)
+    }
+}
+
+fn append_xattrs<W: io::Write>(
+    tar_builder: &mut Builder<W>,
+    xattrs: &[(String, Vec<u8>)],
+) -> Result<(), io::Error> {
+    // nothing is written without xattrs
+    tar_builder.append_pax_extensions(
+        xattrs
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_slice())),
+    )
+}
+
+// fifos and device nodes, like tar's own Builder::append_path does it
+fn set_special_file(
+    header: &mut Header,
+    path: &Path,
+    metadata: &fs::Metadata,
+) -> Result<(), io::Error> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if metadata.file_type().is_socket() {
+        return Err(io::Error::other(format!(
+            "{}: socket can not be archived",
+            path.display()
+        )));
+    }
+
+    let dev_id = metadata.rdev();
+    let dev_major = ((dev_id >> 32) & 0xffff_f000) | ((dev_id >> 8) & 0x0000_0fff);
+    let dev_minor = ((dev_id >> 12) & 0xffff_ff00) | (dev_id & 0x0000_00ff);
+    header.set_device_major(dev_major as u32)?;
+    header.set_device_minor(dev_minor as u32)?;
+    header.set_size(0);
+    Ok(())
+}
+
+// tar only applies the xattrs of files when unpacking, those of directories are set here
+pub fn unpack_dir_xattrs<R: Read>(
+    entry: &mut Entry<R>,
+    path: &Path,
+    policy: &MetadataPolicy,
+) -> Result<(), io::Error> {
+    if !policy.xattrs {
+        return Ok(());
+    }
+    let Some(extensions) = entry.pax_extensions()? else {
+        return Ok(());
+    };
+
+    for extension in extensions {
+        let extension = extension?;
+        if let Some(name) = extension
+            .key()
+            .ok()
+            .and_then(|key| key.strip_prefix(PAX_XATTR_PREFIX))
+        {
+            xattr::set(path, name, extension.value_bytes())?;
+        }
+    }
+
+    Ok(())
+}
+
+// applies the policy to an archive before its entries are unpacked
+pub fn configure_unpack<R: Read>(tar_archive: &mut Archive<R>, policy: &MetadataPolicy) {
+    tar_archive.set_preserve_permissions(policy.permissions);
+    tar_archive.set_preserve_mtime(policy.mtime);
+    tar_archive.set_unpack_xattrs(policy.xattrs);
+    tar_archive.set_preserve_ownerships(policy.ownership && is_root());
+}
+
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
@@ -0,0 +1,352 @@
+// the kernel side of FUSE spoken directly over /dev/fuse, only the subset a read-only
+// file system needs. Structs are written in the layout of protocol version 7.31, see
+// include/uapi/linux/fuse.h.
+use std::{
+    ffi::{CString, OsStr},
+    fs::{File, OpenOptions},
+    io::{self, ErrorKind, Read, Write},
+    os::unix::{ffi::OsStrExt, io::AsRawFd},
+    path::{Path, PathBuf},
+};
+
+pub const ROOT_ID: u64 = 1;
+
+const KERNEL_VERSION: u32 = 7;
+const KERNEL_MINOR_VERSION: u32 = 31;
+
+const IN_HEADER_SIZE: usize = 40;
+const OUT_HEADER_SIZE: usize = 16;
+// large enough for any request, bkp never accepts writes
+pub const BUFFER_SIZE: usize = 1024 * 1024 + 4096;
+const MAX_WRITE: u32 = 4096;
+
+pub const LOOKUP: u32 = 1;
+pub const FORGET: u32 = 2;
+pub const GETATTR: u32 = 3;
+pub const READLINK: u32 = 5;
+pub const OPEN: u32 = 14;
+pub const READ: u32 = 15;
+pub const STATFS: u32 = 17;
+pub const RELEASE: u32 = 18;
+pub const FLUSH: u32 = 25;
+pub const INIT: u32 = 26;
+pub const OPENDIR: u32 = 27;
+pub const READDIR: u32 = 28;
+pub const RELEASEDIR: u32 = 29;
+pub const INTERRUPT: u32 = 36;
+pub const DESTROY: u32 = 38;
+pub const BATCH_FORGET: u32 = 42;
+
+// attributes of a node, mode includes the file type bits
+#[derive(Debug, Clone)]
+pub struct Attr {
+    pub ino: u64,
+    pub size: u64,
+    pub mtime: u64,
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Attr {
+    // struct fuse_attr
+    fn write_to(&self, out: &mut Vec<u8>) {
+        put_u64(out, self.ino);
+        put_u64(out, self.size);
+        put_u64(out, self.size.div_ceil(512));
+        // atime, mtime, ctime
+        put_u64(out, self.mtime);
+        put_u64(out, self.mtime);
+        put_u64(out, self.mtime);
+        // atimensec, mtimensec, ctimensec
+        put_u32(out, 0);
+        put_u32(out, 0);
+        put_u32(out, 0);
+        put_u32(out, self.mode);
+        put_u32(out, self.nlink);
+        put_u32(out, self.uid);
+        put_u32(out, self.gid);
+        // rdev, blksize, flags
+        put_u32(out, 0);
+        put_u32(out, 4096);
+        put_u32(out, 0);
+    }
+
+    // the d_type of a directory entry
+    pub fn dirent_type(&self) -> u32 {
+        (self.mode >> 12) & 0o17
+    }
+}
+
+pub struct Request {
+    pub opcode: u32,
+    pub unique: u64,
+    pub nodeid: u64,
+    data: Vec<u8>,
+}
+
+impl Request {
+    pub fn u32_at(&self, offset: usize) -> u32 {
+        self.data
+            .get(offset..offset + 4)
+            .map(|bytes| u32::from_ne_bytes(bytes.try_into().unwrap()))
+            .unwrap_or(0)
+    }
+
+    pub fn u64_at(&self, offset: usize) -> u64 {
+        self.data
+            .get(offset..offset + 8)
+            .map(|bytes| u64::from_ne_bytes(bytes.try_into().unwrap()))
+            .unwrap_or(0)
+    }
+
+    // the null terminated name of lookup requests
+    pub fn name(&self) -> &OsStr {
+        let end = self
+            .data
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(self.data.len());
+        OsStr::from_bytes(&self.data[..end])
+    }
+}
+
+// entries of a readdir reply, each padded to 8 bytes
+pub struct DirEntries {
+    data: Vec<u8>,
+    size: usize,
+}
+
+impl DirEntries {
+    pub fn new(size: u32) -> DirEntries {
+        DirEntries {
+            data: Vec::new(),
+            size: size as usize,
+        }
+    }
+
+    // returns false when the entry doesn't fit anymore, offset is where the next
+    // readdir continues
+    pub fn push(&mut self, ino: u64, offset: u64, dirent_type: u32, name: &OsStr) -> bool {
+        let name = name.as_bytes();
+        let entry_size = (24 + name.len()).next_multiple_of(8);
+        if self.data.len() + entry_size > self.size {
+            return false;
+        }
+
+        put_u64(&mut self.data, ino);
+        put_u64(&mut self.data, offset);
+        put_u32(&mut self.data, name.len() as u32);
+        put_u32(&mut self.data, dirent_type);
+        self.data.extend_from_slice(name);
+        self.data
+            .resize(self.data.len() + entry_size - 24 - name.len(), 0);
+        true
+    }
+}
+
+// a mounted file system, requests are read from and replies written to the fuse device.
+// Several threads may wait for requests at the same time, each with its own buffer
+pub struct Session {
+    device: File,
+    mountpoint: PathBuf,
+}
+
+impl Session {
+    // mounts read-only with mount(2), which needs root. bkp doesn't use the setuid
+    // fusermount helper of libfuse which unprivileged mounts go through
+    pub fn mount(mountpoint: &Path) -> Result<Session, io::Error> {
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/fuse")?;
+
+        let options = format!(
+            "fd={},rootmode=40000,user_id={},group_id={},default_permissions",
+            device.as_raw_fd(),
+            unsafe { libc::geteuid() },
+            unsafe { libc::getegid() }
+        );
+        let target = path_to_cstring(mountpoint)?;
+        let options = CString::new(options).unwrap();
+
+        let result = unsafe {
+            libc::mount(
+                c"bkp".as_ptr(),
+                target.as_ptr(),
+                c"fuse.bkp".as_ptr(),
+                libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV,
+                options.as_ptr() as *const libc::c_void,
+            )
+        };
+        if result != 0 {
+            let error = io::Error::last_os_error();
+            return Err(match error.raw_os_error() {
+                Some(libc::EPERM) => io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!(
+                        "Mounting {} needs root, bkp doesn't use fusermount",
+                        mountpoint.display()
+                    ),
+                ),
+                _ => error,
+            });
+        }
+
+        Ok(Session {
+            device,
+            mountpoint: mountpoint.to_path_buf(),
+        })
+    }
+
+    // the next request, None when the file system was unmounted. The buffer must hold
+    // BUFFER_SIZE bytes
+    pub fn next_request(&self, buffer: &mut [u8]) -> Result<Option<Request>, io::Error> {
+        loop {
+            let size = match (&self.device).read(buffer) {
+                Ok(size) => size,
+                // the request was interrupted before it was read
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => continue,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.raw_os_error() == Some(libc::ENODEV) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+
+            if size < IN_HEADER_SIZE {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Short fuse request of {} bytes", size),
+                ));
+            }
+
+            let header = &buffer[..IN_HEADER_SIZE];
+            return Ok(Some(Request {
+                opcode: u32::from_ne_bytes(header[4..8].try_into().unwrap()),
+                unique: u64::from_ne_bytes(header[8..16].try_into().unwrap()),
+                nodeid: u64::from_ne_bytes(header[16..24].try_into().unwrap()),
+                data: buffer[IN_HEADER_SIZE..size].to_vec(),
+            }));
+        }
+    }
+
+    pub fn reply(&self, request: &Request, data: &[u8]) {
+        self.send(request.unique, 0, data);
+    }
+
+    // errno is a positive libc error number
+    pub fn reply_error(&self, request: &Request, errno: i32) {
+        self.send(request.unique, -errno, &[]);
+    }
+
+    pub fn reply_init(&self, request: &Request) {
+        let mut out = Vec::new();
+        put_u32(&mut out, KERNEL_VERSION);
+        put_u32(&mut out, KERNEL_MINOR_VERSION.min(request.u32_at(4)));
+        // max_readahead as offered by the kernel
+        put_u32(&mut out, request.u32_at(8));
+        // flags
+        put_u32(&mut out, 0);
+        // max_background, congestion_threshold
+        put_u16(&mut out, 0);
+        put_u16(&mut out, 0);
+        put_u32(&mut out, MAX_WRITE);
+        // time_gran, max_pages, map_alignment, flags2, unused
+        put_u32(&mut out, 1);
+        put_u16(&mut out, 0);
+        put_u16(&mut out, 0);
+        out.resize(64, 0);
+        self.reply(request, &out);
+    }
+
+    // struct fuse_entry_out, the kernel may cache entries and attributes for ttl seconds
+    pub fn reply_entry(&self, request: &Request, attr: &Attr, ttl: u64) {
+        let mut out = Vec::new();
+        put_u64(&mut out, attr.ino);
+        // generation
+        put_u64(&mut out, 0);
+        put_u64(&mut out, ttl);
+        put_u64(&mut out, ttl);
+        put_u32(&mut out, 0);
+        put_u32(&mut out, 0);
+        attr.write_to(&mut out);
+        self.reply(request, &out);
+    }
+
+    // struct fuse_attr_out
+    pub fn reply_attr(&self, request: &Request, attr: &Attr, ttl: u64) {
+        let mut out = Vec::new();
+        put_u64(&mut out, ttl);
+        put_u32(&mut out, 0);
+        put_u32(&mut out, 0);
+        attr.write_to(&mut out);
+        self.reply(request, &out);
+    }
+
+    // struct fuse_open_out, the kernel may keep cached pages of the file as it never changes
+    pub fn reply_open(&self, request: &Request, fh: u64) {
+        const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+
+        let mut out = Vec::new();
+        put_u64(&mut out, fh);
+        put_u32(&mut out, FOPEN_KEEP_CACHE);
+        put_u32(&mut out, 0);
+        self.reply(request, &out);
+    }
+
+    pub fn reply_dir_entries(&self, request: &Request, entries: &DirEntries) {
+        self.reply(request, &entries.data);
+    }
+
+    // struct fuse_kstatfs of an empty file system
+    pub fn reply_statfs(&self, request: &Request) {
+        let mut out = vec![0; 40];
+        // bsize, namelen, frsize
+        put_u32(&mut out, 4096);
+        put_u32(&mut out, 255);
+        put_u32(&mut out, 4096);
+        out.resize(80, 0);
+        self.reply(request, &out);
+    }
+
+    fn send(&self, unique: u64, error: i32, data: &[u8]) {
+        let mut out = Vec::with_capacity(OUT_HEADER_SIZE + data.len());
+        put_u32(&mut out, (OUT_HEADER_SIZE + data.len()) as u32);
+        out.extend_from_slice(&error.to_ne_bytes());
+        put_u64(&mut out, unique);
+        out.extend_from_slice(data);
+
+        // fails when the request was interrupted meanwhile, nobody waits for the reply then
+        let _ = (&self.device).write_all(&out);
+    }
+
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+}
+
+// lazily detaches the mount, the session sees it as the end of requests
+pub fn unmount(mountpoint: &Path) -> Result<(), io::Error> {
+    let target = path_to_cstring(mountpoint)?;
+    match unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+fn path_to_cstring(path: &Path) -> Result<CString, io::Error> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_ne_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_ne_bytes());
+}
+
+fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_ne_bytes());
+}
@@ -0,0 +1,674 @@
+pub mod fuse;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::{OsStr, OsString},
+    fs::{self, File, OpenOptions},
+    io::{Error, ErrorKind},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    thread,
+};
+
+use log::{error, info};
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
+use tar::EntryType;
+
+use self::fuse::{Attr, DirEntries, Request, Session};
+use crate::{
+    backup::{get_backup_chain, Backup},
+    browse::{cat_archive, find_backups, list_archive, open_archive, EntryInfo, Located, Lookup},
+    globalconfig::GlobalConfig,
+};
+
+// backups never change, the kernel may cache everything for this many seconds
+const TTL: u64 = 3600;
+
+// requests handled at the same time, so reading one archive doesn't block the whole mount
+const MOUNT_THREADS: usize = 4;
+
+#[derive(Debug)]
+enum NodeKind {
+    Dir(BTreeMap<OsString, u64>),
+    // a backup, its files are read from the archives of its chain when it is first entered
+    Snapshot {
+        // newest first, like get_backup_chain
+        chain: Vec<Located>,
+        children: Option<BTreeMap<OsString, u64>>,
+        // held while the archives are read, so they are read once
+        loading: Arc<Mutex<()>>,
+    },
+    // the content is the entry path of an archive of the chain, which differs for hard links
+    File {
+        archive: Located,
+        path: PathBuf,
+    },
+    Symlink(PathBuf),
+    // device nodes and fifos are listed but can't be opened
+    Other,
+}
+
+#[derive(Debug)]
+struct Node {
+    parent: u64,
+    name: OsString,
+    kind: NodeKind,
+    attr: Attr,
+}
+
+// the nodes of the mount, inode n is nodes[n - 1]
+struct Tree {
+    nodes: Vec<Node>,
+}
+
+impl Tree {
+    fn add_node(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        kind: NodeKind,
+        mode: u32,
+        mtime: u64,
+    ) -> u64 {
+        let ino = self.nodes.len() as u64 + 1;
+        let size = match &kind {
+            NodeKind::Symlink(target) => target.as_os_str().len() as u64,
+            _ => 0,
+        };
+        let nlink = match kind {
+            NodeKind::Dir(_) | NodeKind::Snapshot { .. } => 2,
+            _ => 1,
+        };
+
+        self.nodes.push(Node {
+            parent,
+            name: name.to_os_string(),
+            kind,
+            attr: Attr {
+                ino,
+                size,
+                mtime,
+                mode,
+                nlink,
+                uid: unsafe { libc::geteuid() },
+                gid: unsafe { libc::getegid() },
+            },
+        });
+
+        ino
+    }
+
+    fn add_child(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        kind: NodeKind,
+        mode: u32,
+        mtime: u64,
+    ) -> u64 {
+        let ino = self.add_node(parent, name, kind, mode, mtime);
+
+        if let Some(children) = self.children_mut(parent) {
+            children.insert(name.to_os_string(), ino);
+        }
+
+        ino
+    }
+
+    fn node(&self, ino: u64) -> Result<&Node, i32> {
+        self.nodes
+            .get((ino as usize).wrapping_sub(1))
+            .ok_or(libc::ENOENT)
+    }
+
+    // children of a directory, None for a snapshot which wasn't read yet
+    fn children(&self, ino: u64) -> Result<Option<&BTreeMap<OsString, u64>>, i32> {
+        match &self.node(ino)?.kind {
+            NodeKind::Dir(children) => Ok(Some(children)),
+            NodeKind::Snapshot { children, .. } => Ok(children.as_ref()),
+            _ => Err(libc::ENOTDIR),
+        }
+    }
+
+    fn child(&self, parent: u64, name: &OsStr) -> Option<u64> {
+        self.children(parent).ok()??.get(name).copied()
+    }
+
+    fn children_mut(&mut self, ino: u64) -> Option<&mut BTreeMap<OsString, u64>> {
+        match &mut self.nodes.get_mut((ino as usize).wrapping_sub(1))?.kind {
+            NodeKind::Dir(children) => Some(children),
+            NodeKind::Snapshot {
+                children: Some(children),
+                ..
+            } => Some(children),
+            _ => None,
+        }
+    }
+
+    // adds the merged entries of the archives of a snapshot below it
+    fn insert_snapshot(&mut self, ino: u64, entries: BTreeMap<PathBuf, (EntryInfo, NodeKind)>) {
+        if let NodeKind::Snapshot { children, .. } = &mut self.nodes[ino as usize - 1].kind {
+            *children = Some(BTreeMap::new());
+        }
+
+        for (path, (entry, kind)) in entries {
+            let parent = self.make_dirs(ino, path.parent().unwrap_or(Path::new("")));
+            let name = path.file_name().unwrap_or_default();
+            let file_type = match kind {
+                NodeKind::Dir(_) => libc::S_IFDIR,
+                NodeKind::Symlink(_) => libc::S_IFLNK,
+                NodeKind::File { .. } => libc::S_IFREG,
+                _ => match entry.entry_type {
+                    EntryType::Char => libc::S_IFCHR,
+                    EntryType::Block => libc::S_IFBLK,
+                    _ => libc::S_IFIFO,
+                },
+            };
+            let mode = file_type | (entry.mode & 0o7777);
+
+            // directories may already exist as parents of earlier paths
+            let existing = self
+                .children_mut(parent)
+                .and_then(|children| children.get(name).copied());
+            match existing {
+                Some(existing) if file_type == libc::S_IFDIR => {
+                    let attr = &mut self.nodes[existing as usize - 1].attr;
+                    attr.mode = mode;
+                    attr.mtime = entry.mtime;
+                }
+                _ => {
+                    let child = self.add_child(parent, name, kind, mode, entry.mtime);
+                    if file_type == libc::S_IFREG {
+                        self.nodes[child as usize - 1].attr.size = entry.size;
+                    }
+                }
+            }
+        }
+    }
+
+    // the directory at path below the snapshot, created when the archives have no entry
+    // for it
+    fn make_dirs(&mut self, snapshot: u64, path: &Path) -> u64 {
+        let mut ino = snapshot;
+        for name in path.iter() {
+            let existing = self
+                .children_mut(ino)
+                .and_then(|children| children.get(name).copied());
+            ino = match existing {
+                Some(child) => child,
+                None => {
+                    let mtime = self.nodes[ino as usize - 1].attr.mtime;
+                    self.add_child(
+                        ino,
+                        name,
+                        NodeKind::Dir(BTreeMap::new()),
+                        libc::S_IFDIR | 0o755,
+                        mtime,
+                    )
+                }
+            };
+        }
+        ino
+    }
+
+    fn path(&self, mut ino: u64) -> PathBuf {
+        let mut names: Vec<&OsStr> = Vec::new();
+        while ino != fuse::ROOT_ID {
+            let Ok(node) = self.node(ino) else {
+                break;
+            };
+            names.push(&node.name);
+            ino = node.parent;
+        }
+
+        let mut path = PathBuf::from("/");
+        path.extend(names.iter().rev());
+        path
+    }
+}
+
+// the unpacked copy of an archive entry, None until it was unpacked
+type Unpacked = Mutex<Option<Arc<File>>>;
+
+// /<app>/<server>/<backup time>/<files of the backup>, each backup showing the merged files of its
+// full and incremental backups. Errors are libc error numbers, as the kernel gets them
+pub struct BackupFs<'a> {
+    global_config: &'a GlobalConfig,
+    tree: RwLock<Tree>,
+    open_files: Mutex<HashMap<u64, Arc<File>>>,
+    // unpacked copies of archive entries by backup and entry path, shared by every open of
+    // the entry and kept until unmount
+    unpacked: Mutex<HashMap<(String, PathBuf), Arc<Unpacked>>>,
+    next_fh: AtomicU64,
+}
+
+impl BackupFs<'_> {
+    pub fn new(global_config: &GlobalConfig) -> BackupFs<'_> {
+        let mut tree = Tree { nodes: Vec::new() };
+        tree.add_node(
+            fuse::ROOT_ID,
+            OsStr::new(""),
+            NodeKind::Dir(BTreeMap::new()),
+            libc::S_IFDIR | 0o555,
+            0,
+        );
+
+        // servers backing up the same app to a shared bucket have chains of their own
+        let mut servers: BTreeMap<(String, String), Vec<Located>> = BTreeMap::new();
+        for located in find_backups(global_config) {
+            let key = (
+                located.backup.app_name.clone(),
+                located.backup.server_name.clone(),
+            );
+            servers.entry(key).or_default().push(located);
+        }
+
+        for ((app_name, server_name), backups) in servers {
+            let app = match tree.child(fuse::ROOT_ID, OsStr::new(&app_name)) {
+                Some(app) => app,
+                None => tree.add_child(
+                    fuse::ROOT_ID,
+                    OsStr::new(&app_name),
+                    NodeKind::Dir(BTreeMap::new()),
+                    libc::S_IFDIR | 0o555,
+                    0,
+                ),
+            };
+            let server = tree.add_child(
+                app,
+                OsStr::new(&server_name),
+                NodeKind::Dir(BTreeMap::new()),
+                libc::S_IFDIR | 0o555,
+                0,
+            );
+
+            let chain_backups = backups
+                .iter()
+                .map(|located| located.backup.clone())
+                .collect::<Vec<Backup>>();
+
+            for located in &backups {
+                let chain = get_backup_chain(&chain_backups, &located.backup.file_name)
+                    .into_iter()
+                    .map(|backup| {
+                        backups
+                            .iter()
+                            .find(|located| located.backup.file_name == backup.file_name)
+                            .unwrap()
+                            .clone()
+                    })
+                    .collect::<Vec<Located>>();
+
+                // backups of the same second are told apart by their full time
+                let mut name = located.backup.time.format("%Y-%m-%dT%H:%M:%SZ").to_string();
+                if tree.child(server, OsStr::new(&name)).is_some() {
+                    name = located
+                        .backup
+                        .time
+                        .format("%Y-%m-%dT%H:%M:%S%.fZ")
+                        .to_string();
+                }
+                tree.add_child(
+                    server,
+                    OsStr::new(&name),
+                    NodeKind::Snapshot {
+                        chain,
+                        children: None,
+                        loading: Arc::new(Mutex::new(())),
+                    },
+                    libc::S_IFDIR | 0o555,
+                    located.backup.time.timestamp() as u64,
+                );
+            }
+        }
+
+        BackupFs {
+            global_config,
+            tree: RwLock::new(tree),
+            open_files: Mutex::new(HashMap::new()),
+            unpacked: Mutex::new(HashMap::new()),
+            next_fh: AtomicU64::new(1),
+        }
+    }
+
+    pub fn attr(&self, ino: u64) -> Result<Attr, i32> {
+        Ok(self.tree.read().unwrap().node(ino)?.attr.clone())
+    }
+
+    pub fn lookup(&self, parent: u64, name: &OsStr) -> Result<Attr, i32> {
+        self.load(parent)?;
+
+        let tree = self.tree.read().unwrap();
+        let child = *tree
+            .children(parent)?
+            .ok_or(libc::EIO)?
+            .get(name)
+            .ok_or(libc::ENOENT)?;
+        Ok(tree.node(child)?.attr.clone())
+    }
+
+    // the children of a directory by name, without . and ..
+    pub fn read_dir(&self, ino: u64) -> Result<Vec<(OsString, Attr)>, i32> {
+        self.load(ino)?;
+
+        let tree = self.tree.read().unwrap();
+        tree.children(ino)?
+            .ok_or(libc::EIO)?
+            .iter()
+            .map(|(name, child)| Ok((name.clone(), tree.node(*child)?.attr.clone())))
+            .collect()
+    }
+
+    pub fn read_link(&self, ino: u64) -> Result<PathBuf, i32> {
+        match &self.tree.read().unwrap().node(ino)?.kind {
+            NodeKind::Symlink(target) => Ok(target.clone()),
+            _ => Err(libc::EINVAL),
+        }
+    }
+
+    // the content is unpacked into an unlinked temporary file on the first open of the
+    // entry, later opens read the same file
+    pub fn open(&self, ino: u64) -> Result<u64, i32> {
+        let (archive, path) = match &self.tree.read().unwrap().node(ino)?.kind {
+            NodeKind::File { archive, path } => (archive.clone(), path.clone()),
+            NodeKind::Dir(_) | NodeKind::Snapshot { .. } => return Err(libc::EISDIR),
+            _ => return Err(libc::ENXIO),
+        };
+
+        let fh = self.next_fh.fetch_add(1, Ordering::Relaxed);
+        match self.unpacked(&archive, &path, fh) {
+            Ok(file) => {
+                self.open_files.lock().unwrap().insert(fh, file);
+                Ok(fh)
+            }
+            Err(e) => {
+                error!(
+                    "Error reading {} from {}: {}",
+                    path.display(),
+                    archive.backup.file_name,
+                    e
+                );
+                Err(libc::EIO)
+            }
+        }
+    }
+
+    pub fn read(&self, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>, i32> {
+        let file = self
+            .open_files
+            .lock()
+            .unwrap()
+            .get(&fh)
+            .cloned()
+            .ok_or(libc::EBADF)?;
+        let mut data = vec![0; size as usize];
+
+        let mut size = 0;
+        while size < data.len() {
+            match file.read_at(&mut data[size..], offset + size as u64) {
+                Ok(0) => break,
+                Ok(read) => size += read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.raw_os_error().unwrap_or(libc::EIO)),
+            }
+        }
+
+        data.truncate(size);
+        Ok(data)
+    }
+
+    pub fn release(&self, fh: u64) {
+        self.open_files.lock().unwrap().remove(&fh);
+    }
+
+    // reads the archives of a snapshot the first time it is entered, requests for other
+    // nodes go on meanwhile
+    fn load(&self, ino: u64) -> Result<(), i32> {
+        let (chain, loading) = match &self.tree.read().unwrap().node(ino)?.kind {
+            NodeKind::Snapshot {
+                chain,
+                children: None,
+                loading,
+            } => (chain.clone(), loading.clone()),
+            NodeKind::Dir(_) | NodeKind::Snapshot { .. } => return Ok(()),
+            _ => return Err(libc::ENOTDIR),
+        };
+
+        let _loading = loading.lock().unwrap();
+        // another request read the archives while this one waited
+        if self.tree.read().unwrap().children(ino)?.is_some() {
+            return Ok(());
+        }
+
+        let path = self.tree.read().unwrap().path(ino);
+        info!("Reading {} backups of {}", chain.len(), path.display());
+        match self.read_snapshot(&chain) {
+            Ok(entries) => {
+                self.tree.write().unwrap().insert_snapshot(ino, entries);
+                Ok(())
+            }
+            Err(e) => {
+                error!("Error reading backups of {}: {}", path.display(), e);
+                Err(libc::EIO)
+            }
+        }
+    }
+
+    // the entries of the chain, entries of newer archives replace those of older ones
+    fn read_snapshot(
+        &self,
+        chain: &[Located],
+    ) -> Result<BTreeMap<PathBuf, (EntryInfo, NodeKind)>, Error> {
+        let mut entries: BTreeMap<PathBuf, (EntryInfo, NodeKind)> = BTreeMap::new();
+        for located in chain.iter().rev() {
+            let mut archive_entries: BTreeMap<PathBuf, EntryInfo> = BTreeMap::new();
+            let mut archive = open_archive(self.global_config, &located.backup, located.remote)?;
+            list_archive(&mut archive, Path::new(""), &mut archive_entries)?;
+
+            for (path, entry) in &archive_entries {
+                let (entry, kind) = match (entry.entry_type, &entry.link_name) {
+                    (EntryType::Directory, _) => (entry.clone(), NodeKind::Dir(BTreeMap::new())),
+                    (EntryType::Symlink, Some(target)) => {
+                        (entry.clone(), NodeKind::Symlink(target.clone()))
+                    }
+                    // the content of hard links is stored with their target
+                    (EntryType::Link, Some(target)) => match archive_entries.get(target) {
+                        Some(target_entry) => (
+                            EntryInfo {
+                                path: path.clone(),
+                                ..target_entry.clone()
+                            },
+                            NodeKind::File {
+                                archive: located.clone(),
+                                path: target.clone(),
+                            },
+                        ),
+                        None => continue,
+                    },
+                    (entry_type, _) if entry_type.is_file() => (
+                        entry.clone(),
+                        NodeKind::File {
+                            archive: located.clone(),
+                            path: path.clone(),
+                        },
+                    ),
+                    _ => (entry.clone(), NodeKind::Other),
+                };
+                entries.insert(path.clone(), (entry, kind));
+            }
+        }
+
+        Ok(entries)
+    }
+
+    // opens of the same entry wait for the one unpacking it, other entries go on meanwhile
+    fn unpacked(&self, archive: &Located, path: &Path, fh: u64) -> Result<Arc<File>, Error> {
+        let unpacked = self
+            .unpacked
+            .lock()
+            .unwrap()
+            .entry((archive.backup.file_name.clone(), path.to_path_buf()))
+            .or_default()
+            .clone();
+
+        let mut unpacked = unpacked.lock().unwrap();
+        if let Some(file) = unpacked.as_ref() {
+            return Ok(file.clone());
+        }
+        let file = Arc::new(self.unpack(archive, path, fh)?);
+        *unpacked = Some(file.clone());
+        Ok(file)
+    }
+
+    fn unpack(&self, archive: &Located, path: &Path, fh: u64) -> Result<File, Error> {
+        let temp_path = std::env::temp_dir().join(format!("bkp-mount-{}-{}", process::id(), fh));
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)?;
+        fs::remove_file(&temp_path)?;
+
+        let mut tar_archive = open_archive(self.global_config, &archive.backup, archive.remote)?;
+        match cat_archive(&mut tar_archive, path, &mut file)? {
+            Lookup::Found => Ok(file),
+            _ => Err(Error::new(ErrorKind::NotFound, "entry not found")),
+        }
+    }
+
+    fn handle(&self, session: &Session, request: &Request) {
+        let result = match request.opcode {
+            fuse::INIT => {
+                session.reply_init(request);
+                Ok(())
+            }
+            fuse::LOOKUP => self
+                .lookup(request.nodeid, request.name())
+                .map(|attr| session.reply_entry(request, &attr, TTL)),
+            fuse::GETATTR => self
+                .attr(request.nodeid)
+                .map(|attr| session.reply_attr(request, &attr, TTL)),
+            fuse::READLINK => self
+                .read_link(request.nodeid)
+                .map(|target| session.reply(request, target.as_os_str().as_encoded_bytes())),
+            fuse::OPENDIR => self
+                .load(request.nodeid)
+                .map(|_| session.reply_open(request, 0)),
+            fuse::READDIR => self.readdir(session, request),
+            fuse::OPEN => self
+                .open(request.nodeid)
+                .map(|fh| session.reply_open(request, fh)),
+            fuse::READ => self
+                .read(request.u64_at(0), request.u64_at(8), request.u32_at(16))
+                .map(|data| session.reply(request, &data)),
+            fuse::RELEASE => {
+                self.release(request.u64_at(0));
+                session.reply(request, &[]);
+                Ok(())
+            }
+            fuse::RELEASEDIR | fuse::FLUSH => {
+                session.reply(request, &[]);
+                Ok(())
+            }
+            fuse::STATFS => {
+                session.reply_statfs(request);
+                Ok(())
+            }
+            // nodes are kept for the whole mount, these get no reply
+            fuse::FORGET | fuse::BATCH_FORGET | fuse::INTERRUPT => Ok(()),
+            fuse::DESTROY => {
+                session.reply(request, &[]);
+                Ok(())
+            }
+            _ => Err(libc::ENOSYS),
+        };
+
+        if let Err(errno) = result {
+            session.reply_error(request, errno);
+        }
+    }
+
+    fn readdir(&self, session: &Session, request: &Request) -> Result<(), i32> {
+        let offset = request.u64_at(8) as usize;
+        let mut entries = DirEntries::new(request.u32_at(16));
+
+        let node = self.attr(request.nodeid)?;
+        let parent = self.tree.read().unwrap().node(request.nodeid)?.parent;
+        let mut listing: Vec<(OsString, u64, u32)> = vec![
+            (OsString::from("."), request.nodeid, node.dirent_type()),
+            (
+                OsString::from(".."),
+                parent,
+                self.attr(parent)?.dirent_type(),
+            ),
+        ];
+        listing.extend(
+            self.read_dir(request.nodeid)?
+                .into_iter()
+                .map(|(name, attr)| (name, attr.ino, attr.dirent_type())),
+        );
+
+        for (index, (name, ino, dirent_type)) in listing.iter().enumerate().skip(offset) {
+            if !entries.push(*ino, index as u64 + 1, *dirent_type, name) {
+                break;
+            }
+        }
+
+        session.reply_dir_entries(request, &entries);
+        Ok(())
+    }
+}
+
+// answers requests until the file system is unmounted, a failing device unmounts it so
+// the other threads stop as well
+fn serve(backup_fs: &BackupFs, session: &Session) -> Result<(), Error> {
+    let mut buffer = vec![0; fuse::BUFFER_SIZE];
+    loop {
+        match session.next_request(&mut buffer) {
+            Ok(Some(request)) => backup_fs.handle(session, &request),
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                let _ = fuse::unmount(session.mountpoint());
+                return Err(e);
+            }
+        }
+    }
+}
+
+// serves the backups read-only at mountpoint until it is unmounted or bkp is stopped
+pub fn mount(global_config: &GlobalConfig, mountpoint: &Path) -> Result<(), Error> {
+    let backup_fs = BackupFs::new(global_config);
+    let session = Session::mount(mountpoint)?;
+    info!("Mounted backups at {}", mountpoint.display());
+
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    let signal_mountpoint = mountpoint.to_path_buf();
+    thread::spawn(move || {
+        if signals.forever().next().is_some() {
+            info!("Unmounting {}", signal_mountpoint.display());
+            if let Err(e) = fuse::unmount(&signal_mountpoint) {
+                error!("Error unmounting {}: {}", signal_mountpoint.display(), e);
+            }
+        }
+    });
+
+    thread::scope(|scope| {
+        let workers = (0..MOUNT_THREADS)
+            .map(|_| scope.spawn(|| serve(&backup_fs, &session)))
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().unwrap())
+    })?;
+
+    info!("Unmounted {}", session.mountpoint().display());
+    Ok(())
+}
@@ -0,0 +1,327 @@
+use std::{
+    fmt, fs,
+    io::{Error, ErrorKind, Write},
+    panic,
+    path::Path,
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{error, info, warn};
+use toml::Value;
+
+use crate::{
+    actions::restore_remote_chain,
+    backup::{get_backup_chain, Backup},
+    config::{get_all_configs, get_config_table, parse_config_with_defaults, Config},
+    extract::RestoreOptions,
+    globalconfig::GlobalConfig,
+    storage::s3::{get_all_remote_backups, get_remote_object, put_remote_object},
+};
+
+// every backup stores the config of its app as configs/<server_name>/<app_name>.toml in
+// the bucket, so a new server can be set up from remote storage alone
+const CONFIG_SNAPSHOT_PREFIX: &str = "configs/";
+
+// the backup recover restores of each app
+#[derive(Debug, Clone, Default)]
+pub enum RecoveryPoint {
+    #[default]
+    Latest,
+    // a backup by name, with or without the .tar.gz extension
+    Backup(String),
+    // the latest backup made before this time
+    Before(DateTime<Utc>),
+}
+
+impl fmt::Display for RecoveryPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecoveryPoint::Latest => write!(f, "latest"),
+            RecoveryPoint::Backup(name) => write!(f, "named {}", name),
+            RecoveryPoint::Before(time) => write!(
+                f,
+                "before {}",
+                time.to_rfc3339_opts(SecondsFormat::Secs, true)
+            ),
+        }
+    }
+}
+
+// an app found in remote storage, with its backups newest first
+pub struct RecoverableApp {
+    pub app_name: String,
+    pub backups: Vec<Backup>,
+    pub config_snapshot: Option<String>,
+}
+
+impl RecoverableApp {
+    pub fn backup(&self, point: &RecoveryPoint) -> Option<&Backup> {
+        match point {
+            RecoveryPoint::Latest => self.backups.first(),
+            RecoveryPoint::Backup(name) => {
+                let name = name.strip_suffix(".tar.gz").unwrap_or(name);
+                self.backups.iter().find(|backup| backup.file_name == name)
+            }
+            RecoveryPoint::Before(time) => self.backups.iter().find(|backup| backup.time < *time),
+        }
+    }
+
+    // the backups restored by recover: the backup at point and its chain, newest first
+    pub fn chain(&self, point: &RecoveryPoint) -> Vec<Backup> {
+        match self.backup(point) {
+            Some(backup) => get_backup_chain(&self.backups, &backup.file_name),
+            None => Vec::new(),
+        }
+    }
+}
+
+// the config of an app as uploaded next to its backups. The server name is written out,
+// the recovered server keeps backing up under the name of the one it replaces.
+pub fn config_snapshot(global_config: &GlobalConfig, config: &Config) -> Option<String> {
+    let mut table = get_config_table(global_config, &config.app_name)?;
+    table.insert(
+        "server_name".to_string(),
+        Value::String(config.server_name.clone()),
+    );
+
+    match toml::to_string(&Value::Table(table)) {
+        Ok(snapshot) => Some(snapshot),
+        Err(e) => {
+            error!("Error serializing the config of {}: {}", config.app_name, e);
+            None
+        }
+    }
+}
+
+pub fn upload_config_snapshot(global_config: &GlobalConfig, config: &Config) {
+    let snapshot = match config_snapshot(global_config, config) {
+        Some(snapshot) => snapshot,
+        None => return,
+    };
+
+    let key = config_snapshot_key(&config.server_name, &config.app_name);
+    if panic::catch_unwind(|| put_remote_object(global_config, &key, &snapshot)).is_err() {
+        error!("Error uploading the config of {}", config.app_name);
+    }
+}
+
+// writes a config snapshot to the config files of this server, unless the app already has
+// a config, which is kept
+pub fn install_config_snapshot(
+    global_config: &GlobalConfig,
+    snapshot: &str,
+) -> Result<Config, Error> {
+    // validated like in a dry run of recover
+    let config = parse_config_with_defaults(snapshot, &global_config.defaults)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+    if let Some(existing) = find_config(global_config, &config.app_name) {
+        info!("Keeping the existing config of {}", config.app_name);
+        return Ok(existing);
+    }
+
+    let config_files_location = Path::new(&global_config.config_files_location);
+    let path = config_files_location.join(format!("{}.toml", config.app_name));
+    if path.exists() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!(
+                "{} exists but doesn't configure {}",
+                path.display(),
+                config.app_name
+            ),
+        ));
+    }
+
+    fs::create_dir_all(config_files_location)?;
+    fs::write(&path, snapshot)?;
+    info!(
+        "Wrote the config of {} to {}",
+        config.app_name,
+        path.display()
+    );
+
+    // read back so the defaults of this server apply like to every other config
+    find_config(global_config, &config.app_name).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid config snapshot of {}", config.app_name),
+        )
+    })
+}
+
+// the apps backed up by server_name, by app name
+pub fn find_recoverable_apps(
+    global_config: &GlobalConfig,
+    server_name: &str,
+) -> Result<Vec<RecoverableApp>, Error> {
+    // the s3 client panics when the remote storage is unreachable
+    let mut backups = panic::catch_unwind(|| get_all_remote_backups(global_config))
+        .map_err(|_| Error::other("Remote storage is unavailable"))?;
+    backups.retain(|backup| backup.server_name == server_name);
+    backups.reverse();
+
+    let mut apps: Vec<RecoverableApp> = Vec::new();
+    for backup in backups {
+        match apps.iter_mut().find(|app| app.app_name == backup.app_name) {
+            Some(app) => app.backups.push(backup),
+            None => apps.push(RecoverableApp {
+                app_name: backup.app_name.clone(),
+                backups: vec![backup],
+                config_snapshot: None,
+            }),
+        }
+    }
+    apps.sort_by(|a, b| a.app_name.cmp(&b.app_name));
+
+    for app in &mut apps {
+        let key = config_snapshot_key(server_name, &app.app_name);
+        app.config_snapshot = match panic::catch_unwind(|| get_remote_object(global_config, &key)) {
+            Ok(Ok(snapshot)) => snapshot,
+            Ok(Err(e)) => {
+                warn!("Unable to read config snapshot {}: {}", key, e);
+                None
+            }
+            Err(_) => None,
+        };
+    }
+
+    Ok(apps)
+}
+
+// lists the apps server_name backed up, and restores the selected ones from their backup
+// at point, with the config they were backed up with unless they are configured here
+#[allow(clippy::too_many_arguments)]
+pub fn recover(
+    global_config: &GlobalConfig,
+    server_name: &str,
+    app_names: &[String],
+    all: bool,
+    point: &RecoveryPoint,
+    options: &RestoreOptions,
+    wait: bool,
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    let apps = find_recoverable_apps(global_config, server_name)?;
+    if apps.is_empty() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("No backups of {} in remote storage", server_name),
+        ));
+    }
+
+    for app in &apps {
+        let config = match app.config_snapshot {
+            Some(_) => "config snapshot",
+            None => "no config snapshot",
+        };
+        match app.backup(point) {
+            Some(backup) => writeln!(
+                out,
+                "{}: {} backups, {}: {} (chain of {}), {}",
+                app.app_name,
+                app.backups.len(),
+                point,
+                backup.file_name,
+                app.chain(point).len(),
+                config
+            )?,
+            None => writeln!(
+                out,
+                "{}: {} backups, none {}, {}",
+                app.app_name,
+                app.backups.len(),
+                point,
+                config
+            )?,
+        }
+    }
+
+    if let Some(unknown) = app_names
+        .iter()
+        .find(|name| apps.iter().all(|app| &app.app_name != *name))
+    {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("No backups of {} from {}", unknown, server_name),
+        ));
+    }
+
+    let selected = apps
+        .iter()
+        .filter(|app| all || app_names.contains(&app.app_name))
+        .collect::<Vec<&RecoverableApp>>();
+    if selected.is_empty() {
+        info!("Select apps to recover with --app or --all");
+        return Ok(());
+    }
+
+    let mut failed = 0;
+    for app in selected {
+        info!("Recovering {}", app.app_name);
+        if let Err(e) = recover_app(global_config, app, point, options, wait) {
+            error!("Recovering {} failed: {}", app.app_name, e);
+            failed += 1;
+        }
+    }
+
+    match failed {
+        0 => Ok(()),
+        _ => Err(Error::other(format!("Recovering {} apps failed", failed))),
+    }
+}
+
+fn recover_app(
+    global_config: &GlobalConfig,
+    app: &RecoverableApp,
+    point: &RecoveryPoint,
+    options: &RestoreOptions,
+    wait: bool,
+) -> Result<(), Error> {
+    let chain = app.chain(point);
+    if chain.is_empty() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("No backup of {} {}", app.app_name, point),
+        ));
+    }
+
+    let config = match (
+        find_config(global_config, &app.app_name),
+        &app.config_snapshot,
+    ) {
+        (Some(config), _) => config,
+        // a dry run doesn't touch the config files either
+        (None, Some(snapshot)) if options.dry_run => {
+            parse_config_with_defaults(snapshot, &global_config.defaults)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?
+        }
+        (None, Some(snapshot)) => install_config_snapshot(global_config, snapshot)?,
+        (None, None) => {
+            warn!("Backups made before config snapshots have none");
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "No config snapshot of {}, add its config first",
+                    app.app_name
+                ),
+            ));
+        }
+    };
+
+    // the chain listed above, restoring by name would list remote storage again
+    restore_remote_chain(global_config, &config, &chain, options, wait)
+}
+
+fn find_config(global_config: &GlobalConfig, app_name: &str) -> Option<Config> {
+    get_all_configs(global_config)
+        .into_iter()
+        .find(|config| config.app_name == app_name)
+}
+
+fn config_snapshot_key(server_name: &str, app_name: &str) -> String {
+    format!(
+        "{}{}/{}.toml",
+        CONFIG_SNAPSHOT_PREFIX, server_name, app_name
+    )
+}
@@ -1,27 +1,399 @@
-use std::process::exit;
+use std::{
+    fmt,
+    io::{BufRead, BufReader, Error, ErrorKind, Read},
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    thread::{self, Scope},
+    time::{Duration, Instant},
+};
 
-use log::{error, info};
+use log::{debug, error, info, warn};
+use serde::Deserialize;
 
-pub fn run_script(script: &str) -> () {
-    if script == "" {
-        error!("No script to run");
-        return;
+use crate::{backup::BackupType, config::Config};
+
+// how often a running script is checked for having exited or timed out
+const SCRIPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// how long the output of an exited script is read, background processes may hold the pipes
+const SCRIPT_OUTPUT_DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+// a hook, in toml either a command string run through the shell, an argv array run without
+// a shell, or a table { command = '...' } / { argv = [...] } which can also set timeout
+// (seconds), working_dir and shell for this hook only
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum ScriptSource {
+    Command(String),
+    Argv(Vec<String>),
+    Table {
+        command: Option<String>,
+        argv: Option<Vec<String>>,
+        timeout: Option<u64>,
+        working_dir: Option<String>,
+        shell: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptCommand {
+    Shell(String),
+    Argv(Vec<String>),
+}
+
+impl Default for ScriptCommand {
+    fn default() -> ScriptCommand {
+        ScriptCommand::Shell(String::new())
+    }
+}
+
+// settings which are not set on the hook itself fall back to the script_* fields of the app config
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(try_from = "ScriptSource")]
+pub struct Script {
+    pub command: ScriptCommand,
+    pub timeout: Option<u64>,
+    pub working_dir: Option<String>,
+    pub shell: Option<String>,
+}
+
+impl Script {
+    // an empty script means the hook is not configured
+    pub fn is_empty(&self) -> bool {
+        match &self.command {
+            ScriptCommand::Shell(command) => command.trim().is_empty(),
+            ScriptCommand::Argv(argv) => argv.is_empty(),
+        }
+    }
+
+    // the executable which is run, for shell commands the first word
+    pub fn program(&self) -> Option<&str> {
+        match &self.command {
+            ScriptCommand::Shell(command) => command.split_whitespace().next(),
+            ScriptCommand::Argv(argv) => argv.first().map(|program| program.as_str()),
+        }
+    }
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.command {
+            ScriptCommand::Shell(command) => write!(f, "{}", command),
+            ScriptCommand::Argv(argv) => write!(f, "{:?}", argv),
+        }
+    }
+}
+
+impl TryFrom<ScriptSource> for Script {
+    type Error = String;
+
+    fn try_from(source: ScriptSource) -> Result<Script, String> {
+        let script = match source {
+            ScriptSource::Command(command) => Script {
+                command: ScriptCommand::Shell(command),
+                ..Default::default()
+            },
+            ScriptSource::Argv(argv) => Script {
+                command: ScriptCommand::Argv(argv),
+                ..Default::default()
+            },
+            ScriptSource::Table {
+                command,
+                argv,
+                timeout,
+                working_dir,
+                shell,
+            } => {
+                let command = match (command, argv) {
+                    (Some(command), None) => ScriptCommand::Shell(command),
+                    (None, Some(argv)) => ScriptCommand::Argv(argv),
+                    _ => return Err("a script needs exactly one of command or argv".to_string()),
+                };
+
+                Script {
+                    command,
+                    timeout,
+                    working_dir,
+                    shell,
+                }
+            }
+        };
+
+        Ok(script)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptPhase {
+    PreBackup,
+    PostBackup,
+    PreRestore,
+    PostRestore,
+    OnFailure,
+    Finally,
+}
+
+impl ScriptPhase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScriptPhase::PreBackup => "pre_backup",
+            ScriptPhase::PostBackup => "post_backup",
+            ScriptPhase::PreRestore => "pre_restore",
+            ScriptPhase::PostRestore => "post_restore",
+            ScriptPhase::OnFailure => "on_failure",
+            ScriptPhase::Finally => "finally",
+        }
+    }
+}
+
+// context passed to hook scripts as BKP_* env vars, so one script can handle several
+// apps and phases. result is only set for post scripts.
+#[derive(Debug, Clone)]
+pub struct ScriptContext {
+    pub app_name: String,
+    pub server_name: String,
+    pub app_root: String,
+    pub phase: ScriptPhase,
+    pub backup_type: Option<BackupType>,
+    pub backup_name: Option<String>,
+    pub backup_path: Option<PathBuf>,
+    pub result: Option<Result<(), String>>,
+}
+
+impl ScriptContext {
+    pub fn new(config: &Config, phase: ScriptPhase) -> ScriptContext {
+        ScriptContext {
+            app_name: config.app_name.clone(),
+            server_name: config.server_name.clone(),
+            app_root: config.app_root.clone(),
+            phase,
+            backup_type: None,
+            backup_name: None,
+            backup_path: None,
+            result: None,
+        }
     }
 
-    let output = std::process::Command::new("sh")
-        .arg("-c")
-        .arg(script)
-        .output()
-        .expect("failed to execute process");
+    pub fn env_vars(&self) -> Vec<(&'static str, String)> {
+        let mut env_vars = vec![
+            ("BKP_APP_NAME", self.app_name.clone()),
+            ("BKP_SERVER_NAME", self.server_name.clone()),
+            ("BKP_APP_ROOT", self.app_root.clone()),
+            ("BKP_PHASE", self.phase.as_str().to_string()),
+        ];
 
-    match output.status.success() {
-        true => {
+        if let Some(backup_type) = &self.backup_type {
+            env_vars.push(("BKP_BACKUP_TYPE", backup_type.as_str().to_string()));
+        }
+        if let Some(backup_name) = &self.backup_name {
+            env_vars.push(("BKP_BACKUP_NAME", backup_name.clone()));
+        }
+        if let Some(backup_path) = &self.backup_path {
+            env_vars.push(("BKP_BACKUP_PATH", backup_path.display().to_string()));
+        }
+
+        match &self.result {
+            Some(Ok(())) => {
+                env_vars.push(("BKP_STATUS", "success".to_string()));
+                env_vars.push(("BKP_ERROR", String::new()));
+            }
+            Some(Err(e)) => {
+                env_vars.push(("BKP_STATUS", "failure".to_string()));
+                env_vars.push(("BKP_ERROR", e.clone()));
+            }
+            None => {}
+        }
+
+        env_vars
+    }
+}
+
+// runs a hook with the context as env vars. stdout and stderr are logged line by line while
+// the script runs, a script running longer than its timeout is killed with its children.
+// A failing script is returned as an error, the caller decides whether the run can continue.
+pub fn run_script(config: &Config, script: &Script, context: &ScriptContext) -> Result<(), Error> {
+    if script.is_empty() {
+        debug!("No script to run");
+        return Ok(());
+    }
+
+    let mut command = match &script.command {
+        ScriptCommand::Shell(shell_command) => {
+            let shell = script
+                .shell
+                .as_deref()
+                .unwrap_or(config.script_shell.as_str());
+            let mut command = Command::new(shell);
+            command.arg("-c").arg(shell_command);
+            command
+        }
+        ScriptCommand::Argv(argv) => {
+            let mut command = Command::new(&argv[0]);
+            command.args(&argv[1..]);
+            command
+        }
+    };
+
+    // relative working directories are relative to app_root
+    let working_dir = script
+        .working_dir
+        .as_ref()
+        .or(config.script_working_dir.as_ref());
+    if let Some(working_dir) = working_dir {
+        command.current_dir(Path::new(&config.app_root).join(working_dir));
+    }
+
+    command
+        .envs(context.env_vars())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    // its own process group, so a timeout also kills what the script started
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+
+    let mut child = command.spawn()?;
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+
+    let timeout = script.timeout.or(config.script_timeout);
+
+    // the loggers are not joined, a process the script started in the background can keep
+    // the pipes open long after the script exited
+    let (done, output_done) = mpsc::channel();
+    spawn_detached_output_logger(stdout, false, done.clone());
+    spawn_detached_output_logger(stderr, true, done);
+
+    let status = wait_with_timeout(&mut child, timeout)?;
+    wait_for_output(&output_done, script);
+
+    match status {
+        Some(status) if status.success() => {
             info!("Script {} ran successfully", script);
+            Ok(())
+        }
+        Some(status) => {
+            error!("Script {} failed with {}", script, status);
+            Err(Error::other(format!(
+                "{} script failed with {}",
+                context.phase.as_str(),
+                status
+            )))
         }
-        false => {
-            error!("Script {} failed", script);
-            error!("{}", String::from_utf8_lossy(&output.stderr));
-            exit(1);
+        None => {
+            error!(
+                "Script {} timed out after {}s and was killed",
+                script,
+                timeout.unwrap_or_default()
+            );
+            Err(Error::new(
+                ErrorKind::TimedOut,
+                format!(
+                    "{} script timed out after {}s",
+                    context.phase.as_str(),
+                    timeout.unwrap_or_default()
+                ),
+            ))
         }
     }
 }
+
+// logs the output of a child process line by line, stderr as warnings. The thread is named
+// like the current thread, so the lines are labelled with the app.
+pub(crate) fn spawn_output_logger<'scope, R: Read + Send + 'scope>(
+    scope: &'scope Scope<'scope, '_>,
+    output: R,
+    prefix: &'static str,
+    is_stderr: bool,
+) {
+    let thread_name = thread::current().name().unwrap_or(prefix).to_string();
+    thread::Builder::new()
+        .name(thread_name)
+        .spawn_scoped(scope, move || log_output(output, prefix, is_stderr))
+        .expect("failed to spawn output thread");
+}
+
+fn spawn_detached_output_logger<R: Read + Send + 'static>(
+    output: R,
+    is_stderr: bool,
+    done: mpsc::Sender<()>,
+) {
+    let thread_name = thread::current().name().unwrap_or("script").to_string();
+    thread::Builder::new()
+        .name(thread_name)
+        .spawn(move || {
+            log_output(output, "script", is_stderr);
+            let _ = done.send(());
+        })
+        .expect("failed to spawn output thread");
+}
+
+// after the script exited its output is read to the end, unless something else still holds
+// the pipes
+fn wait_for_output(output_done: &Receiver<()>, script: &Script) {
+    let deadline = Instant::now() + SCRIPT_OUTPUT_DRAIN_TIMEOUT;
+
+    for _ in 0..2 {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match output_done.recv_timeout(remaining) {
+            Ok(()) => {}
+            Err(RecvTimeoutError::Timeout) => {
+                warn!(
+                    "Script {} left processes running which hold its output, not waiting for them",
+                    script
+                );
+                return;
+            }
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+fn log_output<R: Read>(output: R, prefix: &str, is_stderr: bool) {
+    for line in BufReader::new(output).lines() {
+        match line {
+            Ok(line) if is_stderr => warn!("{}: {}", prefix, line),
+            Ok(line) => info!("{}: {}", prefix, line),
+            Err(e) => {
+                error!("Unable to read {} output: {}", prefix, e);
+                break;
+            }
+        }
+    }
+}
+
+// None if the script timed out and was killed
+fn wait_with_timeout(child: &mut Child, timeout: Option<u64>) -> Result<Option<ExitStatus>, Error> {
+    let deadline = timeout.map(|timeout| Instant::now() + Duration::from_secs(timeout));
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            kill_script(child);
+            child.wait()?;
+            return Ok(None);
+        }
+
+        thread::sleep(SCRIPT_POLL_INTERVAL);
+    }
+}
+
+#[cfg(unix)]
+fn kill_script(child: &mut Child) {
+    // the script is the leader of its process group, see run_script
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_script(child: &mut Child) {
+    let _ = child.kill();
+}
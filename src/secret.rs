@@ -0,0 +1,143 @@
+use std::{
+    fmt,
+    io::Error,
+    path::PathBuf,
+    process::Command,
+    sync::{Arc, OnceLock, RwLock},
+};
+
+use serde::Deserialize;
+
+use crate::storage::fs::read_file_to_string;
+
+const REDACTED: &str = "<redacted>";
+
+// very short values would redact unrelated text all over the log
+const MIN_REDACTED_SECRET_LENGTH: usize = 4;
+
+// every secret value resolved so far, replaced in all log output
+static REDACTED_VALUES: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+// where a secret comes from, in toml either a plain string or one of
+// { env = 'VAR' }, { file = '/run/secrets/name' } or { command = 'pass show name' }
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum SecretSource {
+    Plain(String),
+    Env { env: String },
+    File { file: PathBuf },
+    Command { command: String },
+}
+
+// a secret value, it is never printed by Debug or Display so it doesn't end up in logs.
+// Use expose() to get the value. Plain, env and file secrets are read when the config is
+// loaded, commands only run on the first expose(), so e.g. `bkp config check` or a local
+// backup don't unlock a password manager.
+#[derive(Deserialize, Clone)]
+#[serde(try_from = "SecretSource")]
+pub struct Secret {
+    command: Option<String>,
+    // shared by clones, so the command runs once per process
+    value: Arc<OnceLock<Result<String, String>>>,
+}
+
+impl Secret {
+    pub fn new(value: String) -> Secret {
+        Secret {
+            command: None,
+            value: Arc::new(OnceLock::from(Ok(register(value)))),
+        }
+    }
+
+    pub fn expose(&self) -> Result<&str, Error> {
+        let value = self.value.get_or_init(|| match &self.command {
+            Some(command) => run_secret_command(command).map(register),
+            None => Err("Secret has no value".to_string()),
+        });
+
+        value.as_deref().map_err(|e| Error::other(e.clone()))
+    }
+}
+
+impl PartialEq for Secret {
+    fn eq(&self, other: &Secret) -> bool {
+        match (self.value.get(), other.value.get()) {
+            (Some(value), Some(other_value)) => value == other_value,
+            _ => self.command.is_some() && self.command == other.command,
+        }
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret({})", REDACTED)
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", REDACTED)
+    }
+}
+
+impl TryFrom<SecretSource> for Secret {
+    type Error = String;
+
+    fn try_from(source: SecretSource) -> Result<Secret, String> {
+        let value = match source {
+            SecretSource::Plain(value) => value,
+            SecretSource::Env { env } => std::env::var(&env)
+                .map_err(|e| format!("Unable to read secret from env var {}: {}", env, e))?,
+            SecretSource::File { file } => read_file_to_string(&file)
+                .map_err(|e| format!("Unable to read secret from file {}: {}", file.display(), e))?
+                .trim_end_matches(['\n', '\r'])
+                .to_string(),
+            SecretSource::Command { command } => {
+                return Ok(Secret {
+                    command: Some(command),
+                    value: Arc::new(OnceLock::new()),
+                })
+            }
+        };
+
+        Ok(Secret::new(value))
+    }
+}
+
+fn run_secret_command(command: &str) -> Result<String, String> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()
+        .map_err(|e| format!("Unable to run secret command {}: {}", command, e))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "Secret command {} failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .trim_end_matches(['\n', '\r'])
+        .to_string())
+}
+
+fn register(value: String) -> String {
+    if value.len() >= MIN_REDACTED_SECRET_LENGTH {
+        let mut values = REDACTED_VALUES.write().unwrap_or_else(|e| e.into_inner());
+        if !values.contains(&value) {
+            values.push(value.clone());
+        }
+    }
+    value
+}
+
+// replaces the value of every secret resolved so far
+pub fn redact(text: &str) -> String {
+    let values = REDACTED_VALUES.read().unwrap_or_else(|e| e.into_inner());
+    values.iter().fold(text.to_string(), |text, value| {
+        text.replace(value.as_str(), REDACTED)
+    })
+}
@@ -0,0 +1,41 @@
+use std::sync::{Condvar, Mutex};
+
+// counting semaphore used to limit how many backups compress or upload at the same time
+pub struct Semaphore {
+    permits: Mutex<usize>,
+    available: Condvar,
+}
+
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Semaphore {
+    // without permits acquire would block forever
+    pub fn new(permits: usize) -> Semaphore {
+        assert!(permits > 0, "a semaphore needs at least one permit");
+        Semaphore {
+            permits: Mutex::new(permits),
+            available: Condvar::new(),
+        }
+    }
+
+    // blocks until a permit is available, the permit is returned when the guard is dropped
+    pub fn acquire(&self) -> SemaphorePermit<'_> {
+        let mut permits = self.permits.lock().unwrap();
+        while *permits == 0 {
+            permits = self.available.wait(permits).unwrap();
+        }
+        *permits -= 1;
+
+        SemaphorePermit { semaphore: self }
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        let mut permits = self.semaphore.permits.lock().unwrap();
+        *permits += 1;
+        self.semaphore.available.notify_one();
+    }
+}
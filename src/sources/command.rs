@@ -0,0 +1,24 @@
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use super::VIRTUAL_ENTRY_DIR;
+
+const COMMANDS_DIR: &str = "commands";
+
+// output of a command kept for reference, e.g. ['crontab', '-l'], ['iptables-save'] or
+// ['docker', 'inspect', 'myapp']. It is not restored, `tar -xzOf` or the archive shows it.
+#[derive(Deserialize, Debug, Clone)]
+pub struct CommandSource {
+    // file name of the output in the archive, it is stored under .bkp/commands/
+    pub name: String,
+    pub command: Vec<String>,
+}
+
+impl CommandSource {
+    pub fn entry_name(&self) -> PathBuf {
+        Path::new(VIRTUAL_ENTRY_DIR)
+            .join(COMMANDS_DIR)
+            .join(&self.name)
+    }
+}
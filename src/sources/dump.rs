@@ -0,0 +1,77 @@
+use std::{
+    io::{self, Error, Read},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+};
+
+use log::info;
+use serde::Deserialize;
+
+use crate::scripts::spawn_output_logger;
+
+use super::VIRTUAL_ENTRY_DIR;
+
+const DUMPS_DIR: &str = "dumps";
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DumpSource {
+    // file name of the dump in the archive, it is stored under .bkp/dumps/
+    pub name: String,
+    // the dump is the stdout of the command, e.g. ['pg_dump', '--format=custom', 'mydb'],
+    // ['mysqldump', '--single-transaction', 'mydb'] or ['mongodump', '--archive']
+    pub command: Vec<String>,
+    // gets the dump on stdin on restore, e.g. ['pg_restore', '--clean', '--dbname=mydb'],
+    // ['mysql', 'mydb'] or ['mongorestore', '--archive']. Without it the dump is not restored.
+    #[serde(default)]
+    pub restore_command: Vec<String>,
+}
+
+impl DumpSource {
+    pub fn entry_name(&self) -> PathBuf {
+        Path::new(VIRTUAL_ENTRY_DIR)
+            .join(DUMPS_DIR)
+            .join(&self.name)
+    }
+}
+
+// pipes a dump from the archive into the restore command of its source
+pub fn restore_dump<R: Read + ?Sized>(source: &DumpSource, dump: &mut R) -> Result<(), Error> {
+    info!(
+        "Restoring dump {} with {:?}",
+        source.name, source.restore_command
+    );
+
+    let mut child = Command::new(&source.restore_command[0])
+        .args(&source.restore_command[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+
+    let status = thread::scope(|scope| {
+        spawn_output_logger(scope, stdout, "restore", false);
+        spawn_output_logger(scope, stderr, "restore", true);
+
+        // stdin is closed before waiting, so the command sees the end of the dump
+        let copied = io::copy(dump, &mut stdin);
+        drop(stdin);
+        let status = child.wait()?;
+        copied?;
+
+        Ok::<_, Error>(status)
+    })?;
+
+    if !status.success() {
+        return Err(Error::other(format!(
+            "Restore command {:?} of dump {} failed with {}",
+            source.restore_command, source.name, status
+        )));
+    }
+
+    Ok(())
+}
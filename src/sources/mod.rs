@@ -0,0 +1,73 @@
+pub mod command;
+pub mod dump;
+pub mod sqlite;
+
+use std::{
+    io::{self, Read, Write},
+    path::PathBuf,
+};
+
+use serde::Deserialize;
+use tar::{Builder, Entry};
+
+use self::{command::CommandSource, dump::DumpSource, sqlite::SqliteSource};
+
+// archive entries produced by bkp, the manifest, dumps and command outputs, are stored
+// under this directory. They are told apart from the files of a real .bkp directory in
+// app_root by the pax key below, and are not restored into app_root as files.
+pub const VIRTUAL_ENTRY_DIR: &str = ".bkp";
+const VIRTUAL_ENTRY_PAX_KEY: &str = "BKP.virtual";
+
+// marks the entry appended next as produced by bkp
+pub fn append_virtual_entry_mark<W: Write>(tar_builder: &mut Builder<W>) -> Result<(), io::Error> {
+    tar_builder.append_pax_extensions([(VIRTUAL_ENTRY_PAX_KEY, b"1".as_slice())])
+}
+
+pub fn is_virtual_entry<R: Read>(entry: &mut Entry<R>) -> Result<bool, io::Error> {
+    let Some(extensions) = entry.pax_extensions()? else {
+        return Ok(false);
+    };
+
+    for extension in extensions {
+        if extension?.key_bytes() == VIRTUAL_ENTRY_PAX_KEY.as_bytes() {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+// data which is backed up in addition to the included_paths globs, in toml
+// [[sources.sqlite]], [[sources.dump]] and [[sources.command]] sections of the app config
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Sources {
+    #[serde(default)]
+    pub sqlite: Vec<SqliteSource>,
+    #[serde(default)]
+    pub dump: Vec<DumpSource>,
+    #[serde(default)]
+    pub command: Vec<CommandSource>,
+}
+
+// a command whose stdout is streamed into the archive as the file name
+#[derive(Debug, Clone)]
+pub struct CommandOutput {
+    pub name: PathBuf,
+    pub argv: Vec<String>,
+}
+
+impl Sources {
+    pub fn command_outputs(&self) -> Vec<CommandOutput> {
+        self.dump
+            .iter()
+            .map(|source| CommandOutput {
+                name: source.entry_name(),
+                argv: source.command.clone(),
+            })
+            .chain(self.command.iter().map(|source| CommandOutput {
+                name: source.entry_name(),
+                argv: source.command.clone(),
+            }))
+            .collect()
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    fs::{remove_file, rename},
+    io::{Error, ErrorKind, Read},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::info;
+use rusqlite::{
+    backup::{Backup, StepResult},
+    Connection, DatabaseName, OpenFlags,
+};
+use serde::Deserialize;
+use tar::Entry;
+
+// journal files next to the database, they are never archived as files because
+// the snapshot already contains their committed content
+const SQLITE_JOURNAL_SUFFIXES: [&str; 3] = ["-wal", "-shm", "-journal"];
+
+// the online backup copies all pages in one step. A backup in several steps starts over
+// whenever the app writes between two steps, so it may never finish while the app is busy.
+const BACKUP_STEP_PAGES: i32 = -1;
+// pause before retrying the step while the database is locked
+const BACKUP_STEP_PAUSE: Duration = Duration::from_millis(10);
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SqliteSource {
+    // path of the database file relative to app_root, the snapshot is archived under it
+    pub path: String,
+}
+
+impl SqliteSource {
+    pub fn db_path(&self, app_root: &str) -> PathBuf {
+        Path::new(app_root).join(&self.path)
+    }
+
+    // the database and its journal files
+    pub fn is_database_file(&self, app_root: &str, path: &Path) -> bool {
+        path == self.db_path(app_root)
+            || SQLITE_JOURNAL_SUFFIXES
+                .iter()
+                .any(|suffix| path == path_with_suffix(&self.db_path(app_root), suffix))
+    }
+
+    // files whose modification means the database changed, used for incremental backups
+    pub fn changed_files(&self, app_root: &str) -> Vec<PathBuf> {
+        [
+            self.db_path(app_root),
+            path_with_suffix(&self.db_path(app_root), "-wal"),
+        ]
+        .into_iter()
+        .filter(|path| path.is_file())
+        .collect()
+    }
+}
+
+// copies a consistent snapshot of a live database with the SQLite online backup API in a
+// single read transaction. Writers keep going in WAL mode, with a rollback journal they wait
+// for the copy. The database is opened read only, so the backup never changes it.
+pub fn snapshot_database(db_path: &Path, snapshot_path: &Path) -> Result<(), Error> {
+    info!("Creating snapshot of SQLite database {}", db_path.display());
+
+    let source = Connection::open_with_flags(
+        db_path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .map_err(sqlite_error)?;
+    source.busy_timeout(BUSY_TIMEOUT).map_err(sqlite_error)?;
+
+    let mut snapshot = Connection::open(snapshot_path).map_err(sqlite_error)?;
+
+    let backup = Backup::new_with_names(
+        &source,
+        DatabaseName::Main,
+        &mut snapshot,
+        DatabaseName::Main,
+    )
+    .map_err(sqlite_error)?;
+    let deadline = Instant::now() + BUSY_TIMEOUT;
+    loop {
+        match backup.step(BACKUP_STEP_PAGES).map_err(sqlite_error)? {
+            StepResult::Done => return Ok(()),
+            _ if Instant::now() >= deadline => {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "Database stayed locked during the snapshot",
+                ))
+            }
+            _ => thread::sleep(BACKUP_STEP_PAUSE),
+        }
+    }
+}
+
+// unpacks the snapshot next to the database and renames it over the database, so the
+// database is either the old or the restored one. Journals of the old database are
+// removed, SQLite would otherwise apply them to the restored one.
+pub fn restore_database<R: Read>(entry: &mut Entry<R>, db_path: &Path) -> Result<(), Error> {
+    info!("Restoring SQLite database {}", db_path.display());
+
+    let restore_path = path_with_suffix(db_path, ".bkp-restore");
+
+    if let Err(e) = entry.unpack(&restore_path) {
+        let _ = remove_file(&restore_path);
+        return Err(e);
+    }
+
+    remove_journals(db_path)?;
+
+    rename(&restore_path, db_path)
+}
+
+// a -wal file left next to a replaced database would be applied to it on open
+pub fn remove_journals(db_path: &Path) -> Result<(), Error> {
+    for journal in journal_paths(db_path) {
+        match remove_file(journal) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+// the journal files SQLite may keep next to a database, whether they exist or not
+pub fn journal_paths(db_path: &Path) -> Vec<PathBuf> {
+    SQLITE_JOURNAL_SUFFIXES
+        .iter()
+        .map(|suffix| path_with_suffix(db_path, suffix))
+        .collect()
+}
+
+fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_os_string();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+fn sqlite_error(e: rusqlite::Error) -> Error {
+    Error::other(e)
+}
@@ -11,11 +11,11 @@ use glob::glob;
 
 use crate::config::Config;
 
-pub fn read_file_to_string(path: &Path) -> String {
-    let mut file = File::open(path).expect("Unable to open file");
+pub fn read_file_to_string(path: &Path) -> Result<String, Error> {
+    let mut file = File::open(path)?;
     let mut contents = String::new();
-    file.read_to_string(&mut contents).unwrap();
-    contents
+    file.read_to_string(&mut contents)?;
+    Ok(contents)
 }
 
 pub fn list_files_in_dir(dir: PathBuf) -> Result<Vec<PathBuf>, Error> {
@@ -24,18 +24,18 @@ pub fn list_files_in_dir(dir: PathBuf) -> Result<Vec<PathBuf>, Error> {
     Ok(paths)
 }
 
-fn list_files_rec(dir: PathBuf, mut paths: &mut Vec<PathBuf>) -> Result<(), Error> {
+fn list_files_rec(dir: PathBuf, paths: &mut Vec<PathBuf>) -> Result<(), Error> {
     for entry in read_dir(dir)? {
         let path = entry?.path();
         match path.is_dir() {
-            true => list_files_rec(path, &mut paths)?,
+            true => list_files_rec(path, paths)?,
             false => paths.push(path),
         }
     }
     Ok(())
 }
 
-pub fn delete_file(path: &PathBuf) -> Result<(), Error> {
+pub fn delete_file(path: &Path) -> Result<(), Error> {
     remove_file(path)
 }
 
@@ -72,12 +72,19 @@ pub fn get_files_to_backup(config: &Config) -> Vec<PathBuf> {
         included_pathbufs.retain(|pathbuf| pathbuf != &excluded_pathbuf);
     }
 
+    // sqlite databases are backed up from a snapshot, the files are only listed so
+    // incremental backups notice when a database changed
+    for source in &config.sources.sqlite {
+        included_pathbufs.retain(|pathbuf| !source.is_database_file(app_root, pathbuf));
+        included_pathbufs.extend(source.changed_files(app_root));
+    }
+
     // return included_pathbufs
     included_pathbufs
 }
 
 pub fn filter_files_newer_than(
-    paths: &Vec<PathBuf>,
+    paths: &[PathBuf],
     time: &DateTime<Utc>,
 ) -> Result<Vec<PathBuf>, Error> {
     let mut filtered_paths: Vec<PathBuf> = Vec::new();
@@ -1,25 +1,38 @@
-use std::path::PathBuf;
+use std::{
+    io::{self, PipeReader},
+    path::{Path, PathBuf},
+    thread,
+};
 
-use log::info;
+use log::{debug, error, info};
 use s3::creds::Credentials;
 use s3::region::Region;
 use s3::Bucket;
 
 use crate::{
     backup::{get_backup_path_with_extension, parse_backup_from_path, Backup},
-    globalconfig::GLOBAL_CONFIG,
+    globalconfig::GlobalConfig,
+    secret::Secret,
 };
 
-fn create_bucket() -> Bucket {
+// like the other remote storage errors, a failing secret command panics, callers catch it
+fn create_bucket(global_config: &GlobalConfig) -> Bucket {
+    let expose = |secret: &Secret| {
+        secret
+            .expose()
+            .unwrap_or_else(|e| panic!("{}", e))
+            .to_string()
+    };
+
     Bucket::new(
         "bkp",
         Region::Custom {
             region: "eu-central-1".to_string(),
-            endpoint: GLOBAL_CONFIG.remote_storage_address.to_string(),
+            endpoint: global_config.remote_storage_address.to_string(),
         },
         Credentials::new(
-            Some(&GLOBAL_CONFIG.remote_storage_access_id),
-            Some(&GLOBAL_CONFIG.remote_storage_secret_key),
+            Some(&expose(&global_config.remote_storage_access_id)),
+            Some(&expose(&global_config.remote_storage_secret_key)),
             None,
             None,
             None,
@@ -30,8 +43,8 @@ fn create_bucket() -> Bucket {
     .with_path_style()
 }
 
-pub fn get_all_remote_backups() -> Vec<Backup> {
-    let bucket = create_bucket();
+pub fn get_all_remote_backups(global_config: &GlobalConfig) -> Vec<Backup> {
+    let bucket = create_bucket(global_config);
 
     let list_response = bucket.list("/".to_string(), Some("/".to_string())).unwrap();
 
@@ -40,7 +53,7 @@ pub fn get_all_remote_backups() -> Vec<Backup> {
         .contents
         .clone()
         .into_iter()
-        .map(|s3object| parse_backup_from_path(&s3object.key.into()))
+        .map(|s3object| parse_backup_from_path(s3object.key.as_ref()))
         .collect();
 
     backups.sort_by_key(|b| b.time);
@@ -48,8 +61,12 @@ pub fn get_all_remote_backups() -> Vec<Backup> {
     backups
 }
 
-pub fn upload_backup_to_remote(backup_file_path: PathBuf, backup_file_name: String) {
-    let bucket = create_bucket();
+pub fn upload_backup_to_remote(
+    global_config: &GlobalConfig,
+    backup_file_path: PathBuf,
+    backup_file_name: String,
+) {
+    let bucket = create_bucket(global_config);
 
     let backup_file_path_with_extension =
         get_backup_path_with_extension(&backup_file_path, ".tar.gz");
@@ -62,26 +79,113 @@ pub fn upload_backup_to_remote(backup_file_path: PathBuf, backup_file_name: Stri
     // assert_eq!(response_data.status_code(), 200);
 }
 
-pub fn download_backup_from_remote(backup: &Backup) {
-    let bucket = create_bucket();
+pub fn download_backup_from_remote(global_config: &GlobalConfig, backup: &Backup, path: &Path) {
+    let bucket = create_bucket(global_config);
 
     // create file writer
-    let mut writer = std::fs::File::create(&backup.path).unwrap();
+    let mut writer = std::fs::File::create(path).unwrap();
 
-    bucket
+    let status_code = bucket
         .get_object_stream(backup.path.to_str().unwrap(), &mut writer)
         .unwrap();
+    assert_eq!(status_code, 200);
 }
 
-pub fn delete_backup_from_remote(backup: &Backup) {
-    let bucket = create_bucket();
+// streams a backup from remote storage without storing it locally. The download runs in
+// a thread writing into a pipe and stops when the reader is dropped.
+pub fn stream_backup_from_remote(
+    global_config: &GlobalConfig,
+    backup: &Backup,
+) -> Result<PipeReader, io::Error> {
+    let bucket = create_bucket(global_config);
+    let key = backup.path.to_str().unwrap().to_string();
+    let (reader, mut writer) = io::pipe()?;
+
+    thread::spawn(move || match bucket.get_object_stream(&key, &mut writer) {
+        Ok(200) => {}
+        Ok(status) => error!("Downloading {} failed with status {}", key, status),
+        // also when the reader stopped early, the reader notices real failures as a
+        // truncated archive
+        Err(e) => debug!("Stopped downloading {}: {}", key, e),
+    });
+
+    Ok(reader)
+}
 
-    let response_data = bucket
-        .delete_object(&backup.path.to_str().unwrap())
-        .unwrap();
+pub fn delete_backup_from_remote(global_config: &GlobalConfig, backup: &Backup) {
+    let bucket = create_bucket(global_config);
+
+    let response_data = bucket.delete_object(backup.path.to_str().unwrap()).unwrap();
     assert_eq!(response_data.status_code(), 204);
 }
 
+// None if key doesn't exist. Requests which don't reach the remote storage fail with
+// NotConnected, so callers can tell them apart from errors the storage answered with.
+pub fn get_remote_object(
+    global_config: &GlobalConfig,
+    key: &str,
+) -> Result<Option<String>, io::Error> {
+    let bucket = create_bucket(global_config);
+
+    let response_data = bucket
+        .get_object(key)
+        .map_err(|e| io::Error::new(io::ErrorKind::NotConnected, e))?;
+    match response_data.status_code() {
+        200 => Ok(Some(
+            String::from_utf8_lossy(response_data.bytes()).to_string(),
+        )),
+        404 => Ok(None),
+        status => Err(io::Error::other(format!(
+            "Reading {} failed with status {}",
+            key, status
+        ))),
+    }
+}
+
+pub fn put_remote_object(global_config: &GlobalConfig, key: &str, content: &str) {
+    let bucket = create_bucket(global_config);
+
+    let response_data = bucket.put_object(key, content.as_bytes()).unwrap();
+    assert_eq!(response_data.status_code(), 200);
+}
+
+// creates key only if it doesn't exist yet, using a conditional put (If-None-Match: *).
+// Returns false if the object exists. Storage without conditional writes overwrites it.
+pub fn create_remote_object(
+    global_config: &GlobalConfig,
+    key: &str,
+    content: &str,
+) -> Result<bool, io::Error> {
+    let mut bucket = create_bucket(global_config);
+    bucket.add_header("If-None-Match", "*");
+
+    let response_data = bucket
+        .put_object(key, content.as_bytes())
+        .map_err(io::Error::other)?;
+    match response_data.status_code() {
+        200 => Ok(true),
+        // 409 when a concurrent conditional write is still in progress
+        412 | 409 => Ok(false),
+        status => Err(io::Error::other(format!(
+            "Creating {} failed with status {}",
+            key, status
+        ))),
+    }
+}
+
+pub fn delete_remote_object(global_config: &GlobalConfig, key: &str) -> Result<(), io::Error> {
+    let bucket = create_bucket(global_config);
+
+    let response_data = bucket.delete_object(key).map_err(io::Error::other)?;
+    match response_data.status_code() {
+        200 | 204 | 404 => Ok(()),
+        status => Err(io::Error::other(format!(
+            "Deleting {} failed with status {}",
+            key, status
+        ))),
+    }
+}
+
 // fn parse_backup_from_s3object(path: &PathBuf) -> Backup {
 //     let file_name = path.file_name().unwrap().to_str().unwrap().to_string();
 
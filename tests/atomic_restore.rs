@@ -0,0 +1,210 @@
+mod common;
+
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use bkp::{compress::read_archive_entry, extract::RestoreOptions, BackupType};
+use common::TestDir;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+impl TestDir {
+    fn staging_exists(&self) -> bool {
+        self.dir.join("app.bkp-staging").exists()
+    }
+}
+
+// replaces bytes of the uncompressed archive, the tar headers stay valid
+fn tamper(archive: &Path, from: &[u8], to: &[u8]) {
+    let mut tar = Vec::new();
+    GzDecoder::new(File::open(archive).unwrap())
+        .read_to_end(&mut tar)
+        .unwrap();
+
+    let position = tar
+        .windows(from.len())
+        .position(|window| window == from)
+        .unwrap();
+    tar[position..position + to.len()].copy_from_slice(to);
+
+    let mut encoder = GzEncoder::new(File::create(archive).unwrap(), Compression::default());
+    encoder.write_all(&tar).unwrap();
+    encoder.finish().unwrap();
+}
+
+#[test]
+fn archives_contain_a_manifest() {
+    let test = TestDir::new("manifest");
+    test.write("file", "content\n");
+    fs::create_dir(test.app().join("dir")).unwrap();
+
+    let backup_name = test.backup(BackupType::Full, &[]);
+
+    let manifest = read_archive_entry(
+        &test.archive(&backup_name),
+        Path::new(".bkp/manifest"),
+        |entry| {
+            let mut manifest = String::new();
+            entry.read_to_string(&mut manifest)?;
+            Ok(manifest)
+        },
+    )
+    .unwrap()
+    .unwrap();
+
+    assert!(manifest.contains("dir\tdir\n"));
+    // crc32 of "content\n"
+    assert!(manifest.contains("file\t8\t9d23d8ef\tfile\n"));
+}
+
+#[test]
+fn restore_replaces_backed_up_files_only() {
+    let test = TestDir::new("atomic-restore");
+    test.write("a", "version 1\n");
+    let backup_name = test.backup(BackupType::Full, &[]);
+    test.write("a", "version 2\n");
+    test.write("new", "not backed up\n");
+
+    test.restore(&backup_name, RestoreOptions::default())
+        .unwrap();
+
+    assert_eq!(test.read("a"), "version 1\n");
+    assert_eq!(test.read("new"), "not backed up\n");
+    assert!(!test.staging_exists());
+}
+
+#[test]
+fn failing_archive_leaves_app_root_untouched() {
+    let test = TestDir::new("atomic-failing");
+    test.write("a", "version 1\n");
+    test.backup(BackupType::Full, &[]);
+    test.write("b", "version 1\n");
+    let backup_name = test.backup(BackupType::Incremental, &["b"]);
+    test.write("a", "version 2\n");
+    test.write("b", "version 2\n");
+
+    // the full backup unpacks fine, the incremental one is cut off
+    let length = fs::metadata(test.archive(&backup_name)).unwrap().len();
+    File::options()
+        .write(true)
+        .open(test.archive(&backup_name))
+        .unwrap()
+        .set_len(length / 2)
+        .unwrap();
+
+    assert!(test
+        .restore(&backup_name, RestoreOptions::default())
+        .is_err());
+
+    assert_eq!(test.read("a"), "version 2\n");
+    assert_eq!(test.read("b"), "version 2\n");
+    assert!(!test.staging_exists());
+}
+
+#[test]
+fn manifest_mismatch_leaves_app_root_untouched() {
+    let test = TestDir::new("atomic-mismatch");
+    test.write("a", "version 1\n");
+    let backup_name = test.backup(BackupType::Full, &[]);
+    test.write("a", "version 2\n");
+
+    tamper(&test.archive(&backup_name), b"version 1\n", b"version X\n");
+
+    let error = test
+        .restore(&backup_name, RestoreOptions::default())
+        .unwrap_err();
+
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(test.read("a"), "version 2\n");
+    assert!(!test.staging_exists());
+}
+
+#[test]
+fn failing_swap_is_rolled_back() {
+    let test = TestDir::new("atomic-rollback");
+    for name in ["a", "x", "dir/c", "dir/d"] {
+        test.write(name, "version 1\n");
+    }
+    let backup_name = test.backup(BackupType::Full, &[]);
+    for name in ["a", "dir/c", "dir/d"] {
+        test.write(name, "version 2\n");
+    }
+    fs::remove_file(test.app().join("x")).unwrap();
+    test.write("x/y", "version 2\n");
+
+    // the top level is swapped before dir, where a directory is in the way of moving the
+    // replaced dir/d away
+    test.write("dir/d.bkp-replaced/keep", "");
+
+    assert!(test
+        .restore(&backup_name, RestoreOptions::default())
+        .is_err());
+
+    for name in ["a", "x/y", "dir/c", "dir/d"] {
+        assert_eq!(test.read(name), "version 2\n");
+    }
+    let mut names = fs::read_dir(test.app().join("dir"))
+        .unwrap()
+        .chain(fs::read_dir(test.app()).unwrap())
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<String>>();
+    names.sort();
+    assert_eq!(names, ["a", "c", "d", "d.bkp-replaced", "dir", "x"]);
+    assert!(!test.staging_exists());
+}
+
+#[test]
+fn move_aside_swaps_the_whole_tree() {
+    let test = TestDir::new("atomic-move-aside");
+    test.write("a", "version 1\n");
+    let backup_name = test.backup(BackupType::Full, &[]);
+    test.write("a", "version 2\n");
+    test.write("new", "not backed up\n");
+
+    test.restore(
+        &backup_name,
+        RestoreOptions {
+            move_aside: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    assert_eq!(test.read("a"), "version 1\n");
+    assert!(!test.app().join("new").exists());
+
+    let aside = fs::read_dir(&test.dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.to_str().unwrap().contains("app.before-restore-"))
+        .unwrap();
+    assert_eq!(
+        fs::read_to_string(aside.join("new")).unwrap(),
+        "not backed up\n"
+    );
+    assert!(!test.staging_exists());
+}
+
+#[test]
+fn restore_into_missing_app_root() {
+    let test = TestDir::new("atomic-missing-root");
+    test.write("a", "a\n");
+    fs::create_dir(test.app().join("dir")).unwrap();
+    let backup_name = test.backup(BackupType::Full, &[]);
+    fs::remove_dir_all(test.app()).unwrap();
+
+    let dry_run = RestoreOptions {
+        dry_run: true,
+        ..Default::default()
+    };
+    test.restore(&backup_name, dry_run).unwrap();
+    assert!(!test.app().exists());
+
+    test.restore(&backup_name, RestoreOptions::default())
+        .unwrap();
+    assert_eq!(test.read("a"), "a\n");
+    assert!(test.app().join("dir").is_dir());
+    assert!(!test.staging_exists());
+}
@@ -0,0 +1,84 @@
+mod common;
+
+use std::{fs, io::ErrorKind, path::Path};
+
+use bkp::{cat, ls, BackupType};
+use common::TestDir;
+
+impl TestDir {
+    fn ls(&self, backup_name: &str, path: Option<&str>) -> String {
+        let mut out = Vec::new();
+        ls(
+            &self.global_config(),
+            &self.config(),
+            backup_name,
+            path.map(Path::new),
+            &mut out,
+        )
+        .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn cat(&self, backup_name: &str, file: &str) -> Result<String, std::io::Error> {
+        let mut out = Vec::new();
+        cat(
+            &self.global_config(),
+            &self.config(),
+            backup_name,
+            Path::new(file),
+            &mut out,
+        )?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+}
+
+#[test]
+fn ls_lists_the_files_of_the_whole_chain() {
+    let test = TestDir::new("browse-ls");
+    test.write("etc/app.conf", "port = 1\n");
+    test.write("data", "data\n");
+    test.backup(BackupType::Full, &[]);
+    test.write("etc/app.conf", "port = 22\n");
+    let backup_name = test.backup(BackupType::Incremental, &["etc/app.conf"]);
+
+    let listing = test.ls(&backup_name, None);
+    let lines = listing.lines().collect::<Vec<&str>>();
+
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("-rw-r--r--          5 "));
+    assert!(lines[0].ends_with(" data"));
+    assert!(lines[1].starts_with("drwx"));
+    assert!(lines[1].ends_with(" etc"));
+    // the version of the incremental backup
+    assert!(lines[2].starts_with("-rw-r--r--         10 "));
+    assert!(lines[2].ends_with(" etc/app.conf"));
+
+    let listing = test.ls(&backup_name, Some("./etc"));
+    assert_eq!(listing.lines().count(), 2);
+}
+
+#[test]
+fn cat_writes_the_file_as_of_the_backup() {
+    let test = TestDir::new("browse-cat");
+    test.write("etc/app.conf", "port = 1\n");
+    test.write("data", "data\n");
+    fs::hard_link(test.app().join("data"), test.app().join("etc/data")).unwrap();
+    let full_name = test.backup(BackupType::Full, &[]);
+    test.write("etc/app.conf", "port = 2\n");
+    let backup_name = test.backup(BackupType::Incremental, &["etc/app.conf"]);
+    test.write("etc/app.conf", "port = 3\n");
+
+    assert_eq!(test.cat(&full_name, "etc/app.conf").unwrap(), "port = 1\n");
+    assert_eq!(
+        test.cat(&backup_name, "/etc/app.conf").unwrap(),
+        "port = 2\n"
+    );
+    // only in the full backup the incremental one is based on
+    assert_eq!(test.cat(&backup_name, "data").unwrap(), "data\n");
+    assert_eq!(test.cat(&backup_name, "etc/data").unwrap(), "data\n");
+
+    let error = test.cat(&backup_name, "missing").unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::NotFound);
+    let error = test.cat(&backup_name, "etc").unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+}
@@ -0,0 +1,255 @@
+mod common;
+
+use std::{
+    fs,
+    io::ErrorKind,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+};
+
+use bkp::{
+    backup::{get_all_local_backups, parse_backup_from_path},
+    catalog::Catalog,
+    extract::RestoreOptions,
+    BackupType,
+};
+use chrono::{Duration, SecondsFormat, Utc};
+use common::{name, TestDir};
+use rusqlite::{params, Connection};
+
+impl TestDir {
+    fn catalog(&self) -> Catalog {
+        Catalog::open(&self.dir.join("storage")).unwrap()
+    }
+
+    // pretends remote storage was listed at that time
+    fn set_remote_listed(&self, listed: &str) {
+        Connection::open(self.dir.join("storage/.catalog.sqlite"))
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO state (key, value) VALUES ('remote_listed', ?1)",
+                params![listed],
+            )
+            .unwrap();
+    }
+}
+
+#[test]
+fn catalog_records_new_backups_and_their_chain() {
+    let test = TestDir::new("catalog-chain");
+    test.write("a", "version 1\n");
+    let full = test.backup(BackupType::Full, &[]);
+    test.write("a", "version 2\n");
+    let incremental = test.backup(BackupType::Incremental, &["a"]);
+
+    let entries = test.catalog().entries(Some("app")).unwrap();
+
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].name, name(&full));
+    assert_eq!(entries[0].base, None);
+    assert_eq!(entries[1].name, name(&incremental));
+    assert_eq!(entries[1].base, Some(name(&full)));
+    assert_eq!(entries[1].local_path, Some(test.archive(&incremental)));
+    assert_eq!(
+        entries[1].size,
+        Some(fs::metadata(test.archive(&incremental)).unwrap().len())
+    );
+    assert!(!entries[1].remote);
+
+    let backups = get_all_local_backups(&test.global_config());
+    assert_eq!(backups[0].path, test.archive(&incremental));
+    assert_eq!(backups[1].path, test.archive(&full));
+}
+
+#[test]
+fn new_catalog_is_filled_from_local_storage() {
+    let test = TestDir::new("catalog-scan");
+    test.write("a", "version 1\n");
+    let full = test.backup(BackupType::Full, &[]);
+    fs::remove_file(test.dir.join("storage/.catalog.sqlite")).unwrap();
+
+    let entries = test.catalog().entries(None).unwrap();
+
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].name, name(&full));
+
+    let catalog = test.catalog();
+    catalog
+        .remove_local(&parse_backup_from_path(&test.archive(&full)))
+        .unwrap();
+    assert!(catalog.entries(None).unwrap().is_empty());
+}
+
+#[test]
+fn catalogs_of_earlier_versions_are_migrated_once() {
+    let test = TestDir::new("catalog-migrate");
+    let path = test.dir.join("storage/.catalog.sqlite");
+    let tables = |connection: &Connection| {
+        connection
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+            .unwrap()
+            .query_map([], |row| row.get::<_, String>(0))
+            .unwrap()
+            .collect::<Result<Vec<String>, _>>()
+            .unwrap()
+    };
+
+    // indexes were called manifests before
+    Connection::open(&path)
+        .unwrap()
+        .execute_batch(
+            "CREATE TABLE backups (name TEXT PRIMARY KEY, app_name TEXT NOT NULL,
+                backup_type TEXT NOT NULL, time TEXT NOT NULL, base TEXT, size INTEGER,
+                local_path TEXT, remote INTEGER NOT NULL DEFAULT 0, verified_at TEXT,
+                verification TEXT);
+            CREATE TABLE manifests (backup TEXT PRIMARY KEY, content BLOB NOT NULL);
+            INSERT INTO backups (name, app_name, backup_type, time)
+                VALUES ('app_web_full_2026-01-01T00:00:00+00:00', 'app', 'full',
+                    '2026-01-01T00:00:00Z');",
+        )
+        .unwrap();
+
+    test.catalog();
+    let connection = Connection::open(&path).unwrap();
+    assert_eq!(tables(&connection), ["backups", "indexes", "state"]);
+    // the server of recorded backups is taken from their name
+    let server_name: String = connection
+        .query_row("SELECT server_name FROM backups", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(server_name, "web");
+
+    // opening a migrated catalog changes nothing
+    connection
+        .execute_batch("CREATE TABLE manifests (backup TEXT PRIMARY KEY);")
+        .unwrap();
+    test.catalog();
+    assert_eq!(
+        tables(&connection),
+        ["backups", "indexes", "manifests", "state"]
+    );
+}
+
+#[test]
+fn restore_records_the_verification() {
+    let test = TestDir::new("catalog-verification");
+    test.write("a", "version 1\n");
+    let full = test.backup(BackupType::Full, &[]);
+    test.write("a", "version 2\n");
+    let incremental = test.backup(BackupType::Incremental, &["a"]);
+
+    test.restore(&incremental, RestoreOptions::default())
+        .unwrap();
+
+    let entries = test.catalog().entries(Some("app")).unwrap();
+    assert_eq!(entries[0].name, name(&full));
+    assert_eq!(entries[0].verification.as_deref(), Some("ok"));
+    assert_eq!(entries[1].verification.as_deref(), Some("ok"));
+    assert!(entries[1].verified_at.is_some());
+}
+
+#[test]
+fn bases_follow_removed_and_recorded_backups() {
+    let test = TestDir::new("catalog-bases");
+    test.write("a", "version 1\n");
+    let full = test.backup(BackupType::Full, &[]);
+    test.write("a", "version 2\n");
+    let first = test.backup(BackupType::Incremental, &["a"]);
+    test.write("a", "version 3\n");
+    let second = test.backup(BackupType::Incremental, &["a"]);
+
+    let catalog = test.catalog();
+    let first_backup = parse_backup_from_path(&test.archive(&first));
+    catalog.remove_local(&first_backup).unwrap();
+
+    let entries = catalog.entries(Some("app")).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[1].name, name(&second));
+    assert_eq!(entries[1].base, Some(name(&full)));
+
+    // recorded again, e.g. by a rebuild, it is the base of the next backup again
+    catalog.add_local(&first_backup).unwrap();
+
+    let entries = catalog.entries(Some("app")).unwrap();
+    assert_eq!(entries[1].base, Some(name(&full)));
+    assert_eq!(entries[2].base, Some(name(&first)));
+
+    // backups of another server sharing the bucket are no bases of this server's backups
+    let time = |offset| {
+        (parse_backup_from_path(&test.archive(&full)).time + Duration::nanoseconds(offset))
+            .to_rfc3339_opts(SecondsFormat::Nanos, false)
+    };
+    let other_full = format!("app_other_full_{}", time(1));
+    let other_incremental = format!("app_other_incremental_{}", time(2));
+    for other in [&other_full, &other_incremental] {
+        catalog
+            .add_remote(&parse_backup_from_path(Path::new(other)))
+            .unwrap();
+    }
+
+    let entries = catalog.entries(Some("app")).unwrap();
+    assert_eq!(entries.len(), 5);
+    assert_eq!(entries[2].name, other_incremental);
+    assert_eq!(entries[2].base, Some(other_full));
+    assert_eq!(entries[3].base, Some(name(&full)));
+    assert_eq!(entries[4].base, Some(name(&first)));
+}
+
+#[test]
+fn remote_backups_are_listed_again_after_the_ttl() {
+    let test = TestDir::new("catalog-remote");
+    let global_config = test.global_config();
+    let catalog = test.catalog();
+    let remote = parse_backup_from_path(Path::new("app_server_full_2026-01-01T00:00:00+00:00"));
+    catalog.add_remote(&remote).unwrap();
+
+    // never listed, the unreachable remote storage panics like without a catalog
+    let listing = panic::catch_unwind(AssertUnwindSafe(|| catalog.remote_backups(&global_config)));
+    assert!(listing.is_err());
+
+    // a recent listing is used without listing remote storage
+    test.set_remote_listed(&Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true));
+    let backups = catalog.remote_backups(&global_config).unwrap();
+    assert_eq!(backups.len(), 1);
+    assert_eq!(backups[0].file_name, remote.file_name);
+
+    // an old one is listed again, when that fails the catalog is used
+    test.set_remote_listed("2026-01-01T00:00:00Z");
+    let backups = catalog.remote_backups(&global_config).unwrap();
+    assert_eq!(backups.len(), 1);
+}
+
+#[test]
+fn restore_chain_stays_within_the_backups_of_one_server() {
+    let test = TestDir::new("catalog-restore-server");
+    test.write("a", "server\n");
+    test.backup(BackupType::Full, &[]);
+    test.write("a", "other\n");
+    test.full_backup_of("other");
+    test.write("b", "b\n");
+    let incremental = test.backup(BackupType::Incremental, &["b"]);
+    fs::remove_file(test.app().join("a")).unwrap();
+
+    test.restore(&incremental, RestoreOptions::default())
+        .unwrap();
+
+    assert_eq!(test.read("a"), "server\n");
+    assert_eq!(test.read("b"), "b\n");
+}
+
+#[test]
+fn restore_of_an_unknown_backup_fails() {
+    let test = TestDir::new("catalog-unknown");
+    test.write("a", "version 1\n");
+    test.backup(BackupType::Full, &[]);
+    test.catalog();
+    test.set_remote_listed(&Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true));
+
+    let error = test
+        .restore(
+            "app_server_full_2020-01-01T00:00:00+00:00.tar.gz",
+            RestoreOptions::default(),
+        )
+        .unwrap_err();
+
+    assert_eq!(error.kind(), ErrorKind::NotFound);
+}
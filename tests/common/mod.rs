@@ -0,0 +1,136 @@
+// fixtures shared by the integration tests, not every test file uses all of them
+#![allow(dead_code)]
+
+use std::{fs, io, path::PathBuf};
+
+use bkp::{
+    backup::{do_full_backup, do_incremental_backup, get_new_backup_file_path},
+    config::parse_config_with_defaults,
+    extract::RestoreOptions,
+    globalconfig::parse_global_config,
+    restore, BackupType, Config, GlobalConfig,
+};
+
+pub struct TestDir {
+    pub dir: PathBuf,
+}
+
+impl TestDir {
+    // a fresh temporary directory with an empty app_root and local storage
+    pub fn new(name: &str) -> TestDir {
+        let dir = std::env::temp_dir().join(format!("bkp-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("storage")).unwrap();
+        fs::create_dir_all(dir.join("app")).unwrap();
+        TestDir { dir }
+    }
+
+    pub fn app(&self) -> PathBuf {
+        self.dir.join("app")
+    }
+
+    // the global config keeps everything inside the test directory, extra is appended as is
+    pub fn global_config_toml(&self, extra: &str) -> String {
+        format!(
+            "config_files_location = '{dir}/conf.d'\n\
+             local_storage_location = '{dir}/storage'\n\
+             remote_storage_address = 'http://localhost:9'\n\
+             remote_storage_access_id = 'id'\n\
+             remote_storage_secret_key = 'key'\n\
+             log_file_location = '{dir}/bkp.log'\n\
+             {extra}",
+            dir = self.dir.display(),
+            extra = extra
+        )
+    }
+
+    pub fn global_config(&self) -> GlobalConfig {
+        self.global_config_with("")
+    }
+
+    pub fn global_config_with(&self, extra: &str) -> GlobalConfig {
+        parse_global_config(&self.global_config_toml(extra)).unwrap()
+    }
+
+    pub fn config(&self) -> Config {
+        self.config_of("server")
+    }
+
+    // the config of the app on another server sharing local and remote storage
+    pub fn config_of(&self, server_name: &str) -> Config {
+        parse_config_with_defaults(
+            &format!(
+                "app_name = 'app'\nserver_name = '{}'\napp_root = '{}/'\nincluded_paths = ['**/*']",
+                server_name,
+                self.app().display()
+            ),
+            &Default::default(),
+        )
+        .unwrap()
+    }
+
+    // backs up the named files of app_root, all of them for full backups,
+    // and returns the backup name the commands take
+    pub fn backup(&self, backup_type: BackupType, names: &[&str]) -> String {
+        let config = self.config();
+        let backup_file_path =
+            get_new_backup_file_path(&self.global_config(), &config, &backup_type);
+        match backup_type {
+            BackupType::Full => do_full_backup(&config, &backup_file_path).unwrap(),
+            BackupType::Incremental => {
+                let paths = names
+                    .iter()
+                    .map(|name| self.app().join(name))
+                    .collect::<Vec<PathBuf>>();
+                do_incremental_backup(&config, &paths, &backup_file_path).unwrap()
+            }
+        }
+        let file_name = backup_file_path.file_name().unwrap().to_str().unwrap();
+        format!("{}.tar.gz", file_name)
+    }
+
+    // a full backup of all of app_root made by another server
+    pub fn full_backup_of(&self, server_name: &str) -> String {
+        let config = self.config_of(server_name);
+        let backup_file_path =
+            get_new_backup_file_path(&self.global_config(), &config, &BackupType::Full);
+        do_full_backup(&config, &backup_file_path).unwrap();
+        let file_name = backup_file_path.file_name().unwrap().to_str().unwrap();
+        format!("{}.tar.gz", file_name)
+    }
+
+    pub fn archive(&self, backup_name: &str) -> PathBuf {
+        self.dir.join("storage").join(backup_name)
+    }
+
+    pub fn restore(&self, backup_name: &str, options: RestoreOptions) -> Result<(), io::Error> {
+        restore(
+            &self.global_config(),
+            &self.config(),
+            backup_name,
+            &options,
+            false,
+        )
+    }
+
+    pub fn read(&self, name: &str) -> String {
+        fs::read_to_string(self.app().join(name)).unwrap()
+    }
+
+    pub fn write(&self, name: &str, content: &str) {
+        let path = self.app().join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+// the backup name without the archive extension, as the catalog records it
+pub fn name(backup_name: &str) -> String {
+    backup_name.strip_suffix(".tar.gz").unwrap().to_string()
+}
@@ -0,0 +1,67 @@
+use bkp::config::{
+    merge_defaults, resolve_config, DEFAULT_KEEP_FULL_LOCAL_BACKUPS,
+    DEFAULT_KEEP_FULL_REMOTE_BACKUPS,
+};
+use toml::value::Table;
+
+fn table(content: &str) -> Table {
+    toml::from_str(content).unwrap()
+}
+
+#[test]
+fn app_values_take_precedence_over_the_defaults() {
+    let defaults = table(
+        "keep_full_local_backups = 3\nscript_shell = 'bash'\nincluded_paths = ['data', 'logs']\n",
+    );
+    let app_config = table(
+        "app_name = 'app'\napp_root = '/srv/app/'\nscript_shell = 'sh'\nincluded_paths = ['db']\n",
+    );
+
+    let config = resolve_config(&app_config, &defaults).unwrap();
+
+    assert_eq!(config.script_shell, "sh");
+    assert_eq!(config.keep_full_local_backups, 3);
+    // arrays are replaced, not appended to
+    assert_eq!(config.included_paths, ["db"]);
+    assert_eq!(
+        config.keep_full_remote_backups,
+        DEFAULT_KEEP_FULL_REMOTE_BACKUPS
+    );
+
+    let config = resolve_config(&app_config, &Table::new()).unwrap();
+    assert_eq!(
+        config.keep_full_local_backups,
+        DEFAULT_KEEP_FULL_LOCAL_BACKUPS
+    );
+}
+
+#[test]
+fn nested_tables_are_merged_key_by_key() {
+    let defaults = table(
+        "[metadata]\nxattrs = true\nownership = false\n\
+         [sources]\ncommand = [{ name = 'crontab', command = ['crontab', '-l'] }]\n\
+         [restore]\nallow_special_files = true\n",
+    );
+    let app_config = table(
+        "app_name = 'app'\napp_root = '/srv/app/'\n\
+         [metadata]\nownership = true\n\
+         [[sources.dump]]\nname = 'db'\ncommand = ['pg_dump', 'db']\n",
+    );
+
+    let merged = merge_defaults(&app_config, &defaults);
+    assert_eq!(
+        merged["metadata"],
+        toml::Value::Table(table("xattrs = true\nownership = true\n"))
+    );
+
+    let config = resolve_config(&app_config, &defaults).unwrap();
+    assert!(config.metadata.xattrs);
+    assert!(config.metadata.ownership);
+    assert!(config.restore.allow_special_files);
+    assert_eq!(config.sources.dump[0].name, "db");
+    // the app sets [sources] without a command list, the default one is kept
+    assert_eq!(config.sources.command[0].name, "crontab");
+
+    // the defaults are not changed by the merge
+    assert_eq!(defaults["metadata"]["ownership"].as_bool(), Some(false));
+}
@@ -0,0 +1,150 @@
+mod common;
+
+use std::fs;
+
+use bkp::configcheck::check_configs;
+use common::TestDir;
+
+impl TestDir {
+    fn write_file(&self, name: &str, content: &str) {
+        let path = self.dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, self.expand(content)).unwrap();
+    }
+
+    fn expand(&self, text: &str) -> String {
+        text.replace("{dir}", &self.dir.display().to_string())
+    }
+
+    // writes the global config and returns every problem found, formatted like `bkp check`
+    fn check(&self, extra: &str) -> Vec<String> {
+        fs::create_dir_all(self.dir.join("conf.d")).unwrap();
+        self.write_file("bkp.toml", &self.global_config_toml(extra));
+        check_configs(&self.dir.join("bkp.toml"))
+            .iter()
+            .map(|problem| problem.to_string())
+            .collect()
+    }
+}
+
+#[test]
+fn check_reports_problems_at_the_line_of_their_key() {
+    // app config, expected problems
+    let cases: [(&str, &[&str]); 7] = [
+        (
+            "app_name = 'my_app'\napp_root = '{dir}/app/'\n",
+            &["{dir}/conf.d/app.toml:1: app_name 'my_app' must be non empty and must not contain '_' or '/'"],
+        ),
+        (
+            "app_name = 'app'\n\n  app_root   =   '{dir}/missing/'\n",
+            &["{dir}/conf.d/app.toml:3: app_root '{dir}/missing/' is not an existing directory"],
+        ),
+        (
+            "app_name = 'app'\napp_root = '{dir}/app/'\nexcluded_paths = ['*.tmp']\nkeep_full_local_backups = -1\n",
+            &["{dir}/conf.d/app.toml:4: keep_full_local_backups must not be negative, found -1"],
+        ),
+        (
+            "app_name = 'app'\napp_root = '{dir}/app/'\n[[sources.dump]]\nname = 'db'\ncommand = ['pg_dump']\n\
+             [[sources.dump]]\nname = 'a/b'\ncommand = []\nrestore_command = ['/missing/restore']\n",
+            &[
+                "{dir}/conf.d/app.toml:7: sources.dump[1].name 'a/b' must be a file name without '/'",
+                "{dir}/conf.d/app.toml:8: sources.dump[1].command must not be empty",
+                "{dir}/conf.d/app.toml:9: sources.dump[1].restore_command '/missing/restore' does not exist",
+            ],
+        ),
+        (
+            "app_name = 'app'\napp_root = '{dir}/app/'\n[sources]\nsqlite = [\n  { path = 'missing.db' },\n]\n\
+             command = [{ name = 'crontab', command = ['crontab', '-l'] }, { name = 'crontab', command = ['ls'] }]\n",
+            &[
+                "{dir}/conf.d/app.toml:5: sources.sqlite[0].path 'missing.db' is not an existing file",
+                "{dir}/conf.d/app.toml:7: sources.command[1].name 'crontab' is already used",
+            ],
+        ),
+        (
+            "app_name = 'app'\napp_root = '{dir}/app/'\n[metadata]\nxattrs = true\n\n[restore]\nallow_special_files = true\n[[sources.dump]]\nname = 'db'\ncommand = ['pg_dump']\n",
+            &[],
+        ),
+        (
+            "app_name = 'app'\napp_root = \n",
+            &["{dir}/conf.d/app.toml:2: "],
+        ),
+    ];
+
+    for (index, (config, expected)) in cases.iter().enumerate() {
+        let test = TestDir::new(&format!("configcheck-{}", index));
+        test.write_file("conf.d/app.toml", config);
+
+        let problems = test.check("");
+        assert_eq!(problems.len(), expected.len(), "{:?}", problems);
+        for (problem, expected) in problems.iter().zip(expected.iter()) {
+            assert!(problem.starts_with(&test.expand(expected)), "{}", problem);
+        }
+    }
+}
+
+#[test]
+fn check_reports_inline_apps_at_their_line_in_the_global_config() {
+    let test = TestDir::new("configcheck-inline");
+
+    let problems = test.check(
+        "[[apps]]\napp_name = 'first'\napp_root = '{dir}/app/'\n\n\
+         [[apps]]\napp_name = 'second'\napp_root = '{dir}/app/'\nschedule = 'never'\n\
+         [[apps]]\napp_name = 'first'\napp_root = '{dir}/app/'\n",
+    );
+
+    assert_eq!(problems.len(), 2, "{:?}", problems);
+    assert!(problems[0].starts_with(&test.expand("{dir}/bkp.toml:14: Invalid schedule")));
+    assert_eq!(
+        problems[1],
+        test.expand("{dir}/bkp.toml:16: app_name 'first' is already used in {dir}/bkp.toml:8")
+    );
+}
+
+#[test]
+fn missing_default_conf_d_is_fine_with_inline_apps_only() {
+    let test = TestDir::new("configcheck-no-conf-d");
+    // without config_files_location, conf.d next to bkp.toml is used
+    let global_config = test.global_config_toml("");
+    let global_config = global_config
+        .lines()
+        .filter(|line| !line.starts_with("config_files_location"))
+        .collect::<Vec<&str>>()
+        .join("\n");
+    let check = |apps: &str| -> Vec<String> {
+        test.write_file("bkp.toml", &format!("{}\n{}", global_config, apps));
+        check_configs(&test.dir.join("bkp.toml"))
+            .iter()
+            .map(|problem| problem.to_string())
+            .collect()
+    };
+
+    assert_eq!(
+        check("[[apps]]\napp_name = 'app'\napp_root = '{dir}/app/'\n"),
+        Vec::<String>::new()
+    );
+    assert_eq!(
+        check(""),
+        [test.expand(
+            "{dir}/bkp.toml: config_files_location '{dir}/conf.d' is not an existing directory"
+        )]
+    );
+}
+
+#[test]
+fn check_reports_an_invalid_server_name_from_the_defaults() {
+    let test = TestDir::new("configcheck-server-name");
+    test.write_file(
+        "conf.d/app.toml",
+        "app_name = 'app'\napp_root = '{dir}/app/'\n",
+    );
+
+    let problems = test.check("[defaults]\nserver_name = 'web_1'\n");
+
+    assert_eq!(
+        problems,
+        [test.expand(
+            "{dir}/conf.d/app.toml: server_name 'web_1' from [defaults] or the hostname must be \
+             non empty and must not contain '_' or '/'"
+        )]
+    );
+}
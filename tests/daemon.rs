@@ -0,0 +1,47 @@
+use std::io::ErrorKind;
+
+use bkp::daemon::{first_run, parse_schedule};
+use chrono::{DateTime, Utc};
+
+fn utc(s: &str) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+}
+
+#[test]
+fn parse_schedule_accepts_five_and_six_field_expressions() {
+    let now = utc("2023-01-14T02:30:00Z");
+
+    // the classic format runs at second 0
+    let daily = parse_schedule("0 3 * * *").unwrap();
+    let next = first_run(&daily, None, now).unwrap();
+    assert_eq!(next.with_timezone(&Utc).format("%S").to_string(), "00");
+
+    let every_ten_seconds = parse_schedule("*/10 * * * * *").unwrap();
+    let next = first_run(&every_ten_seconds, None, now).unwrap();
+    assert_eq!(next.with_timezone(&Utc), utc("2023-01-14T02:30:10Z"));
+
+    for invalid in ["", "0 3 * *", "61 * * * *", "not a schedule"] {
+        let error = parse_schedule(invalid).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput, "{}", invalid);
+    }
+}
+
+#[test]
+fn first_run_catches_up_a_missed_run_once() {
+    let hourly = parse_schedule("0 * * * *").unwrap();
+    let now = utc("2023-01-14T12:30:00Z");
+
+    // the last backup was hours ago, the first run is the first slot after it, in the past
+    let last_run = utc("2023-01-14T08:10:00Z");
+    let next = first_run(&hourly, Some(last_run), now).unwrap();
+    assert_eq!(next.with_timezone(&Utc), utc("2023-01-14T09:00:00Z"));
+    assert!(next.with_timezone(&Utc) <= now);
+
+    // without a backup the first run is the next slot
+    let next = first_run(&hourly, None, now).unwrap();
+    assert_eq!(next.with_timezone(&Utc), utc("2023-01-14T13:00:00Z"));
+
+    // a schedule which never runs again
+    let past = parse_schedule("0 0 0 1 1 * 2020").unwrap();
+    assert_eq!(first_run(&past, None, now), None);
+}
@@ -0,0 +1,99 @@
+mod common;
+
+use std::{
+    fs::{self, Permissions},
+    os::unix::fs::PermissionsExt,
+};
+
+use bkp::{
+    diff,
+    diff::{unified_diff, DiffTarget},
+    BackupType,
+};
+use common::TestDir;
+
+impl TestDir {
+    fn diff(&self, backup_name: &str, target: DiffTarget, unified: bool) -> String {
+        let mut out = Vec::new();
+        diff(
+            &self.global_config(),
+            &self.config(),
+            backup_name,
+            &target,
+            unified,
+            &mut out,
+        )
+        .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+}
+
+#[test]
+fn diff_between_two_backups() {
+    let test = TestDir::new("diff-backups");
+    test.write("changed", "version 1\n");
+    test.write("same-size", "aaaa\n");
+    test.write("removed", "removed\n");
+    test.write("etc/mode", "mode\n");
+    let first = test.backup(BackupType::Full, &[]);
+
+    test.write("changed", "version 2, longer\n");
+    test.write("same-size", "bbbb\n");
+    fs::remove_file(test.app().join("removed")).unwrap();
+    fs::set_permissions(test.app().join("etc/mode"), Permissions::from_mode(0o600)).unwrap();
+    test.write("etc/added", "added\n");
+    let second = test.backup(BackupType::Full, &[]);
+
+    let output = test.diff(&first, DiffTarget::Backup(second), false);
+    let lines = output.lines().collect::<Vec<&str>>();
+
+    assert_eq!(
+        lines,
+        [
+            "M changed (size 10 -> 18)",
+            "A etc/added",
+            "M etc/mode (mode 0644 -> 0600)",
+            "D removed",
+            "M same-size (crc32 34cc2b69 -> 48b213cd)",
+        ]
+    );
+}
+
+#[test]
+fn diff_with_live_files_merges_the_chain() {
+    let test = TestDir::new("diff-live");
+    test.write("etc/app.conf", "port = 1\nhost = a\n");
+    test.write("data", "data\n");
+    test.backup(BackupType::Full, &[]);
+    test.write("etc/app.conf", "port = 2\nhost = a\n");
+    let backup_name = test.backup(BackupType::Incremental, &["etc/app.conf"]);
+
+    assert_eq!(test.diff(&backup_name, DiffTarget::Live, false), "");
+
+    test.write("etc/app.conf", "port = 3\nhost = a\n");
+    let output = test.diff(&backup_name, DiffTarget::Live, true);
+
+    assert!(output.starts_with("M etc/app.conf (crc32 "));
+    assert!(output.ends_with("@@ -1,2 +1,2 @@\n-port = 2\n+port = 3\n host = a\n"));
+}
+
+#[test]
+fn unified_diff_shows_hunks_with_context() {
+    let old = (1..=20).map(|i| format!("{}\n", i)).collect::<String>();
+    let new = (1..=20)
+        .filter(|i| *i != 18)
+        .map(|i| match i {
+            3 => "three\n".to_string(),
+            i => format!("{}\n", i),
+        })
+        .collect::<String>()
+        + "21";
+
+    assert_eq!(
+        unified_diff("a", "b", &old, &new),
+        "--- a\n+++ b\n\
+         @@ -1,6 +1,6 @@\n 1\n 2\n-3\n+three\n 4\n 5\n 6\n\
+         @@ -15,6 +15,6 @@\n 15\n 16\n 17\n-18\n 19\n 20\n+21\n\\ No newline at end of file\n"
+    );
+    assert_eq!(unified_diff("a", "b", &old, &old), "");
+}
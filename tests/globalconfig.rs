@@ -0,0 +1,137 @@
+mod common;
+
+use std::{
+    env, fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use bkp::{
+    config::get_all_configs,
+    globalconfig::{find_global_config_path, load_global_config},
+};
+use common::TestDir;
+
+impl TestDir {
+    // writes a global config without config_files_location, so conf.d next to it is used
+    fn write_global_config(&self, path: &str, extra: &str) -> PathBuf {
+        let path = self.dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(
+            &path,
+            format!(
+                "local_storage_location = '{dir}'\n\
+                 remote_storage_address = 'http://localhost:9'\n\
+                 remote_storage_access_id = 'id'\n\
+                 remote_storage_secret_key = 'key'\n\
+                 log_file_location = '{dir}/bkp.log'\n\
+                 {extra}",
+                dir = self.dir.display(),
+                extra = extra
+            ),
+        )
+        .unwrap();
+        path
+    }
+}
+
+fn app_config(app_name: &str) -> String {
+    format!(
+        "app_name = '{}'\napp_root = '/srv/{}/'\n",
+        app_name, app_name
+    )
+}
+
+// the only test of this file which changes the environment, tests run in parallel
+#[test]
+fn global_config_is_looked_up_in_order() {
+    let test = TestDir::new("globalconfig-lookup");
+    let explicit = test.write_global_config("explicit.toml", "");
+    let from_env = test.write_global_config("env.toml", "");
+    let home = test.write_global_config("home/.bkpconfig", "");
+    let xdg = test.write_global_config("xdg/bkp/config.toml", "");
+    let dot_config = test.write_global_config("home/.config/bkp/config.toml", "");
+
+    env::set_var("HOME", test.dir.join("home"));
+    env::set_var("XDG_CONFIG_HOME", test.dir.join("xdg"));
+    env::set_var("BKP_CONFIG", &from_env);
+
+    let find = |explicit_path: Option<&Path>| find_global_config_path(explicit_path);
+
+    assert_eq!(find(Some(&explicit)).unwrap(), explicit);
+    assert_eq!(find(None).unwrap(), from_env);
+
+    env::remove_var("BKP_CONFIG");
+    assert_eq!(find(None).unwrap(), home);
+
+    fs::remove_file(&home).unwrap();
+    assert_eq!(find(None).unwrap(), xdg);
+
+    // without XDG_CONFIG_HOME, ~/.config is used
+    env::remove_var("XDG_CONFIG_HOME");
+    assert_eq!(find(None).unwrap(), dot_config);
+
+    fs::remove_file(&dot_config).unwrap();
+    let system = Path::new("/etc/bkp/config.toml");
+    match find(None) {
+        Ok(path) => assert_eq!(path, system),
+        Err(e) => {
+            assert!(!system.is_file());
+            assert_eq!(e.kind(), ErrorKind::NotFound);
+        }
+    }
+}
+
+#[test]
+fn app_configs_are_read_from_conf_d_and_the_global_config() {
+    let test = TestDir::new("globalconfig-conf-d");
+    let path = test.write_global_config(
+        "bkp.toml",
+        "[[apps]]\napp_name = 'inline'\napp_root = '/srv/inline/'\n",
+    );
+    let conf_d = test.dir.join("conf.d");
+    fs::create_dir_all(&conf_d).unwrap();
+    fs::write(conf_d.join("b.toml"), app_config("second")).unwrap();
+    fs::write(conf_d.join("a.toml"), app_config("first")).unwrap();
+    // only *.toml files are configs, broken ones are skipped
+    fs::write(conf_d.join("notes.txt"), app_config("notes")).unwrap();
+    fs::write(conf_d.join("c.toml"), "app_name = \n").unwrap();
+
+    let global_config = load_global_config(&path).unwrap();
+    assert_eq!(
+        Path::new(&global_config.config_files_location),
+        conf_d.as_path()
+    );
+
+    let app_names = get_all_configs(&global_config)
+        .into_iter()
+        .map(|config| config.app_name)
+        .collect::<Vec<String>>();
+    assert_eq!(app_names, ["inline", "first", "second"]);
+}
+
+#[test]
+fn config_flag_takes_precedence_over_bkp_config() {
+    let test = TestDir::new("globalconfig-cli");
+    let valid = test.write_global_config("valid.toml", "");
+    fs::create_dir_all(test.dir.join("conf.d")).unwrap();
+    let broken = test.dir.join("broken.toml");
+    fs::write(&broken, "local_storage_location = \n").unwrap();
+
+    let check = |args: &[&Path]| {
+        Command::new(env!("CARGO_BIN_EXE_bkp"))
+            .args(args)
+            .args(["config", "check"])
+            .env("BKP_CONFIG", &broken)
+            .output()
+            .unwrap()
+    };
+
+    let output = check(&[Path::new("--config"), &valid]);
+    assert!(output.status.success(), "{:?}", output);
+
+    let output = check(&[]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("broken.toml:1"));
+}
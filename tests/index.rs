@@ -0,0 +1,106 @@
+mod common;
+
+use std::{fs, io::ErrorKind, os::unix::fs::symlink, path::Path};
+
+use bkp::{
+    backup::parse_backup_from_path,
+    catalog::Catalog,
+    find, history,
+    index::{index_to_bytes, load_index, parse_index},
+    BackupType,
+};
+use common::TestDir;
+
+impl TestDir {
+    fn find(&self, pattern: &str) -> String {
+        let mut out = Vec::new();
+        find(&self.global_config(), &self.config(), pattern, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn history(&self, file: &str) -> Result<String, std::io::Error> {
+        let mut out = Vec::new();
+        history(
+            &self.global_config(),
+            &self.config(),
+            Path::new(file),
+            &mut out,
+        )?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+}
+
+#[test]
+fn find_lists_every_backup_with_a_matching_path() {
+    let test = TestDir::new("index-find");
+    test.write("etc/app.conf", "port = 1\n");
+    test.write("data", "data\n");
+    let full_name = test.backup(BackupType::Full, &[]);
+    test.write("data", "more data\n");
+    let backup_name = test.backup(BackupType::Incremental, &["data"]);
+
+    let output = test.find("/etc/*.conf");
+    let lines = output.lines().collect::<Vec<&str>>();
+
+    // the incremental backup restores the file of the full backup
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with(&format!("{}          9 ", full_name)));
+    assert!(lines[0].ends_with(" etc/app.conf"));
+    assert!(lines[1].starts_with(&format!("{}          9 ", backup_name)));
+
+    // * doesn't match /
+    assert_eq!(test.find("*.conf"), "");
+    assert_eq!(test.find("**/*.conf").lines().count(), 2);
+
+    // find indexed both backups
+    let catalog = Catalog::open(&test.dir.join("storage")).unwrap();
+    for name in [&full_name, &backup_name] {
+        let backup = parse_backup_from_path(&test.dir.join("storage").join(name));
+        assert!(catalog.index(&backup).unwrap().is_some());
+    }
+}
+
+#[test]
+fn history_shows_when_a_file_changed() {
+    let test = TestDir::new("index-history");
+    test.write("etc/app.conf", "port = 1\n");
+    test.write("data", "data\n");
+    let first = test.backup(BackupType::Full, &[]);
+    test.write("data", "more data\n");
+    test.backup(BackupType::Incremental, &["data"]);
+    test.write("etc/app.conf", "port = 2\n");
+    let changed = test.backup(BackupType::Incremental, &["etc/app.conf"]);
+    fs::remove_file(test.app().join("etc/app.conf")).unwrap();
+    let removed = test.backup(BackupType::Full, &[]);
+
+    let output = test.history("etc/app.conf").unwrap();
+    let lines = output.lines().collect::<Vec<&str>>();
+
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].ends_with(&format!(" {} added", first)));
+    assert!(lines[1].contains(&format!(" {} modified (crc32 ", changed)));
+    assert!(lines[2].ends_with(&format!(" {} removed", removed)));
+
+    let error = test.history("missing").unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn index_round_trips_through_its_text_format() {
+    let test = TestDir::new("index-format");
+    test.write("etc/with\ttab", "tab\n");
+    symlink("with\ttab", test.app().join("etc/link")).unwrap();
+    let backup_name = test.backup(BackupType::Full, &[]);
+
+    let backup = parse_backup_from_path(&test.dir.join("storage").join(backup_name));
+    let tree = load_index(&test.global_config(), &backup, false).unwrap();
+    let parsed = parse_index(&index_to_bytes(&tree)).unwrap();
+
+    assert_eq!(index_to_bytes(&parsed), index_to_bytes(&tree));
+    let file = &parsed[Path::new("etc/with\ttab")];
+    assert_eq!((file.size, file.crc.is_some()), (4, true));
+    assert_eq!(
+        format!("{:?}", parsed[Path::new("etc/link")].kind),
+        "Symlink(\"with\\ttab\")"
+    );
+}
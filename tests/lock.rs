@@ -0,0 +1,40 @@
+mod common;
+
+use std::{fs, io::ErrorKind};
+
+use bkp::{full_backup, lock::lock_remote_app, storage::s3::get_remote_object};
+use common::TestDir;
+
+// the fixtures point remote storage at localhost:9, where nothing listens
+
+#[test]
+fn unreachable_remote_storage_is_an_error_not_a_missing_object() {
+    let test = TestDir::new("lock-unreachable");
+
+    let error = get_remote_object(&test.global_config(), "locks/app.lock").unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::NotConnected);
+}
+
+#[test]
+fn unreachable_remote_storage_skips_the_remote_lock() {
+    let test = TestDir::new("lock-skip");
+
+    let lock = lock_remote_app(&test.global_config(), "app", "prune", false).unwrap();
+    assert!(lock.is_none());
+}
+
+#[test]
+fn backups_are_kept_locally_when_remote_storage_is_unreachable() {
+    let test = TestDir::new("lock-backup");
+    test.write("data", "data\n");
+
+    // the upload fails, the archive made before it stays in local storage
+    let result = full_backup(&test.global_config(), &test.config(), false);
+    assert!(result.is_err());
+    let archives = fs::read_dir(test.dir.join("storage"))
+        .unwrap()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().ends_with(".tar.gz"))
+        .count();
+    assert_eq!(archives, 1);
+}
@@ -0,0 +1,66 @@
+mod common;
+
+use std::{
+    fs::{self, File, FileTimes},
+    os::unix::fs::{chown, MetadataExt, PermissionsExt},
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use bkp::{extract::RestoreOptions, BackupType};
+use common::TestDir;
+
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+fn set_mtime(path: &Path, mtime: SystemTime) {
+    File::open(path)
+        .unwrap()
+        .set_times(FileTimes::new().set_modified(mtime))
+        .unwrap();
+}
+
+#[test]
+fn metadata_survives_backup_and_restore() {
+    let test = TestDir::new("metadata-round-trip");
+    let data_dir = test.app().join("data");
+    fs::create_dir(&data_dir).unwrap();
+
+    let file = data_dir.join("file");
+    let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    fs::write(&file, "content\n").unwrap();
+    xattr::set(&file, "user.origin", b"bkp test").unwrap();
+    xattr::set(&data_dir, "user.kind", b"dir").unwrap();
+    // files without xattrs are appended after ones with them, they must not get theirs
+    fs::write(data_dir.join("plain"), "plain\n").unwrap();
+    // chown clears the setuid bit, so it comes first
+    if is_root() {
+        chown(&file, Some(1234), Some(4321)).unwrap();
+    }
+    fs::set_permissions(&file, fs::Permissions::from_mode(0o4751)).unwrap();
+    set_mtime(&file, mtime);
+
+    let backup_name = test.backup(BackupType::Full, &[]);
+    fs::remove_dir_all(&data_dir).unwrap();
+
+    test.restore(&backup_name, RestoreOptions::default())
+        .unwrap();
+
+    let metadata = fs::metadata(&file).unwrap();
+    assert_eq!(fs::read_to_string(&file).unwrap(), "content\n");
+    assert_eq!(metadata.permissions().mode() & 0o7777, 0o4751);
+    assert_eq!(metadata.modified().unwrap(), mtime);
+    assert_eq!(
+        xattr::get(&file, "user.origin").unwrap().as_deref(),
+        Some(b"bkp test".as_slice())
+    );
+    assert_eq!(
+        xattr::get(&data_dir, "user.kind").unwrap().as_deref(),
+        Some(b"dir".as_slice())
+    );
+    assert_eq!(xattr::list(data_dir.join("plain")).unwrap().count(), 0);
+    if is_root() {
+        assert_eq!((metadata.uid(), metadata.gid()), (1234, 4321));
+    }
+}
@@ -0,0 +1,157 @@
+mod common;
+
+use std::{
+    collections::HashSet, ffi::OsStr, fs, os::unix::fs::symlink, path::Path, thread, time::Duration,
+};
+
+use bkp::{
+    mount::{fuse::ROOT_ID, BackupFs},
+    BackupType,
+};
+use common::TestDir;
+
+fn names(backup_fs: &BackupFs, ino: u64) -> Vec<String> {
+    backup_fs
+        .read_dir(ino)
+        .unwrap()
+        .into_iter()
+        .map(|(name, _)| name.into_string().unwrap())
+        .collect()
+}
+
+// looks up every name of path below ino and returns the inode of the last one
+fn lookup_path(backup_fs: &BackupFs, ino: u64, path: &str) -> Result<u64, i32> {
+    Path::new(path)
+        .iter()
+        .try_fold(ino, |ino, name| Ok(backup_fs.lookup(ino, name)?.ino))
+}
+
+fn content(backup_fs: &BackupFs, ino: u64) -> String {
+    let fh = backup_fs.open(ino).unwrap();
+    let data = backup_fs.read(fh, 0, 4096).unwrap();
+    backup_fs.release(fh);
+    String::from_utf8(data).unwrap()
+}
+
+#[test]
+fn snapshots_show_the_merged_files_of_their_chain() {
+    let test = TestDir::new("mount-tree");
+    test.write("etc/app.conf", "port = 1\n");
+    test.write("data", "data\n");
+    fs::hard_link(test.app().join("data"), test.app().join("etc/data")).unwrap();
+    symlink("data", test.app().join("link")).unwrap();
+    test.backup(BackupType::Full, &[]);
+    // snapshots are named by the second of their backup
+    thread::sleep(Duration::from_millis(1100));
+    test.write("etc/app.conf", "port = 22\n");
+    test.backup(BackupType::Incremental, &["etc/app.conf"]);
+
+    let global_config = test.global_config();
+    let backup_fs = BackupFs::new(&global_config);
+
+    assert_eq!(names(&backup_fs, ROOT_ID), ["app"]);
+    let app = lookup_path(&backup_fs, ROOT_ID, "app/server").unwrap();
+    let snapshots = names(&backup_fs, app);
+    assert_eq!(snapshots.len(), 2);
+    let full = lookup_path(&backup_fs, app, &snapshots[0]).unwrap();
+    let incremental = lookup_path(&backup_fs, app, &snapshots[1]).unwrap();
+
+    assert_eq!(names(&backup_fs, incremental), ["data", "etc", "link"]);
+    assert_eq!(names(&backup_fs, full), ["data", "etc", "link"]);
+
+    let app_conf = lookup_path(&backup_fs, incremental, "etc/app.conf").unwrap();
+    assert_eq!(backup_fs.attr(app_conf).unwrap().size, 10);
+    assert_eq!(content(&backup_fs, app_conf), "port = 22\n");
+    let old_app_conf = lookup_path(&backup_fs, full, "etc/app.conf").unwrap();
+    assert_eq!(backup_fs.attr(old_app_conf).unwrap().size, 9);
+    assert_eq!(content(&backup_fs, old_app_conf), "port = 1\n");
+
+    // the incremental backup has none of these, they come from the full one
+    let hard_link = lookup_path(&backup_fs, incremental, "etc/data").unwrap();
+    assert_eq!(backup_fs.attr(hard_link).unwrap().size, 5);
+    assert_eq!(content(&backup_fs, hard_link), "data\n");
+    let link = lookup_path(&backup_fs, incremental, "link").unwrap();
+    assert_eq!(backup_fs.read_link(link).unwrap(), Path::new("data"));
+    let etc = lookup_path(&backup_fs, incremental, "etc").unwrap();
+    assert_eq!(
+        backup_fs.attr(etc).unwrap().mode & libc::S_IFMT,
+        libc::S_IFDIR
+    );
+
+    // inodes are unique and stay the same for every lookup
+    let inodes = [
+        app,
+        full,
+        incremental,
+        app_conf,
+        old_app_conf,
+        hard_link,
+        link,
+        etc,
+    ];
+    assert_eq!(inodes.iter().collect::<HashSet<_>>().len(), inodes.len());
+    assert_eq!(
+        lookup_path(&backup_fs, incremental, "etc/app.conf"),
+        Ok(app_conf)
+    );
+    for ino in inodes {
+        assert_eq!(backup_fs.attr(ino).unwrap().ino, ino);
+    }
+
+    assert_eq!(
+        lookup_path(&backup_fs, incremental, "etc/missing"),
+        Err(libc::ENOENT)
+    );
+    assert_eq!(backup_fs.read_dir(app_conf).unwrap_err(), libc::ENOTDIR);
+}
+
+#[test]
+fn chains_stay_within_the_backups_of_one_server() {
+    let test = TestDir::new("mount-servers");
+    test.write("a", "server\n");
+    test.backup(BackupType::Full, &[]);
+    test.write("a", "other\n");
+    test.full_backup_of("other");
+    test.write("b", "b\n");
+    test.backup(BackupType::Incremental, &["b"]);
+
+    let global_config = test.global_config();
+    let backup_fs = BackupFs::new(&global_config);
+
+    let app = backup_fs.lookup(ROOT_ID, OsStr::new("app")).unwrap().ino;
+    assert_eq!(names(&backup_fs, app), ["other", "server"]);
+    let other = lookup_path(&backup_fs, app, "other").unwrap();
+    assert_eq!(names(&backup_fs, other).len(), 1);
+
+    // both backups of server get a directory, also when made in the same second
+    let server = lookup_path(&backup_fs, app, "server").unwrap();
+    let snapshots = names(&backup_fs, server);
+    assert_eq!(snapshots.len(), 2);
+    let incremental = snapshots
+        .iter()
+        .map(|name| lookup_path(&backup_fs, server, name).unwrap())
+        .find(|snapshot| lookup_path(&backup_fs, *snapshot, "b").is_ok())
+        .unwrap();
+
+    // the chain of the incremental backup ends at the full backup of its own server
+    let a = lookup_path(&backup_fs, incremental, "a").unwrap();
+    assert_eq!(content(&backup_fs, a), "server\n");
+}
+
+#[test]
+fn entries_are_unpacked_once_for_every_open() {
+    let test = TestDir::new("mount-unpacked");
+    test.write("data", "data\n");
+    let backup_name = test.backup(BackupType::Full, &[]);
+
+    let global_config = test.global_config();
+    let backup_fs = BackupFs::new(&global_config);
+    let server = lookup_path(&backup_fs, ROOT_ID, "app/server").unwrap();
+    let snapshot = names(&backup_fs, server).remove(0);
+    let data = lookup_path(&backup_fs, server, &format!("{}/data", snapshot)).unwrap();
+    assert_eq!(content(&backup_fs, data), "data\n");
+
+    // later opens read the unpacked copy, not the archive
+    fs::remove_file(test.archive(&backup_name)).unwrap();
+    assert_eq!(content(&backup_fs, data), "data\n");
+}
@@ -0,0 +1,129 @@
+mod common;
+
+use std::{fs, path::Path};
+
+use bkp::{
+    backup::{parse_backup_from_path, Backup},
+    config::{get_all_configs, get_hostname},
+    recover::{config_snapshot, install_config_snapshot, RecoverableApp, RecoveryPoint},
+};
+use chrono::{TimeZone, Utc};
+use common::TestDir;
+
+impl TestDir {
+    fn write_config(&self, name: &str, content: &str) {
+        fs::create_dir_all(self.dir.join("conf.d")).unwrap();
+        fs::write(self.dir.join("conf.d").join(name), content).unwrap();
+    }
+}
+
+#[test]
+fn config_snapshot_has_the_defaults_and_the_server_name() {
+    let test = TestDir::new("recover-snapshot");
+    test.write_config(
+        "app.toml",
+        "app_name = 'app'\napp_root = '/srv/app/'\nincluded_paths = ['*']\n[sources]\ndump = []\n",
+    );
+    let global_config = test.global_config_with(
+        "[defaults]\nkeep_full_remote_backups = 7\n[[apps]]\napp_name = 'inline'\napp_root = '/srv/inline/'",
+    );
+
+    let configs = get_all_configs(&global_config);
+    let config = configs.iter().find(|c| c.app_name == "app").unwrap();
+    let snapshot = config_snapshot(&global_config, config).unwrap();
+    let table: toml::value::Table = toml::from_str(&snapshot).unwrap();
+
+    assert_eq!(table["app_root"].as_str(), Some("/srv/app/"));
+    assert_eq!(table["keep_full_remote_backups"].as_integer(), Some(7));
+    assert_eq!(table["server_name"].as_str(), Some(get_hostname().as_str()));
+    assert!(table["sources"].is_table());
+    // serde defaults aren't written out
+    assert!(!table.contains_key("script_shell"));
+
+    let inline = configs.iter().find(|c| c.app_name == "inline").unwrap();
+    let snapshot = config_snapshot(&global_config, inline).unwrap();
+    assert!(snapshot.contains("app_root = \"/srv/inline/\""));
+}
+
+#[test]
+fn install_config_snapshot_keeps_existing_configs() {
+    let old_server = TestDir::new("recover-old");
+    old_server.write_config(
+        "app.toml",
+        "app_name = 'app'\nserver_name = 'old'\napp_root = '/srv/app/'\n",
+    );
+    let old_global_config = old_server.global_config();
+    let config = &get_all_configs(&old_global_config)[0];
+    let snapshot = config_snapshot(&old_global_config, config).unwrap();
+
+    // a new server with only credentials gets the config of the old one
+    let new_server = TestDir::new("recover-new");
+    let global_config = new_server.global_config();
+    let installed = install_config_snapshot(&global_config, &snapshot).unwrap();
+
+    assert_eq!(
+        (installed.server_name.as_str(), installed.app_root.as_str()),
+        ("old", "/srv/app/")
+    );
+    assert!(new_server.dir.join("conf.d/app.toml").is_file());
+
+    new_server.write_config(
+        "app.toml",
+        "app_name = 'app'\nserver_name = 'new'\napp_root = '/srv/moved/'\n",
+    );
+    let installed = install_config_snapshot(&global_config, &snapshot).unwrap();
+    assert_eq!(installed.app_root, "/srv/moved/");
+}
+
+#[test]
+fn install_config_snapshot_uses_the_defaults_of_this_server() {
+    let test = TestDir::new("recover-defaults");
+    let global_config = test.global_config_with("[defaults]\napp_root = '/srv/default/'\n");
+
+    // a snapshot without a key the defaults have, as validated by a dry run
+    let installed =
+        install_config_snapshot(&global_config, "app_name = 'app'\nserver_name = 'old'\n").unwrap();
+    assert_eq!(installed.app_root, "/srv/default/");
+}
+
+#[test]
+fn recovery_point_selects_the_backup_and_its_chain() {
+    // remote backups are named without extension, newest first
+    let app = RecoverableApp {
+        app_name: "app".to_string(),
+        backups: [
+            "app_old_incremental_2026-03-02T00:00:00+00:00",
+            "app_old_full_2026-03-01T00:00:00+00:00",
+            "app_old_incremental_2026-02-02T00:00:00+00:00",
+            "app_old_full_2026-02-01T00:00:00+00:00",
+        ]
+        .iter()
+        .map(|name| parse_backup_from_path(Path::new(name)))
+        .collect(),
+        config_snapshot: None,
+    };
+    let chain = |point: &RecoveryPoint| {
+        app.chain(point)
+            .iter()
+            .map(|backup: &Backup| backup.time.format("%m-%d").to_string())
+            .collect::<Vec<String>>()
+    };
+
+    assert_eq!(chain(&RecoveryPoint::Latest), ["03-02", "03-01"]);
+    assert_eq!(
+        chain(&RecoveryPoint::Backup(
+            "app_old_incremental_2026-02-02T00:00:00+00:00.tar.gz".to_string()
+        )),
+        ["02-02", "02-01"]
+    );
+    let before = |month, day| {
+        RecoveryPoint::Before(Utc.with_ymd_and_hms(2026, month, day, 0, 0, 0).unwrap())
+    };
+    assert_eq!(chain(&before(3, 1)), ["02-02", "02-01"]);
+    assert_eq!(chain(&before(3, 2)), ["03-01"]);
+    assert!(chain(&before(2, 1)).is_empty());
+    assert!(chain(&RecoveryPoint::Backup(
+        "app_old_full_2020-01-01T00:00:00+00:00".to_string()
+    ))
+    .is_empty());
+}
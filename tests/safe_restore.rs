@@ -0,0 +1,429 @@
+mod common;
+
+use std::{
+    fs::{self, File},
+    io::ErrorKind,
+    os::unix::fs::{symlink, FileTypeExt},
+    path::{Path, PathBuf},
+};
+
+use bkp::{
+    compress::decompress_archive,
+    config::parse_config_with_defaults,
+    extract::{ConflictPolicy, Extraction, PlannedAction, RestoreOptions},
+    Config,
+};
+use common::TestDir;
+use flate2::{write::GzEncoder, Compression};
+use tar::{Builder, EntryType, Header};
+
+// an entry written with raw header fields, the tar crate refuses to create most of these
+struct RawEntry<'a> {
+    name: &'a [u8],
+    entry_type: EntryType,
+    link_name: &'a [u8],
+    data: &'a [u8],
+}
+
+fn file(name: &[u8]) -> RawEntry<'_> {
+    RawEntry {
+        name,
+        entry_type: EntryType::Regular,
+        link_name: b"",
+        data: b"owned\n",
+    }
+}
+
+fn link<'a>(name: &'a [u8], entry_type: EntryType, link_name: &'a [u8]) -> RawEntry<'a> {
+    RawEntry {
+        name,
+        entry_type,
+        link_name,
+        data: b"",
+    }
+}
+
+fn special(name: &[u8], entry_type: EntryType) -> RawEntry<'_> {
+    link(name, entry_type, b"")
+}
+
+impl TestDir {
+    // app_root, entries are unpacked into it directly without the staging of a restore
+    fn root(&self) -> PathBuf {
+        self.app()
+    }
+
+    fn outside(&self) -> PathBuf {
+        let outside = self.dir.join("outside");
+        fs::create_dir_all(&outside).unwrap();
+        outside
+    }
+
+    fn raw_archive(&self, entries: &[RawEntry]) -> PathBuf {
+        let path = self.dir.join("backup.tar.gz");
+        let encoder = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        let mut builder = Builder::new(encoder);
+
+        for entry in entries {
+            let mut header = Header::new_gnu();
+            let old = header.as_old_mut();
+            old.name[..entry.name.len()].copy_from_slice(entry.name);
+            old.linkname[..entry.link_name.len()].copy_from_slice(entry.link_name);
+            header.set_entry_type(entry.entry_type);
+            header.set_mode(0o644);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            header.set_size(entry.data.len() as u64);
+            header.set_cksum();
+            builder.append(&header, entry.data).unwrap();
+        }
+
+        builder.into_inner().unwrap().finish().unwrap();
+        path
+    }
+
+    fn restore_config(&self, restore_policy: &str) -> Config {
+        parse_config_with_defaults(
+            &format!(
+                "app_name = 'app'\napp_root = '{}/'\n[restore]\n{}",
+                self.root().display(),
+                restore_policy
+            ),
+            &Default::default(),
+        )
+        .unwrap()
+    }
+
+    fn unpack(&self, entries: &[RawEntry]) -> Result<(), std::io::Error> {
+        self.unpack_with_policy(entries, "")
+    }
+
+    fn unpack_with_policy(
+        &self,
+        entries: &[RawEntry],
+        restore_policy: &str,
+    ) -> Result<(), std::io::Error> {
+        let archive = self.raw_archive(entries);
+        let mut extraction = Extraction::new(RestoreOptions::default());
+        decompress_archive(
+            archive,
+            self.root(),
+            &self.restore_config(restore_policy),
+            &mut extraction,
+        )
+    }
+
+    fn unpack_with_options(&self, entries: &[RawEntry], options: RestoreOptions) -> Extraction {
+        let archive = self.raw_archive(entries);
+        let mut extraction = Extraction::new(options);
+        decompress_archive(
+            archive,
+            self.root(),
+            &self.restore_config(""),
+            &mut extraction,
+        )
+        .unwrap();
+        extraction
+    }
+}
+
+fn exists(path: &Path) -> bool {
+    path.symlink_metadata().is_ok()
+}
+
+#[test]
+fn benign_archive_is_restored() {
+    let test = TestDir::new("safe-benign");
+
+    test.unpack(&[
+        special(b"./", EntryType::Directory),
+        special(b"dir/", EntryType::Directory),
+        file(b"dir/file"),
+        link(b"dir/link", EntryType::Symlink, b"file"),
+        link(b"up", EntryType::Symlink, b"dir/../dir/file"),
+        link(b"hard", EntryType::Link, b"dir/file"),
+    ])
+    .unwrap();
+
+    assert_eq!(
+        fs::read_to_string(test.root().join("dir/link")).unwrap(),
+        "owned\n"
+    );
+    assert_eq!(
+        fs::read_to_string(test.root().join("up")).unwrap(),
+        "owned\n"
+    );
+    assert_eq!(
+        fs::read_to_string(test.root().join("hard")).unwrap(),
+        "owned\n"
+    );
+}
+
+#[test]
+fn parent_dir_traversal_is_refused() {
+    let test = TestDir::new("safe-traversal");
+
+    let result = test.unpack(&[file(b"../outside/evil"), file(b"dir/../../outside/evil2")]);
+
+    assert!(result.is_err());
+    assert!(!exists(&test.outside().join("evil")));
+    assert!(!exists(&test.outside().join("evil2")));
+}
+
+#[test]
+fn absolute_path_is_refused() {
+    let test = TestDir::new("safe-absolute");
+    let target = test.outside().join("evil");
+
+    let result = test.unpack(&[file(target.to_str().unwrap().as_bytes())]);
+
+    assert!(result.is_err());
+    assert!(!exists(&target));
+    // tar would strip the leading '/' and unpack it below root
+    assert!(!exists(
+        &test.root().join(target.strip_prefix("/").unwrap())
+    ));
+}
+
+#[test]
+fn symlink_outside_root_is_refused() {
+    let test = TestDir::new("safe-symlink");
+    let outside = test.outside();
+
+    let result = test.unpack(&[
+        link(
+            b"abs",
+            EntryType::Symlink,
+            outside.to_str().unwrap().as_bytes(),
+        ),
+        link(b"rel", EntryType::Symlink, b"../outside"),
+        link(b"dir/rel", EntryType::Symlink, b"../../outside"),
+        file(b"abs/evil"),
+        file(b"rel/evil"),
+    ]);
+
+    assert!(result.is_err());
+    // the files below the refused links end up in plain directories inside root
+    assert!(!test.root().join("abs").is_symlink());
+    assert!(!test.root().join("rel").is_symlink());
+    assert!(!exists(&test.root().join("dir/rel")));
+    assert!(!exists(&outside.join("evil")));
+}
+
+#[test]
+fn symlink_outside_root_can_be_allowed() {
+    let test = TestDir::new("safe-symlink-allowed");
+
+    test.unpack_with_policy(
+        &[link(b"passwd", EntryType::Symlink, b"/etc/passwd")],
+        "allow_external_symlinks = true",
+    )
+    .unwrap();
+
+    assert_eq!(
+        fs::read_link(test.root().join("passwd")).unwrap(),
+        Path::new("/etc/passwd")
+    );
+}
+
+#[test]
+fn existing_symlink_outside_root_is_not_followed() {
+    let test = TestDir::new("safe-existing-symlink");
+    symlink(test.outside(), test.root().join("data")).unwrap();
+
+    let result = test.unpack(&[file(b"data/evil")]);
+
+    assert!(result.is_err());
+    assert!(!exists(&test.outside().join("evil")));
+}
+
+#[test]
+fn hard_link_outside_root_is_refused() {
+    let test = TestDir::new("safe-hard-link");
+    fs::write(test.outside().join("secret"), "secret\n").unwrap();
+
+    let result = test.unpack(&[
+        link(b"secret", EntryType::Link, b"../outside/secret"),
+        link(b"passwd", EntryType::Link, b"/etc/passwd"),
+    ]);
+
+    assert!(result.is_err());
+    assert!(!exists(&test.root().join("secret")));
+    assert!(!exists(&test.root().join("passwd")));
+}
+
+#[test]
+fn hard_link_through_an_external_symlink_is_refused() {
+    let test = TestDir::new("safe-hard-link-symlink");
+    fs::write(test.outside().join("secret"), "secret\n").unwrap();
+
+    let result = test.unpack_with_policy(
+        &[
+            link(
+                b"out",
+                EntryType::Symlink,
+                test.outside().to_str().unwrap().as_bytes(),
+            ),
+            link(b"secret", EntryType::Link, b"out/secret"),
+        ],
+        "allow_external_symlinks = true",
+    );
+
+    // refused like any other entry, before the tar crate would fail the whole archive
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+    assert!(test.root().join("out").is_symlink());
+    assert!(!exists(&test.root().join("secret")));
+}
+
+#[test]
+fn special_files_are_refused() {
+    let test = TestDir::new("safe-special");
+
+    let result = test.unpack(&[
+        special(b"null", EntryType::Char),
+        special(b"sda", EntryType::Block),
+        special(b"fifo", EntryType::Fifo),
+        file(b"regular"),
+    ]);
+
+    assert!(result.is_err());
+    assert!(!exists(&test.root().join("null")));
+    assert!(!exists(&test.root().join("sda")));
+    assert!(!exists(&test.root().join("fifo")));
+    // entries which are fine are still restored
+    assert!(exists(&test.root().join("regular")));
+}
+
+#[test]
+fn special_files_can_be_allowed() {
+    let test = TestDir::new("safe-special-allowed");
+
+    test.unpack_with_policy(
+        &[special(b"fifo", EntryType::Fifo)],
+        "allow_special_files = true",
+    )
+    .unwrap();
+
+    let metadata = test.root().join("fifo").symlink_metadata().unwrap();
+    assert!(metadata.file_type().is_fifo());
+}
+
+fn conflict(conflict: ConflictPolicy) -> RestoreOptions {
+    RestoreOptions {
+        conflict,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn dry_run_changes_nothing() {
+    let test = TestDir::new("dry-run");
+    fs::write(test.root().join("existing"), "current\n").unwrap();
+
+    let extraction = test.unpack_with_options(
+        &[file(b"existing"), file(b"new")],
+        RestoreOptions {
+            dry_run: true,
+            ..Default::default()
+        },
+    );
+
+    assert_eq!(
+        extraction.plan.get(Path::new("existing")),
+        Some(&PlannedAction::Overwrite)
+    );
+    assert_eq!(
+        extraction.plan.get(Path::new("new")),
+        Some(&PlannedAction::Create)
+    );
+    assert_eq!(
+        fs::read_to_string(test.root().join("existing")).unwrap(),
+        "current\n"
+    );
+    assert!(!exists(&test.root().join("new")));
+}
+
+#[test]
+fn dry_run_with_move_aside_lists_deletions() {
+    let test = TestDir::new("dry-run-move-aside");
+    fs::create_dir_all(test.root().join("dir/stale")).unwrap();
+    fs::write(test.root().join("dir/stale/file"), "current\n").unwrap();
+    fs::write(test.root().join("dir/file"), "current\n").unwrap();
+
+    let mut extraction = test.unpack_with_options(
+        &[file(b"dir/file")],
+        RestoreOptions {
+            dry_run: true,
+            move_aside: true,
+            ..Default::default()
+        },
+    );
+    extraction.plan_deletions(&test.root()).unwrap();
+
+    // the existing file is gone after moving aside, so it is created again
+    assert_eq!(
+        extraction.plan.get(Path::new("dir/file")),
+        Some(&PlannedAction::Create)
+    );
+    assert_eq!(
+        extraction.plan.get(Path::new("dir/stale")),
+        Some(&PlannedAction::Delete)
+    );
+    assert_eq!(extraction.plan.get(Path::new("dir/stale/file")), None);
+}
+
+#[test]
+fn skip_existing_keeps_files() {
+    let test = TestDir::new("skip-existing");
+    fs::write(test.root().join("existing"), "current\n").unwrap();
+
+    test.unpack_with_options(
+        &[file(b"existing"), file(b"new")],
+        conflict(ConflictPolicy::SkipExisting),
+    );
+
+    assert_eq!(
+        fs::read_to_string(test.root().join("existing")).unwrap(),
+        "current\n"
+    );
+    assert_eq!(
+        fs::read_to_string(test.root().join("new")).unwrap(),
+        "owned\n"
+    );
+}
+
+#[test]
+fn keep_newer_keeps_files_modified_after_the_backup() {
+    let test = TestDir::new("keep-newer");
+    // the archived files have an mtime of 0
+    fs::write(test.root().join("newer"), "current\n").unwrap();
+
+    let extraction =
+        test.unpack_with_options(&[file(b"newer")], conflict(ConflictPolicy::KeepNewer));
+
+    assert_eq!(
+        extraction.plan.get(Path::new("newer")),
+        Some(&PlannedAction::Skip)
+    );
+    assert_eq!(
+        fs::read_to_string(test.root().join("newer")).unwrap(),
+        "current\n"
+    );
+}
+
+#[test]
+fn rename_keeps_existing_files_aside() {
+    let test = TestDir::new("rename");
+    fs::write(test.root().join("existing"), "current\n").unwrap();
+
+    let extraction =
+        test.unpack_with_options(&[file(b"existing")], conflict(ConflictPolicy::Rename));
+
+    assert_eq!(
+        fs::read_to_string(test.root().join("existing")).unwrap(),
+        "owned\n"
+    );
+    let aside = extraction.aside_path(&test.root().join("existing"));
+    assert_eq!(fs::read_to_string(aside).unwrap(), "current\n");
+}
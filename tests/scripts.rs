@@ -0,0 +1,105 @@
+mod common;
+
+use std::{
+    fs,
+    io::ErrorKind,
+    time::{Duration, Instant},
+};
+
+use bkp::{
+    backup::get_all_local_backups,
+    config::{parse_config_with_defaults, resolve_config, Config},
+    extract::RestoreOptions,
+    full_backup, restore,
+    scripts::{run_script, ScriptContext, ScriptPhase},
+};
+use common::{name, TestDir};
+use toml::value::Table;
+
+fn config(pre_backup_script: &str) -> Config {
+    let app_config: Table = toml::from_str(&format!(
+        "app_name = 'app'\nserver_name = 'server'\napp_root = '/tmp/'\npre_backup_script = {}\n",
+        pre_backup_script
+    ))
+    .unwrap();
+    resolve_config(&app_config, &Table::new()).unwrap()
+}
+
+fn run(pre_backup_script: &str) -> (Result<(), std::io::Error>, Duration) {
+    let config = config(pre_backup_script);
+    let context = ScriptContext::new(&config, ScriptPhase::PreBackup);
+    let start = Instant::now();
+    let result = run_script(&config, &config.pre_backup_script, &context);
+    (result, start.elapsed())
+}
+
+#[test]
+fn script_gets_the_context_and_fails_with_its_exit_status() {
+    let (result, _) =
+        run(r#"'test "$BKP_APP_NAME/$BKP_SERVER_NAME/$BKP_PHASE" = app/server/pre_backup'"#);
+    result.unwrap();
+
+    let (result, _) = run("['sh', '-c', 'echo failing >&2; exit 3']");
+    let error = result.unwrap_err();
+    assert!(
+        error.to_string().starts_with("pre_backup script failed"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn script_is_killed_after_its_timeout() {
+    let (result, elapsed) = run("{ command = 'sleep 30', timeout = 1 }");
+
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::TimedOut);
+    assert!(elapsed < Duration::from_secs(10), "{:?}", elapsed);
+}
+
+#[test]
+fn background_processes_holding_the_output_dont_block_the_run() {
+    // the script exits right away, the sleep keeps its stdout and stderr open
+    let (result, elapsed) = run("'sleep 30 & echo started'");
+    result.unwrap();
+    assert!(elapsed < Duration::from_secs(10), "{:?}", elapsed);
+
+    // a process in its own session survives the timeout, the run still ends
+    let (result, elapsed) = run("{ command = 'setsid sleep 30 & sleep 30', timeout = 1 }");
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::TimedOut);
+    assert!(elapsed < Duration::from_secs(10), "{:?}", elapsed);
+}
+
+#[test]
+fn backup_and_restore_scripts_get_the_same_backup_name() {
+    let test = TestDir::new("scripts-backup-name");
+    test.write("a", "a\n");
+    let config = parse_config_with_defaults(
+        &format!(
+            "app_name = 'app'\nserver_name = 'server'\napp_root = '{app}/'\n\
+             included_paths = ['a']\n\
+             pre_backup_script = ['sh', '-c', 'echo $BKP_BACKUP_NAME > {dir}/backup']\n\
+             pre_restore_script = ['sh', '-c', 'echo $BKP_BACKUP_NAME > {dir}/restore']\n",
+            app = test.app().display(),
+            dir = test.dir.display()
+        ),
+        &Default::default(),
+    )
+    .unwrap();
+    let global_config = test.global_config();
+
+    // the upload to the unreachable remote storage fails after the pre backup script
+    let _ = full_backup(&global_config, &config, false);
+    let backup = get_all_local_backups(&global_config).remove(0);
+    restore(
+        &global_config,
+        &config,
+        &backup.file_name,
+        &RestoreOptions::default(),
+        false,
+    )
+    .unwrap();
+
+    let read = |name: &str| fs::read_to_string(test.dir.join(name)).unwrap();
+    assert_eq!(read("backup"), format!("{}\n", name(&backup.file_name)));
+    assert_eq!(read("restore"), read("backup"));
+}
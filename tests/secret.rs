@@ -0,0 +1,95 @@
+use std::{env, fs, path::PathBuf};
+
+use bkp::secret::{redact, Secret};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct Secrets {
+    plain: Secret,
+    env: Secret,
+    file: Secret,
+    command: Secret,
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("bkp-test-{}-{}", name, std::process::id()))
+}
+
+#[test]
+fn secrets_are_read_from_env_file_and_command() {
+    let file = temp_path("secret-file");
+    fs::write(&file, "file-value\n").unwrap();
+    let runs = temp_path("secret-runs");
+    let _ = fs::remove_file(&runs);
+    env::set_var("BKP_TEST_SECRET_ENV", "env-value");
+
+    let secrets: Secrets = toml::from_str(&format!(
+        "plain = 'plain-value'\n\
+         env = {{ env = 'BKP_TEST_SECRET_ENV' }}\n\
+         file = {{ file = '{}' }}\n\
+         command = {{ command = 'echo run >> {}; echo command-value' }}\n",
+        file.display(),
+        runs.display()
+    ))
+    .unwrap();
+
+    assert_eq!(secrets.plain.expose().unwrap(), "plain-value");
+    assert_eq!(secrets.env.expose().unwrap(), "env-value");
+    // the trailing newline of the file is not part of the secret
+    assert_eq!(secrets.file.expose().unwrap(), "file-value");
+
+    // the command only runs on the first expose, clones share its value
+    assert!(!runs.exists());
+    let clone = secrets.command.clone();
+    assert_eq!(secrets.command.expose().unwrap(), "command-value");
+    assert_eq!(clone.expose().unwrap(), "command-value");
+    assert_eq!(fs::read_to_string(&runs).unwrap(), "run\n");
+
+    let _ = fs::remove_file(&file);
+    let _ = fs::remove_file(&runs);
+}
+
+#[test]
+fn missing_secrets_are_errors() {
+    let missing_env = toml::from_str::<Secrets>(
+        "plain = 'x'\nenv = { env = 'BKP_TEST_SECRET_MISSING' }\nfile = 'x'\ncommand = 'x'\n",
+    );
+    assert!(missing_env.is_err());
+
+    let secrets: Secrets = toml::from_str(
+        "plain = 'x'\nenv = 'x'\nfile = 'x'\ncommand = { command = 'echo denied >&2; exit 1' }\n",
+    )
+    .unwrap();
+    let error = secrets.command.expose().unwrap_err();
+    assert!(error.to_string().contains("denied"), "{}", error);
+}
+
+#[test]
+fn secrets_are_redacted() {
+    let secrets: Secrets = toml::from_str(
+        "plain = 'plain-redacted'\nenv = 'env-redacted'\nfile = 'abc'\n\
+         command = { command = 'echo command-redacted' }\n",
+    )
+    .unwrap();
+
+    assert_eq!(
+        format!("{} {:?}", secrets.plain, secrets.plain),
+        "<redacted> Secret(<redacted>)"
+    );
+    assert_eq!(
+        redact("key plain-redacted and env-redacted"),
+        "key <redacted> and <redacted>"
+    );
+    // short values would redact unrelated text
+    assert_eq!(redact("abc"), "abc");
+
+    // command secrets are redacted once they are resolved
+    assert_eq!(redact("command-redacted"), "command-redacted");
+    secrets.command.expose().unwrap();
+    assert_eq!(redact("token command-redacted"), "token <redacted>");
+    assert_eq!(
+        Secret::new("new-redacted".to_string()).expose().unwrap(),
+        "new-redacted"
+    );
+    assert_eq!(redact("new-redacted"), "<redacted>");
+}
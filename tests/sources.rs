@@ -0,0 +1,292 @@
+mod common;
+
+use std::{
+    fs, panic,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+use bkp::{
+    backup::{do_full_backup, do_incremental_backup, get_new_backup_file_path},
+    compress::read_archive_entry,
+    config::parse_config_with_defaults,
+    extract::RestoreOptions,
+    restore, BackupType, Config, GlobalConfig,
+};
+use common::TestDir;
+use rusqlite::Connection;
+
+// the stub database is a file, dumped with cat and restored by appending to another file,
+// so every restore run is visible
+fn app_config(dir: &Path, dump_command: &str) -> Config {
+    parse_config_with_defaults(
+        &format!(
+            r#"
+            app_name = 'app'
+            server_name = 'server'
+            app_root = '{dir}/app/'
+            included_paths = ['*']
+
+            [[sources.dump]]
+            name = 'db.sql'
+            command = ['sh', '-c', '{dump_command}']
+            restore_command = ['sh', '-c', 'cat >> {dir}/restored.sql']
+            "#,
+            dir = dir.display(),
+            dump_command = dump_command
+        ),
+        &Default::default(),
+    )
+    .unwrap()
+}
+
+fn backup(global_config: &GlobalConfig, config: &Config, backup_type: BackupType) -> PathBuf {
+    let backup_file_path = get_new_backup_file_path(global_config, config, &backup_type);
+    match backup_type {
+        BackupType::Full => do_full_backup(config, &backup_file_path).unwrap(),
+        BackupType::Incremental => do_incremental_backup(config, &[], &backup_file_path).unwrap(),
+    }
+
+    let mut archive = backup_file_path.into_os_string();
+    archive.push(".tar.gz");
+    PathBuf::from(archive)
+}
+
+fn read_entry(archive: &Path, name: &str) -> Option<Vec<u8>> {
+    read_archive_entry(archive, Path::new(name), |entry| {
+        let mut dump = Vec::new();
+        entry.read_to_end(&mut dump)?;
+        Ok(dump)
+    })
+    .unwrap()
+}
+
+#[test]
+fn dump_is_streamed_into_archive() {
+    let test = TestDir::new("dump-streamed");
+    let dir = &test.dir;
+    let global_config = test.global_config();
+    // larger than a pipe buffer and not a multiple of the tar block size
+    let config = app_config(
+        dir,
+        "printf header; head -c 200001 /dev/zero | tr \"\\\\0\" x",
+    );
+
+    let archive = backup(&global_config, &config, BackupType::Full);
+
+    let dump = read_entry(&archive, ".bkp/dumps/db.sql").unwrap();
+    assert_eq!(dump.len(), "header".len() + 200001);
+    assert!(dump.starts_with(b"headerxxx"));
+    assert!(dump.ends_with(b"xxx"));
+}
+
+#[test]
+fn failing_dump_command_fails_backup() {
+    let test = TestDir::new("dump-failing");
+    let dir = &test.dir;
+    let global_config = test.global_config();
+    let config = app_config(dir, "echo partial; exit 3");
+
+    let backup_file_path = get_new_backup_file_path(&global_config, &config, &BackupType::Full);
+    let error = do_full_backup(&config, &backup_file_path).unwrap_err();
+    assert!(error.to_string().contains("exit status: 3"), "{}", error);
+}
+
+#[test]
+fn restore_pipes_newest_dump_into_restore_command() {
+    let test = TestDir::new("dump-restore");
+    let dir = &test.dir;
+    let global_config = test.global_config();
+    let config = app_config(dir, &format!("cat {}/db", dir.display()));
+
+    fs::write(dir.join("db"), "version 1\n").unwrap();
+    backup(&global_config, &config, BackupType::Full);
+    fs::write(dir.join("db"), "version 2\n").unwrap();
+    let archive = backup(&global_config, &config, BackupType::Incremental);
+    fs::write(dir.join("db"), "version 3\n").unwrap();
+
+    let backup_name = archive.file_name().unwrap().to_str().unwrap();
+    restore(
+        &global_config,
+        &config,
+        backup_name,
+        &RestoreOptions::default(),
+        false,
+    )
+    .unwrap();
+
+    assert_eq!(
+        fs::read_to_string(dir.join("restored.sql")).unwrap(),
+        "version 2\n"
+    );
+}
+
+#[test]
+fn command_outputs_are_archived_next_to_files() {
+    let test = TestDir::new("command-outputs");
+    let dir = &test.dir;
+    let global_config = test.global_config();
+
+    // stub of the docker cli
+    fs::write(dir.join("docker"), "#!/bin/sh\necho \"$1 of $2\"\n").unwrap();
+    fs::write(dir.join("app/config.ini"), "key = value\n").unwrap();
+
+    let config = parse_config_with_defaults(
+        &format!(
+            r#"
+            app_name = 'app'
+            server_name = 'server'
+            app_root = '{dir}/app/'
+            included_paths = ['*.ini']
+
+            [[sources.command]]
+            name = 'inspect.json'
+            command = ['sh', '{dir}/docker', 'inspect', 'myapp']
+            "#,
+            dir = dir.display()
+        ),
+        &Default::default(),
+    )
+    .unwrap();
+
+    let archive = backup(&global_config, &config, BackupType::Full);
+
+    assert_eq!(
+        read_entry(&archive, ".bkp/commands/inspect.json").unwrap(),
+        b"inspect of myapp\n"
+    );
+    assert_eq!(
+        read_entry(&archive, "config.ini").unwrap(),
+        b"key = value\n"
+    );
+}
+
+#[test]
+fn sqlite_snapshot_is_consistent_while_the_app_writes() {
+    let test = TestDir::new("sqlite-live");
+    let dir = &test.dir;
+    let global_config = test.global_config();
+    let db_path = dir.join("app/db.sqlite");
+
+    let config = parse_config_with_defaults(
+        &format!(
+            r#"
+            app_name = 'app'
+            server_name = 'server'
+            app_root = '{dir}/app/'
+            included_paths = ['*']
+
+            [[sources.sqlite]]
+            path = 'db.sqlite'
+            "#,
+            dir = dir.display()
+        ),
+        &Default::default(),
+    )
+    .unwrap();
+
+    // large enough that the snapshot takes several steps
+    let db = Connection::open(&db_path).unwrap();
+    db.pragma_update(None, "journal_mode", "wal").unwrap();
+    db.execute_batch(
+        "CREATE TABLE rows (batch INTEGER, data BLOB);
+         WITH RECURSIVE n(i) AS (SELECT 0 UNION ALL SELECT i + 1 FROM n WHERE i < 9999)
+         INSERT INTO rows SELECT i / 2, randomblob(2000) FROM n;",
+    )
+    .unwrap();
+
+    // every transaction of the app writes two rows of a batch
+    let stop = &AtomicBool::new(false);
+    let archive = thread::scope(|scope| {
+        let writer = scope.spawn(move || {
+            let mut batch = 5000;
+            while !stop.load(Ordering::Relaxed) {
+                db.execute_batch(&format!(
+                    "BEGIN; INSERT INTO rows VALUES ({batch}, randomblob(2000));
+                     INSERT INTO rows VALUES ({batch}, randomblob(2000)); COMMIT;",
+                    batch = batch
+                ))
+                .unwrap();
+                batch += 1;
+                thread::sleep(Duration::from_millis(1));
+            }
+            batch
+        });
+
+        let archive = panic::catch_unwind(|| backup(&global_config, &config, BackupType::Full));
+        stop.store(true, Ordering::Relaxed);
+        assert!(writer.join().unwrap() > 5000);
+        archive.unwrap()
+    });
+
+    let backup_name = archive.file_name().unwrap().to_str().unwrap();
+    restore(
+        &global_config,
+        &config,
+        backup_name,
+        &RestoreOptions::default(),
+        false,
+    )
+    .unwrap();
+
+    let restored = Connection::open(&db_path).unwrap();
+    let integrity: String = restored
+        .query_row("PRAGMA integrity_check", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(integrity, "ok");
+    let (rows, torn_batches): (i64, i64) = restored
+        .query_row(
+            "SELECT (SELECT count(*) FROM rows),
+                    (SELECT count(*) FROM (SELECT batch FROM rows GROUP BY batch HAVING count(*) != 2))",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap();
+    assert!(rows >= 10000, "{}", rows);
+    assert_eq!(torn_batches, 0);
+
+    drop(restored);
+}
+
+#[test]
+fn real_bkp_directory_of_the_app_is_restored() {
+    let test = TestDir::new("real-bkp-dir");
+    let dir = &test.dir;
+    let global_config = test.global_config();
+    let mut config = app_config(dir, "echo dumped");
+    config.included_paths = vec!["**/*".to_string()];
+
+    // the app keeps its own files where bkp stores its entries
+    fs::create_dir_all(dir.join("app/.bkp/dumps")).unwrap();
+    fs::write(dir.join("app/.bkp/manifest"), "app manifest\n").unwrap();
+    fs::write(dir.join("app/.bkp/dumps/db.sql"), "app dump\n").unwrap();
+
+    let archive = backup(&global_config, &config, BackupType::Full);
+    fs::remove_dir_all(dir.join("app/.bkp")).unwrap();
+
+    let backup_name = archive.file_name().unwrap().to_str().unwrap();
+    restore(
+        &global_config,
+        &config,
+        backup_name,
+        &RestoreOptions::default(),
+        false,
+    )
+    .unwrap();
+
+    assert_eq!(
+        fs::read_to_string(dir.join("app/.bkp/manifest")).unwrap(),
+        "app manifest\n"
+    );
+    assert_eq!(
+        fs::read_to_string(dir.join("app/.bkp/dumps/db.sql")).unwrap(),
+        "app dump\n"
+    );
+    // the dump of the source went to its restore command, not into app_root
+    assert_eq!(
+        fs::read_to_string(dir.join("restored.sql")).unwrap(),
+        "dumped\n"
+    );
+}